@@ -0,0 +1,758 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+use bitcoin::{
+    absolute,
+    consensus::encode::{deserialize_hex, serialize_hex},
+    psbt::Psbt,
+    relative,
+    script::Builder,
+    transaction, Address, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use bitcoincore_rpc::{json::SignRawTransactionInput, jsonrpc::serde_json, Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+
+//an output the backend reports as unspent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    //0 while it is in the mempool
+    pub confirmations: u32,
+}
+
+//what a backend without a wallet (e.g. Esplora) returns for everything that needs one, so a caller
+//can tell it apart from the wallet failing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoWallet;
+
+impl fmt::Display for NoWallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "this chain backend has no wallet, pool spend fees and signatures need one"
+        )
+    }
+}
+
+impl std::error::Error for NoWallet {}
+
+//What the pool needs from the chain, so the same pool logic runs against a Core node, an Esplora
+//server or MockChain. Mining and wallet signing only make sense for some backends, the others
+//return an error.
+pub trait ChainBackend {
+    fn get_tx(&self, txid: Txid) -> Result<Transaction>;
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+
+    //the tx spending outpoint, from the mempool or a block, None while it is unspent
+    fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>>;
+
+    //submit parents and the children paying for them together, parents first
+    fn submit_package(&self, txs: &[Transaction]) -> Result<Vec<Txid>>;
+
+    //sat/kvB (like DEFAULT_FEE_RATE) to confirm within conf_target blocks, None when the backend
+    //has no estimate yet (e.g. a fresh regtest chain)
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>>;
+
+    //unspent outputs paying to addresses
+    fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>>;
+
+    //every unspent output of the backend's wallet, what the children paying for pool spends and
+    //the funding contributions are built from
+    fn wallet_utxos(&self) -> Result<Vec<Utxo>> {
+        Err(NoWallet.into())
+    }
+
+    fn tip_height(&self) -> Result<u32>;
+
+    //confirmations of a tx the backend knows about, 0 while it is in the mempool
+    fn tx_confirmations(&self, txid: Txid) -> Result<u32>;
+
+    //sign and finalize every input of psbt the backend's wallet has a key for, the rest stay as
+    //they are for their own owners to sign
+    fn sign_wallet_psbt(&self, _psbt: Psbt) -> Result<Psbt> {
+        Err(NoWallet.into())
+    }
+
+    //sign every input of tx the backend's wallet has a key for. prevouts are inputs the backend
+    //can't look up yet, like outputs of a parent that isn't broadcast. P2A inputs need no signature
+    fn sign_wallet_tx(
+        &self,
+        _tx: Transaction,
+        _prevouts: &[(OutPoint, TxOut)],
+    ) -> Result<Transaction> {
+        Err(NoWallet.into())
+    }
+}
+
+impl ChainBackend for Client {
+    fn get_tx(&self, txid: Txid) -> Result<Transaction> {
+        Ok(self.get_raw_transaction(&txid, None)?)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        Ok(self.send_raw_transaction(tx)?)
+    }
+
+    //core has no spend index, a spend that isn't in the mempool is found by scanning every block
+    //from the one the output confirmed in
+    fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>> {
+        if self
+            .get_tx_out(&outpoint.txid, outpoint.vout, Some(true))?
+            .is_some()
+        {
+            return Ok(None);
+        }
+
+        let mempool_spends: serde_json::Value = self.call(
+            "gettxspendingprevout",
+            &[json!([{ "txid": outpoint.txid, "vout": outpoint.vout }])],
+        )?;
+        if let Some(spending_txid) = mempool_spends[0]["spendingtxid"].as_str() {
+            return Ok(Some(self.get_tx(spending_txid.parse()?)?));
+        }
+
+        let block_hash = self
+            .get_raw_transaction_info(&outpoint.txid, None)?
+            .blockhash
+            .ok_or_else(|| anyhow!("{} is spent but its tx is not in a block", outpoint))?;
+        let first_height = self.get_block_header_info(&block_hash)?.height as u64;
+
+        for height in first_height..=self.get_block_count()? {
+            let block = self.get_block(&self.get_block_hash(height)?)?;
+            let spend = block.txdata.into_iter().find(|tx| {
+                tx.input
+                    .iter()
+                    .any(|input| input.previous_output == outpoint)
+            });
+            if spend.is_some() {
+                return Ok(spend);
+            }
+        }
+
+        bail!("{} is spent but no block after it has the spend", outpoint)
+    }
+
+    //needs bitcoin core 28 or newer
+    fn submit_package(&self, txs: &[Transaction]) -> Result<Vec<Txid>> {
+        let raw_txs: Vec<String> = txs.iter().map(serialize_hex).collect();
+        let result: serde_json::Value = self.call("submitpackage", &[json!(raw_txs)])?;
+
+        package_txids(txs, &result)
+    }
+
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>> {
+        let estimate = self.estimate_smart_fee(conf_target, None)?;
+        Ok(estimate.fee_rate.map(|rate| rate.to_sat()))
+    }
+
+    fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>> {
+        //core reads an empty filter as no filter at all
+        if addresses.is_empty() {
+            return Ok(Vec::new());
+        }
+        let addresses: Vec<&Address> = addresses.iter().collect();
+        core_unspent(self, Some(&addresses))
+    }
+
+    fn wallet_utxos(&self) -> Result<Vec<Utxo>> {
+        core_unspent(self, None)
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        Ok(self.get_block_count()?.try_into()?)
+    }
+
+    fn tx_confirmations(&self, txid: Txid) -> Result<u32> {
+        Ok(self
+            .get_raw_transaction_info(&txid, None)?
+            .confirmations
+            .unwrap_or(0))
+    }
+
+    fn sign_wallet_psbt(&self, psbt: Psbt) -> Result<Psbt> {
+        let processed = self.wallet_process_psbt(&psbt_to_base64(&psbt), Some(true), None, None)?;
+        psbt_from_base64(&processed.psbt)
+    }
+
+    fn sign_wallet_tx(
+        &self,
+        tx: Transaction,
+        prevouts: &[(OutPoint, TxOut)],
+    ) -> Result<Transaction> {
+        let inputs: Vec<SignRawTransactionInput> = prevouts
+            .iter()
+            .map(|(outpoint, txout)| SignRawTransactionInput {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                script_pub_key: txout.script_pubkey.clone(),
+                redeem_script: None,
+                amount: Some(txout.value),
+            })
+            .collect();
+        let signed = self.sign_raw_transaction_with_wallet(&tx, Some(&inputs), None)?;
+
+        //the wallet has nothing to sign for a P2A anchor, any other input it couldn't sign is an error
+        let anchor_script = ScriptBuf::new_p2a();
+        let unsigned: Vec<String> = signed
+            .errors
+            .iter()
+            .flatten()
+            .filter(|e| {
+                !prevouts.iter().any(|(outpoint, txout)| {
+                    outpoint.txid == e.txid
+                        && outpoint.vout == e.vout
+                        && txout.script_pubkey == anchor_script
+                })
+            })
+            .map(|e| format!("{}:{} {}", e.txid, e.vout, e.error))
+            .collect();
+        ensure!(
+            unsigned.is_empty(),
+            "wallet could not sign every input of {}: {:?}",
+            tx.compute_txid(),
+            unsigned
+        );
+        Ok(signed.transaction()?)
+    }
+}
+
+//core takes and returns psbts as base64
+pub fn psbt_to_base64(psbt: &Psbt) -> String {
+    base64::encode(psbt.serialize())
+}
+
+pub fn psbt_from_base64(psbt: &str) -> Result<Psbt> {
+    let bytes = base64::decode(psbt.trim()).context("psbt isn't valid base64")?;
+    Ok(Psbt::deserialize(&bytes)?)
+}
+
+//the txids of a package submitpackage accepted, result is what it returned
+fn package_txids(txs: &[Transaction], result: &serde_json::Value) -> Result<Vec<Txid>> {
+    let package_msg = result["package_msg"].as_str().unwrap_or_default();
+    if package_msg != "success" {
+        let errors: Vec<String> = result["tx-results"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter_map(|(wtxid, tx_result)| {
+                let error = tx_result["error"].as_str()?;
+                Some(format!("{}: {}", wtxid, error))
+            })
+            .collect();
+        bail!("package rejected: {} {:?}", package_msg, errors);
+    }
+
+    Ok(txs.iter().map(|tx| tx.compute_txid()).collect())
+}
+
+//unspent outputs of the node's wallet, mempool included, only the ones paying to filter if given
+fn core_unspent(client: &Client, filter: Option<&[&Address]>) -> Result<Vec<Utxo>> {
+    let unspent = client.list_unspent(Some(0), None, filter, None, None)?;
+    Ok(unspent
+        .into_iter()
+        .map(|utxo| Utxo {
+            outpoint: OutPoint {
+                txid: utxo.txid,
+                vout: utxo.vout,
+            },
+            txout: TxOut {
+                value: utxo.amount,
+                script_pubkey: utxo.script_pub_key,
+            },
+            confirmations: utxo.confirmations,
+        })
+        .collect())
+}
+
+//client for the REST api of an Esplora server (http only), e.g. a local electrs in front of a
+//regtest node. It has no wallet, so it only lists unspent outputs by address and returns NoWallet
+//for anything that needs one
+#[derive(Debug, Clone)]
+pub struct Esplora {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraUtxo {
+    txid: Txid,
+    vout: u32,
+    value: u64,
+    status: EsploraStatus,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraOutspend {
+    spent: bool,
+    txid: Option<Txid>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EsploraStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+}
+
+impl Esplora {
+    //url of the api root, e.g. http://localhost:3002
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<String> {
+        let response = minreq::get(format!("{}{}", self.url, path)).send()?;
+        Self::body(path, response)
+    }
+
+    fn post(&self, path: &str, body: String) -> Result<String> {
+        let response = minreq::post(format!("{}{}", self.url, path))
+            .with_body(body)
+            .send()?;
+        Self::body(path, response)
+    }
+
+    fn body(path: &str, response: minreq::Response) -> Result<String> {
+        let body = response.as_str()?.trim().to_string();
+        ensure!(
+            (200..300).contains(&response.status_code),
+            "esplora {} returned {}: {}",
+            path,
+            response.status_code,
+            body
+        );
+        Ok(body)
+    }
+}
+
+impl ChainBackend for Esplora {
+    fn get_tx(&self, txid: Txid) -> Result<Transaction> {
+        Ok(deserialize_hex(&self.get(&format!("/tx/{}/hex", txid))?)?)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        Ok(self.post("/tx", serialize_hex(tx))?.parse()?)
+    }
+
+    fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>> {
+        let outspend: EsploraOutspend = serde_json::from_str(
+            &self.get(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))?,
+        )?;
+
+        match (outspend.spent, outspend.txid) {
+            (true, Some(txid)) => Ok(Some(self.get_tx(txid)?)),
+            (true, None) => bail!("esplora says {} is spent but not by which tx", outpoint),
+            (false, _) => Ok(None),
+        }
+    }
+
+    //only servers with package relay have this endpoint (e.g. mempool's electrs), it takes the
+    //same json as submitpackage and answers with its result
+    fn submit_package(&self, txs: &[Transaction]) -> Result<Vec<Txid>> {
+        let raw_txs: Vec<String> = txs.iter().map(serialize_hex).collect();
+        let result: serde_json::Value =
+            serde_json::from_str(&self.post("/txs/package", json!(raw_txs).to_string())?)?;
+
+        package_txids(txs, &result)
+    }
+
+    //estimates are in sat/vB for a fixed set of targets, use the closest one at or below conf_target
+    fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>> {
+        let estimates: HashMap<u16, f64> = serde_json::from_str(&self.get("/fee-estimates")?)?;
+
+        let estimate = estimates
+            .iter()
+            .filter(|(&target, _)| target <= conf_target)
+            .max_by_key(|(&target, _)| target)
+            .or_else(|| estimates.iter().min_by_key(|(&target, _)| target));
+
+        Ok(estimate.map(|(_, sat_per_vb)| (sat_per_vb * 1000.0).ceil() as u64))
+    }
+
+    fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>> {
+        let tip_height = self.tip_height()?;
+
+        let mut unspent = Vec::new();
+        for address in addresses {
+            let utxos: Vec<EsploraUtxo> =
+                serde_json::from_str(&self.get(&format!("/address/{}/utxo", address))?)?;
+
+            unspent.extend(utxos.into_iter().map(|utxo| Utxo {
+                outpoint: OutPoint {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                },
+                txout: TxOut {
+                    value: Amount::from_sat(utxo.value),
+                    script_pubkey: address.script_pubkey(),
+                },
+                confirmations: match (utxo.status.confirmed, utxo.status.block_height) {
+                    (true, Some(height)) => tip_height.saturating_sub(height) + 1,
+                    _ => 0,
+                },
+            }));
+        }
+
+        Ok(unspent)
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        Ok(self.get("/blocks/tip/height")?.parse()?)
+    }
+
+    fn tx_confirmations(&self, txid: Txid) -> Result<u32> {
+        let status: EsploraStatus =
+            serde_json::from_str(&self.get(&format!("/tx/{}/status", txid))?)?;
+
+        match (status.confirmed, status.block_height) {
+            (true, Some(height)) => Ok(self.tip_height()?.saturating_sub(height) + 1),
+            _ => Ok(0),
+        }
+    }
+}
+
+//An in-memory chain for running the pool logic without a node. Every accepted tx goes to the
+//mempool and mining a block confirms all of them. Broadcasts are checked for missing or already
+//spent inputs, outputs worth more than the inputs and BIP68 relative timelocks, but scripts are
+//not run, check them with verify::verify_ctv_spend. Outputs created with fund belong to the mock's
+//wallet, which "signs" a tx by returning it unchanged.
+#[derive(Debug, Default)]
+pub struct MockChain {
+    state: Mutex<MockState>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    height: u32,
+    //every accepted tx and the height it confirmed at, None while it is in the mempool
+    txs: HashMap<Txid, (Transaction, Option<u32>)>,
+    //the tx spending each spent output
+    spent: HashMap<OutPoint, Txid>,
+    wallet: Vec<ScriptBuf>,
+    fee_rate: Option<u64>,
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //confirm a new output paying amount to address, like the funding output of a pool
+    pub fn fund(&self, address: &Address, amount: Amount) -> OutPoint {
+        let mut state = self.state.lock().unwrap();
+
+        //a coinbase-like input that makes every funding tx unique
+        let funding_tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new()
+                    .push_int(state.txs.len() as i64)
+                    .into_script(),
+                sequence: Sequence::MAX,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: amount,
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let txid = funding_tx.compute_txid();
+
+        let height = state.height;
+        state.txs.insert(txid, (funding_tx, Some(height)));
+
+        OutPoint { txid, vout: 0 }
+    }
+
+    //fund, and address counts as a wallet address from now on so children can spend it
+    pub fn fund_wallet(&self, address: &Address, amount: Amount) -> OutPoint {
+        let outpoint = self.fund(address, amount);
+
+        let mut state = self.state.lock().unwrap();
+        if !state.wallet.contains(&address.script_pubkey()) {
+            state.wallet.push(address.script_pubkey());
+        }
+        outpoint
+    }
+
+    pub fn set_fee_rate(&self, fee_rate: Option<u64>) {
+        self.state.lock().unwrap().fee_rate = fee_rate;
+    }
+
+    //mine blocks, the first one confirms everything in the mempool
+    pub fn mine(&self, blocks: u32) {
+        let mut state = self.state.lock().unwrap();
+        if blocks == 0 {
+            return;
+        }
+
+        let height = state.height + 1;
+        for (_, confirmed_height) in state.txs.values_mut() {
+            confirmed_height.get_or_insert(height);
+        }
+        state.height += blocks;
+    }
+
+    pub fn mempool(&self) -> Vec<Txid> {
+        let state = self.state.lock().unwrap();
+        state
+            .txs
+            .iter()
+            .filter(|(_, (_, confirmed_height))| confirmed_height.is_none())
+            .map(|(txid, _)| *txid)
+            .collect()
+    }
+
+    //None if the mock hasn't seen the tx, 0 while it is in the mempool
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        let (_, confirmed_height) = state.txs.get(&txid)?;
+        Some(state.confirmations(*confirmed_height))
+    }
+}
+
+impl MockState {
+    fn confirmations(&self, confirmed_height: Option<u32>) -> u32 {
+        confirmed_height.map_or(0, |height| self.height - height + 1)
+    }
+
+    //outputs paying to one of scripts that nothing spends yet
+    fn unspent_paying(&self, scripts: &[ScriptBuf]) -> Vec<Utxo> {
+        let mut unspent = Vec::new();
+        for (txid, (tx, confirmed_height)) in &self.txs {
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint {
+                    txid: *txid,
+                    vout: vout as u32,
+                };
+                if scripts.contains(&output.script_pubkey) && !self.spent.contains_key(&outpoint) {
+                    unspent.push(Utxo {
+                        outpoint,
+                        txout: output.clone(),
+                        confirmations: self.confirmations(*confirmed_height),
+                    });
+                }
+            }
+        }
+
+        unspent
+    }
+
+    //drops txs the mock accepted and frees the outputs they spent
+    fn rollback(&mut self, txids: &[Txid]) {
+        for txid in txids {
+            if let Some((tx, _)) = self.txs.remove(txid) {
+                for input in &tx.input {
+                    self.spent.remove(&input.previous_output);
+                }
+            }
+        }
+    }
+
+    //a 0 sat output of tx nothing spends yet, op_returns don't count
+    fn unspent_dust(&self, tx: &Transaction) -> Option<OutPoint> {
+        let txid = tx.compute_txid();
+        tx.output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| {
+                output.value == Amount::ZERO && !output.script_pubkey.is_op_return()
+            })
+            .map(|(vout, _)| OutPoint {
+                txid,
+                vout: vout as u32,
+            })
+            .find(|outpoint| !self.spent.contains_key(outpoint))
+    }
+
+    fn accept(&mut self, tx: &Transaction) -> Result<Txid> {
+        let txid = tx.compute_txid();
+        if self.txs.contains_key(&txid) {
+            return Ok(txid);
+        }
+
+        let mut input_value = Amount::ZERO;
+        for input in &tx.input {
+            let Some((prev_tx, confirmed_height)) = self.txs.get(&input.previous_output.txid)
+            else {
+                bail!("{} spends unknown output {}", txid, input.previous_output);
+            };
+            let prevout = prev_tx
+                .output
+                .get(input.previous_output.vout as usize)
+                .ok_or_else(|| {
+                    anyhow!("{} spends unknown output {}", txid, input.previous_output)
+                })?;
+            if let Some(spender) = self.spent.get(&input.previous_output) {
+                bail!(
+                    "{} spends {} which {} already spent",
+                    txid,
+                    input.previous_output,
+                    spender
+                );
+            }
+
+            //only block based timelocks, the mock has no block times
+            if tx.version >= transaction::Version::TWO {
+                if let Some(relative::LockTime::Blocks(blocks)) =
+                    input.sequence.to_relative_lock_time()
+                {
+                    let confirmations = self.confirmations(*confirmed_height);
+                    ensure!(
+                        confirmations >= u32::from(blocks.value()),
+                        "{} spends {} after {} of the {} blocks its sequence needs",
+                        txid,
+                        input.previous_output,
+                        confirmations,
+                        blocks.value()
+                    );
+                }
+            }
+
+            input_value += prevout.value;
+        }
+
+        let output_value: Amount = tx.output.iter().map(|output| output.value).sum();
+        ensure!(
+            output_value <= input_value,
+            "{} pays out {} but only spends {}",
+            txid,
+            output_value,
+            input_value
+        );
+
+        for input in &tx.input {
+            self.spent.insert(input.previous_output, txid);
+        }
+        self.txs.insert(txid, (tx.clone(), None));
+        Ok(txid)
+    }
+}
+
+impl ChainBackend for MockChain {
+    fn get_tx(&self, txid: Txid) -> Result<Transaction> {
+        let state = self.state.lock().unwrap();
+        state
+            .txs
+            .get(&txid)
+            .map(|(tx, _)| tx.clone())
+            .ok_or_else(|| anyhow!("unknown tx {}", txid))
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        let mut state = self.state.lock().unwrap();
+        let known = state.txs.contains_key(&tx.compute_txid());
+        let txid = state.accept(tx)?;
+        if known {
+            return Ok(txid);
+        }
+        if let Some(dust) = state.unspent_dust(tx) {
+            state.rollback(&[txid]);
+            bail!("{} leaves ephemeral dust {} unspent", txid, dust);
+        }
+        Ok(txid)
+    }
+
+    fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .spent
+            .get(&outpoint)
+            .map(|txid| state.txs[txid].0.clone()))
+    }
+
+    //all or nothing, like a package
+    fn submit_package(&self, txs: &[Transaction]) -> Result<Vec<Txid>> {
+        let mut state = self.state.lock().unwrap();
+
+        let mut accepted = Vec::new();
+        //txs the mock already had stay when the package is rejected
+        let mut added = Vec::new();
+        for tx in txs {
+            let known = state.txs.contains_key(&tx.compute_txid());
+            match state.accept(tx) {
+                Ok(txid) if known => accepted.push(txid),
+                Ok(txid) => {
+                    added.push(txid);
+                    accepted.push(txid);
+                }
+                Err(e) => {
+                    state.rollback(&added);
+                    return Err(e.context("package rejected"));
+                }
+            }
+        }
+
+        //a 0 sat anchor only relays when the package spends it
+        for tx in txs {
+            if let Some(dust) = state.unspent_dust(tx) {
+                state.rollback(&added);
+                bail!("package rejected: ephemeral dust {} is left unspent", dust);
+            }
+        }
+
+        Ok(accepted)
+    }
+
+    fn estimate_fee_rate(&self, _conf_target: u16) -> Result<Option<u64>> {
+        Ok(self.state.lock().unwrap().fee_rate)
+    }
+
+    fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>> {
+        let scripts: Vec<ScriptBuf> = addresses
+            .iter()
+            .map(|address| address.script_pubkey())
+            .collect();
+        Ok(self.state.lock().unwrap().unspent_paying(&scripts))
+    }
+
+    fn wallet_utxos(&self) -> Result<Vec<Utxo>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.unspent_paying(&state.wallet))
+    }
+
+    fn tip_height(&self) -> Result<u32> {
+        Ok(self.state.lock().unwrap().height)
+    }
+
+    fn tx_confirmations(&self, txid: Txid) -> Result<u32> {
+        self.confirmations(txid)
+            .ok_or_else(|| anyhow!("unknown tx {}", txid))
+    }
+
+    //the mock doesn't check scripts, a wallet input gets a witness the size a real signature has
+    fn sign_wallet_psbt(&self, mut psbt: Psbt) -> Result<Psbt> {
+        let state = self.state.lock().unwrap();
+        for input in &mut psbt.inputs {
+            let Some(prevout) = &input.witness_utxo else {
+                continue;
+            };
+            if input.final_script_witness.is_some()
+                || !state.wallet.contains(&prevout.script_pubkey)
+            {
+                continue;
+            }
+            let witness = if prevout.script_pubkey.is_p2wpkh() {
+                Witness::from_slice(&[vec![0; 72], vec![0; 33]])
+            } else {
+                Witness::from_slice(&[vec![0; 64]])
+            };
+            input.final_script_witness = Some(witness);
+        }
+        Ok(psbt)
+    }
+
+    fn sign_wallet_tx(
+        &self,
+        tx: Transaction,
+        _prevouts: &[(OutPoint, TxOut)],
+    ) -> Result<Transaction> {
+        Ok(tx)
+    }
+}
@@ -0,0 +1,954 @@
+use std::{
+    fs,
+    net::{TcpListener, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bitcoin::{
+    address::NetworkUnchecked,
+    consensus::encode::deserialize_hex,
+    hashes::Hash,
+    psbt::Psbt,
+    secp256k1::{Keypair, Message, PublicKey, SecretKey, XOnlyPublicKey},
+    sighash::TapSighash,
+    Address, Amount, OutPoint, Transaction, Txid,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+use clap::{Args, Subcommand};
+use op_ctv_payment_pool::{
+    chain::{psbt_to_base64, ChainBackend},
+    config::{PoolMode, PoolParamsArgs, DEFAULT_FEE_RATE},
+    ctv_scripts::SECP,
+    funding::{
+        contribute, coordinate_round, join_round, read_psbt, sign_contribution, write_psbt,
+        FundingRound, RoundFile, ROUND_TIMEOUT,
+    },
+    graph::GraphFormat,
+    payment_pool::ExitSigner,
+    pools::{process_pool_spend, process_tree_split},
+    recover::PoolSpend,
+    watchtower::{PoolEvent, Watchtower},
+    Esplora, MemberSet, NetworkConfig, PaymentPool, PoolMember, PoolParams,
+};
+use tracing::{info, warn};
+
+//every step of a pool's life as its own command, so each one can be run by whoever's turn it is
+#[derive(Subcommand)]
+pub enum Command {
+    /// Build a pool from its members' withdraw addresses and save it to a pool file
+    Create(CreateArgs),
+    /// Run one step of the funding round, as the coordinator or as a member
+    #[command(subcommand)]
+    Fund(FundCommand),
+    /// Show where a pool is on chain
+    Status(StatusArgs),
+    /// Exit one member from the pool state that is on chain now
+    Exit(ExitArgs),
+    /// Pay for a pool spend again with a new child at a higher fee rate
+    BumpFee(BumpFeeArgs),
+    /// Print a pool state's descriptor, and a member's exit from it as a psbt
+    Export(ExportArgs),
+    /// Check a tx spends a pool state through one of its ctv leaves
+    Verify(VerifyArgs),
+    /// Draw the pool's states and the exits between them as DOT or Mermaid
+    Graph(GraphArgs),
+    /// Follow a pool on chain until it is closed and print every exit as a json line
+    Watch(WatchArgs),
+}
+
+#[derive(Args)]
+pub struct CreateArgs {
+    /// Pool file to write
+    #[arg(long)]
+    pool_file: PathBuf,
+    #[command(flatten)]
+    params: PoolParamsArgs,
+    /// Every member's withdraw address in member order, this also sets the number of users
+    #[arg(long, value_delimiter = ',', required = true)]
+    withdraw_addresses: Vec<Address<NetworkUnchecked>>,
+    /// Deposit for each member in sats. Defaults to --amount-per-user for everyone
+    #[arg(long, value_delimiter = ',')]
+    deposits: Option<Vec<u64>>,
+    /// X-only exit key for each member, their exits then need their signature
+    #[arg(long, value_delimiter = ',')]
+    exit_keys: Option<Vec<XOnlyPublicKey>>,
+    /// Musig key for each member, so they can close the pool together with one key path spend
+    #[arg(long, value_delimiter = ',')]
+    musig_keys: Option<Vec<PublicKey>>,
+}
+
+#[derive(Subcommand)]
+pub enum FundCommand {
+    /// Coordinator: start a round in --dir with the proposal paying the pool's funding address
+    Propose {
+        #[command(flatten)]
+        round: RoundArgs,
+        /// Fee rate of the funding tx in sat/kvB, defaults to the node's estimate
+        #[arg(long)]
+        fee_rate: Option<u64>,
+    },
+    /// Member: add their inputs and change from their wallet to the proposal in --dir
+    Contribute {
+        #[command(flatten)]
+        round: RoundArgs,
+        #[command(flatten)]
+        member: MemberArgs,
+    },
+    /// Coordinator: join every contribution in --dir into the unsigned funding psbt
+    Join {
+        #[command(flatten)]
+        round: RoundArgs,
+    },
+    /// Member: check the unsigned funding psbt in --dir and sign their inputs of it
+    Sign {
+        #[command(flatten)]
+        round: RoundArgs,
+        #[command(flatten)]
+        member: MemberArgs,
+    },
+    /// Coordinator: combine every signature in --dir into the funding tx
+    Finalize {
+        #[command(flatten)]
+        round: RoundArgs,
+        /// Broadcast the funding tx instead of only printing it
+        #[arg(long)]
+        broadcast: bool,
+    },
+    /// Coordinator: run the whole round over a local socket and broadcast the funding tx
+    Serve {
+        /// Pool file of the pool to fund
+        #[arg(long)]
+        pool_file: PathBuf,
+        /// Address to listen on, e.g. 127.0.0.1:18500
+        #[arg(long)]
+        listen: String,
+        /// Fee rate of the funding tx in sat/kvB, defaults to the node's estimate
+        #[arg(long)]
+        fee_rate: Option<u64>,
+        /// Seconds to wait for every contribution and for each member's reply
+        #[arg(long, default_value_t = ROUND_TIMEOUT.as_secs())]
+        timeout: u64,
+    },
+    /// Member: take part in a round served over a socket
+    Connect {
+        /// Pool file of the pool to fund
+        #[arg(long)]
+        pool_file: PathBuf,
+        /// Address the coordinator listens on
+        #[arg(long)]
+        connect: String,
+        #[command(flatten)]
+        member: MemberArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct RoundArgs {
+    /// Pool file of the pool to fund
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Directory the round's psbt files are exchanged in
+    #[arg(long, default_value = "funding_round")]
+    dir: PathBuf,
+}
+
+#[derive(Args)]
+pub struct MemberArgs {
+    /// Index of the member in the pool
+    #[arg(long)]
+    member: usize,
+    /// Core wallet the member pays from
+    #[arg(long)]
+    wallet: String,
+    /// The withdraw address the member picked, checked against the pool before signing
+    #[arg(long)]
+    withdraw_address: Address<NetworkUnchecked>,
+    /// The deposit the member agreed to in sats, checked against the pool before signing
+    #[arg(long)]
+    deposit: u64,
+    /// The pool params the member agreed to, the pool file's have to match them before signing
+    #[command(flatten)]
+    params: PoolParamsArgs,
+}
+
+#[derive(Args)]
+pub struct ChainArgs {
+    /// Esplora server to read the chain from instead of the Core node
+    #[arg(long)]
+    esplora: Option<String>,
+}
+
+#[derive(Args)]
+pub struct StatusArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Funding outpoint (txid:vout) of the pool
+    #[arg(long)]
+    funding: OutPoint,
+    #[command(flatten)]
+    chain: ChainArgs,
+}
+
+#[derive(Args)]
+pub struct ExitArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Funding outpoint (txid:vout) of the pool
+    #[arg(long)]
+    funding: OutPoint,
+    /// Index of the member leaving
+    #[arg(long)]
+    member: usize,
+    /// Secret key for the member's exit key, if the pool gave them one
+    #[arg(long)]
+    exit_secret_key: Option<SecretKey>,
+}
+
+#[derive(Args)]
+pub struct BumpFeeArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Txid of the pool spend to pay for
+    #[arg(long)]
+    txid: Txid,
+    /// Fee rate for the pool spend and its new child together, in sat/kvB
+    #[arg(long)]
+    fee_rate: u64,
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Members of the pool state, e.g. 0,2,3. Defaults to the funding state with everyone
+    #[arg(long, value_delimiter = ',')]
+    users: Option<Vec<usize>>,
+    /// Also print this member's exit from the state as a base64 psbt
+    #[arg(long, requires = "outpoint")]
+    member: Option<usize>,
+    /// Outpoint (txid:vout) of the pool state the exit psbt spends
+    #[arg(long)]
+    outpoint: Option<OutPoint>,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Members of the pool state the tx spends, e.g. 0,2,3. Defaults to the funding state
+    #[arg(long, value_delimiter = ',')]
+    users: Option<Vec<usize>>,
+    /// The tx as hex
+    #[arg(long, conflicts_with = "txid", required_unless_present = "txid")]
+    tx_hex: Option<String>,
+    /// Fetch the tx from the chain instead
+    #[arg(long)]
+    txid: Option<Txid>,
+    #[command(flatten)]
+    chain: ChainArgs,
+}
+
+#[derive(Args)]
+pub struct GraphArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+    format: GraphFormat,
+    /// Only draw the states reachable from the funding state within this many exits
+    #[arg(long, conflicts_with = "funding")]
+    max_exits: Option<usize>,
+    /// Only draw the path the pool funded at this outpoint (txid:vout) took on chain
+    #[arg(long)]
+    funding: Option<OutPoint>,
+    #[command(flatten)]
+    chain: ChainArgs,
+}
+
+#[derive(Args)]
+pub struct WatchArgs {
+    #[arg(long)]
+    pool_file: PathBuf,
+    /// Funding outpoint (txid:vout) of the pool
+    #[arg(long)]
+    funding: OutPoint,
+    /// Where the watchtower saves how far it got, so a restart carries on from there
+    #[arg(long, default_value = "watchtower.json")]
+    watch_file: PathBuf,
+    /// Seconds between the watchtower's checks of the chain
+    #[arg(long, default_value_t = 10)]
+    poll_interval: u64,
+    #[command(flatten)]
+    chain: ChainArgs,
+}
+
+pub fn run(command: Command) -> Result<()> {
+    let config = NetworkConfig::new();
+
+    match command {
+        Command::Create(args) => create(&config, args),
+        Command::Fund(command) => fund(&config, command),
+        Command::Status(args) => status(&config, args),
+        Command::Exit(args) => exit(&config, args),
+        Command::BumpFee(args) => bump_fee(&config, args),
+        Command::Export(args) => export(args),
+        Command::Verify(args) => verify(&config, args),
+        Command::Graph(args) => graph(&config, args),
+        Command::Watch(args) => watch(&config, args),
+    }
+}
+
+pub fn load_pool(config: &NetworkConfig, path: &Path) -> Result<PaymentPool> {
+    let pool = PaymentPool::load(path)?;
+    ensure!(
+        pool.network() == config.network,
+        "pool file is for {}, not {}",
+        pool.network(),
+        config.network
+    );
+    Ok(pool)
+}
+
+fn chain_backend(config: &NetworkConfig, args: &ChainArgs) -> Result<Box<dyn ChainBackend>> {
+    Ok(match &args.esplora {
+        Some(url) => Box::new(Esplora::new(url)),
+        None => Box::new(config.bitcoin_rpc()?),
+    })
+}
+
+fn wallet_rpc(config: &NetworkConfig, wallet: &str) -> Result<Client> {
+    let wallet_config = NetworkConfig {
+        wallet_name: wallet.to_string(),
+        ..config.clone()
+    };
+    Ok(wallet_config.bitcoin_rpc()?)
+}
+
+fn users_arg(pool: &PaymentPool, users: Option<Vec<usize>>) -> MemberSet {
+    match users {
+        Some(users) => users.into_iter().collect(),
+        None => MemberSet::full(pool.params().users),
+    }
+}
+
+//a list with one entry per member, or nothing
+fn per_member<T>(values: Option<Vec<T>>, users: usize, name: &str) -> Result<Vec<Option<T>>> {
+    match values {
+        Some(values) => {
+            ensure!(
+                values.len() == users,
+                "got {} {} for {} members",
+                values.len(),
+                name,
+                users
+            );
+            Ok(values.into_iter().map(Some).collect())
+        }
+        None => Ok((0..users).map(|_| None).collect()),
+    }
+}
+
+fn create(config: &NetworkConfig, mut args: CreateArgs) -> Result<()> {
+    let users = args.withdraw_addresses.len();
+    args.params.overrides.users.get_or_insert(users);
+    let params = PoolParams::load(&args.params)?;
+    let withdraw_addresses = args
+        .withdraw_addresses
+        .into_iter()
+        .map(|address| address.require_network(config.network))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let pool = build_pool(
+        config,
+        params,
+        withdraw_addresses,
+        args.deposits,
+        args.exit_keys,
+        args.musig_keys,
+    )?;
+    pool.save(&args.pool_file)?;
+
+    println!("funding address: {}", pool.funding_address()?);
+    println!("funding amount: {} sat", pool.funding_amount().to_sat());
+    println!("pool file: {}", args.pool_file.display());
+    Ok(())
+}
+
+//a pool paying out to withdraw_addresses in member order. Deposits default to
+//params.amount_per_user, the keys are optional but have to be given for every member when they are
+pub fn build_pool(
+    config: &NetworkConfig,
+    params: PoolParams,
+    withdraw_addresses: Vec<Address>,
+    deposits: Option<Vec<u64>>,
+    exit_keys: Option<Vec<XOnlyPublicKey>>,
+    musig_keys: Option<Vec<PublicKey>>,
+) -> Result<PaymentPool> {
+    let users = withdraw_addresses.len();
+    ensure!(
+        params.users == users,
+        "params are for {} users but {} withdraw addresses were given",
+        params.users,
+        users
+    );
+
+    let deposits = match deposits {
+        Some(deposits) => per_member(Some(deposits), users, "deposits")?
+            .into_iter()
+            .flatten()
+            .map(Amount::from_sat)
+            .collect(),
+        None => vec![params.amount_per_user; users],
+    };
+    let exit_keys = per_member(exit_keys, users, "exit keys")?;
+    let musig_keys = per_member(musig_keys, users, "musig keys")?;
+
+    let mut members = Vec::new();
+    for (((address, deposit), exit_key), musig_key) in withdraw_addresses
+        .into_iter()
+        .zip(deposits)
+        .zip(exit_keys)
+        .zip(musig_keys)
+    {
+        let mut member = PoolMember::new(address, deposit);
+        if let Some(exit_key) = exit_key {
+            member = member.with_exit_key(exit_key);
+        }
+        if let Some(musig_key) = musig_key {
+            member = member.with_musig_key(musig_key);
+        }
+        members.push(member);
+    }
+
+    let anchor_addr = Address::from_str(config.fee_anchor_addr)?.require_network(config.network)?;
+    PaymentPool::new(params, config.network, members, anchor_addr)
+}
+
+pub fn round_fee_rate(config: &NetworkConfig, fee_rate: Option<u64>) -> Result<u64> {
+    match fee_rate {
+        Some(fee_rate) => Ok(fee_rate),
+        None => Ok(config
+            .bitcoin_rpc()?
+            .estimate_fee_rate(1)
+            .ok()
+            .flatten()
+            .unwrap_or(DEFAULT_FEE_RATE)),
+    }
+}
+
+//the member as they picked themselves, with the keys the pool has for them
+fn own_member(config: &NetworkConfig, pool: &PaymentPool, args: &MemberArgs) -> Result<PoolMember> {
+    let member = pool
+        .members()
+        .get(args.member)
+        .ok_or_else(|| anyhow!("pool has no member {}", args.member))?;
+    Ok(PoolMember {
+        withdraw_address: args
+            .withdraw_address
+            .clone()
+            .require_network(config.network)?,
+        deposit: Amount::from_sat(args.deposit),
+        ..member.clone()
+    })
+}
+
+//member's side of the round from their own wallet, change goes back to it
+pub fn member_contribution(
+    pool: &PaymentPool,
+    wallet: &Client,
+    own: &PoolMember,
+    member: usize,
+    proposal: &Psbt,
+) -> Result<Psbt> {
+    let change_addr = wallet
+        .get_raw_change_address(None)?
+        .require_network(pool.network())?;
+    contribute(
+        proposal,
+        member,
+        own.deposit,
+        wallet.wallet_utxos()?,
+        &change_addr,
+    )
+}
+
+//member's side of a round served over a socket, signing only after checking the funding psbt
+//against own and the params they agreed to
+pub fn connect_member(
+    addr: impl ToSocketAddrs,
+    pool: &PaymentPool,
+    wallet: &Client,
+    own: &PoolMember,
+    agreed: &PoolParams,
+    member: usize,
+) -> Result<()> {
+    join_round(
+        addr,
+        |proposal| member_contribution(pool, wallet, own, member, proposal),
+        |unsigned, contribution| {
+            sign_contribution(wallet, pool, agreed, own, unsigned, contribution)
+        },
+    )
+}
+
+//The steps of a round through psbt files in dir, each one writes the file the next reads and
+//returns its path. The fund subcommands run one step each, the demo runs them all in turn
+
+pub fn propose_round(pool: &PaymentPool, dir: &Path, fee_rate: u64) -> Result<PathBuf> {
+    let funding = FundingRound::new(pool, fee_rate)?;
+    fs::create_dir_all(dir)?;
+    let path = RoundFile::Proposal.path(dir);
+    write_psbt(&path, funding.proposal())?;
+    Ok(path)
+}
+
+pub fn contribute_to_round(
+    pool: &PaymentPool,
+    wallet: &Client,
+    own: &PoolMember,
+    member: usize,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let proposal = read_psbt(&RoundFile::Proposal.path(dir))?;
+    let contribution = member_contribution(pool, wallet, own, member, &proposal)?;
+    let path = RoundFile::Contribution(member).path(dir);
+    write_psbt(&path, &contribution)?;
+    Ok(path)
+}
+
+pub fn join_contributions(pool: &PaymentPool, dir: &Path) -> Result<PathBuf> {
+    let mut funding = load_round(pool, dir)?;
+    let path = RoundFile::Unsigned.path(dir);
+    write_psbt(&path, &funding.unsigned_psbt()?)?;
+    Ok(path)
+}
+
+pub fn sign_round(
+    pool: &PaymentPool,
+    wallet: &Client,
+    own: &PoolMember,
+    agreed: &PoolParams,
+    member: usize,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let unsigned = read_psbt(&RoundFile::Unsigned.path(dir))?;
+    let contribution = read_psbt(&RoundFile::Contribution(member).path(dir))?;
+    let signed = sign_contribution(wallet, pool, agreed, own, &unsigned, &contribution)?;
+    let path = RoundFile::Signed(member).path(dir);
+    write_psbt(&path, &signed)?;
+    Ok(path)
+}
+
+pub fn finalize_round(pool: &PaymentPool, dir: &Path) -> Result<Transaction> {
+    let mut funding = load_round(pool, dir)?;
+    ensure!(
+        funding.unsigned_psbt()? == read_psbt(&RoundFile::Unsigned.path(dir))?,
+        "unsigned funding psbt in {} doesn't match the contributions",
+        dir.display()
+    );
+    for member in 0..pool.members().len() {
+        let path = RoundFile::Signed(member).path(dir);
+        if path.exists() {
+            funding.add_signature(read_psbt(&path)?)?;
+        }
+    }
+    funding.finalize()
+}
+
+//the coordinator's round with every file in dir that is there so far
+fn load_round(pool: &PaymentPool, dir: &Path) -> Result<FundingRound> {
+    let mut round = FundingRound::from_proposal(pool, &read_psbt(&RoundFile::Proposal.path(dir))?)?;
+    for member in 0..pool.members().len() {
+        let path = RoundFile::Contribution(member).path(dir);
+        if path.exists() {
+            round.add_contribution(read_psbt(&path)?)?;
+        }
+    }
+    Ok(round)
+}
+
+fn fund(config: &NetworkConfig, command: FundCommand) -> Result<()> {
+    match command {
+        FundCommand::Propose { round, fee_rate } => {
+            let pool = load_pool(config, &round.pool_file)?;
+            let path = propose_round(&pool, &round.dir, round_fee_rate(config, fee_rate)?)?;
+            println!("proposal written to {}", path.display());
+        }
+        FundCommand::Contribute { round, member } => {
+            let pool = load_pool(config, &round.pool_file)?;
+            let own = own_member(config, &pool, &member)?;
+            let wallet = wallet_rpc(config, &member.wallet)?;
+            let path = contribute_to_round(&pool, &wallet, &own, member.member, &round.dir)?;
+            println!("contribution written to {}", path.display());
+        }
+        FundCommand::Join { round } => {
+            let pool = load_pool(config, &round.pool_file)?;
+            let path = join_contributions(&pool, &round.dir)?;
+            println!("unsigned funding psbt written to {}", path.display());
+        }
+        FundCommand::Sign { round, member } => {
+            let pool = load_pool(config, &round.pool_file)?;
+            let own = own_member(config, &pool, &member)?;
+            let wallet = wallet_rpc(config, &member.wallet)?;
+            let agreed = PoolParams::load(&member.params)?;
+            let path = sign_round(&pool, &wallet, &own, &agreed, member.member, &round.dir)?;
+            println!("signed funding psbt written to {}", path.display());
+        }
+        FundCommand::Finalize { round, broadcast } => {
+            let pool = load_pool(config, &round.pool_file)?;
+            let tx = finalize_round(&pool, &round.dir)?;
+            print_funding(config, &tx, broadcast)?;
+        }
+        FundCommand::Serve {
+            pool_file,
+            listen,
+            fee_rate,
+            timeout,
+        } => {
+            let pool = load_pool(config, &pool_file)?;
+            let mut funding = FundingRound::new(&pool, round_fee_rate(config, fee_rate)?)?;
+            let listener = TcpListener::bind(&listen)?;
+            info!("funding round listening on {} \n", listen);
+            let tx = coordinate_round(&listener, &mut funding, Duration::from_secs(timeout))?;
+            print_funding(config, &tx, true)?;
+        }
+        FundCommand::Connect {
+            pool_file,
+            connect,
+            member,
+        } => {
+            let pool = load_pool(config, &pool_file)?;
+            let own = own_member(config, &pool, &member)?;
+            let agreed = PoolParams::load(&member.params)?;
+            let wallet = wallet_rpc(config, &member.wallet)?;
+            connect_member(connect, &pool, &wallet, &own, &agreed, member.member)?;
+            println!("member {} signed the funding tx", member.member);
+        }
+    }
+    Ok(())
+}
+
+fn print_funding(config: &NetworkConfig, tx: &Transaction, broadcast: bool) -> Result<()> {
+    let txid = tx.compute_txid();
+    if broadcast {
+        config.bitcoin_rpc()?.broadcast(tx)?;
+        println!("funding tx broadcast: {}", txid);
+    } else {
+        println!("{}", bitcoin::consensus::encode::serialize_hex(tx));
+    }
+    //the pool output is always the first one
+    println!("funding outpoint: {}:0", txid);
+    Ok(())
+}
+
+fn status(config: &NetworkConfig, args: StatusArgs) -> Result<()> {
+    let pool = load_pool(config, &args.pool_file)?;
+    let chain = chain_backend(config, &args.chain)?;
+
+    println!("pool funded at {}", args.funding);
+    if pool.params().mode == PoolMode::SplitTree {
+        return tree_status(&pool, chain.as_ref(), args.funding);
+    }
+
+    let recovered = pool.recover(chain.as_ref(), args.funding)?;
+    for spend in &recovered.spends {
+        match spend {
+            PoolSpend::Exit { member, txid } => println!("member {} exited in {}", member, txid),
+            PoolSpend::FinalExit { txid } => println!("last 2 members exited in {}", txid),
+            PoolSpend::Split { txid } => println!("pool split in {}", txid),
+            PoolSpend::Cooperative { txid } => println!("pool closed cooperatively in {}", txid),
+        }
+    }
+    match recovered.state {
+        Some(state) => println!(
+            "members {:?} are in {} holding {} sat",
+            state.users,
+            state.outpoint,
+            state.amount.to_sat()
+        ),
+        None => println!("pool is closed"),
+    }
+    Ok(())
+}
+
+//a split tree has no single pool state, every member is in the unspent node on their path
+fn tree_status(pool: &PaymentPool, chain: &dyn ChainBackend, funding: OutPoint) -> Result<()> {
+    for member in 0..pool.params().users {
+        match pool
+            .tree_unspent_node(chain, funding, member)?
+            .into_iter()
+            .next()
+        {
+            Some((node, outpoint)) => {
+                println!("member {} is in node {:?} at {}", member, node, outpoint)
+            }
+            None => println!("member {} has been paid out", member),
+        }
+    }
+    Ok(())
+}
+
+fn exit(config: &NetworkConfig, args: ExitArgs) -> Result<()> {
+    let pool = load_pool(config, &args.pool_file)?;
+    let rpc = config.bitcoin_rpc()?;
+
+    if pool.params().mode == PoolMode::SplitTree {
+        return tree_exit(&pool, &rpc, args);
+    }
+
+    let Some(state) = pool.recover(&rpc, args.funding)?.state else {
+        bail!("pool is already closed");
+    };
+    ensure!(
+        state.users.contains(args.member),
+        "member {} isn't in the pool anymore, members {:?} are",
+        args.member,
+        state.users
+    );
+
+    let has_exit_key = pool.members()[args.member].exit_key.is_some();
+    ensure!(
+        has_exit_key == args.exit_secret_key.is_some(),
+        "member {} {} an exit key, pass --exit-secret-key only for members with one",
+        args.member,
+        if has_exit_key { "has" } else { "doesn't have" }
+    );
+    let keypair = args
+        .exit_secret_key
+        .map(|seckey| Keypair::from_secret_key(&SECP, &seckey));
+    let exit_signer = |_member: usize, sighash: TapSighash| {
+        let keypair = keypair.as_ref().expect("checked above");
+        let message = Message::from_digest(sighash.to_byte_array());
+        Ok(SECP.sign_schnorr(&message, keypair))
+    };
+    let signer: Option<&ExitSigner> = keypair.map(|_| &exit_signer as &ExitSigner);
+
+    let txid = process_pool_spend(
+        &pool,
+        &rpc,
+        &state.users,
+        args.member,
+        state.outpoint.txid,
+        signer,
+    )?;
+    println!("member {} exited in {}", args.member, txid);
+    Ok(())
+}
+
+//split the first node still unspent on the member's path, nobody signs anything. Each split spends
+//the one before it, so run exit again once it confirms until the member is paid out
+fn tree_exit(pool: &PaymentPool, rpc: &Client, args: ExitArgs) -> Result<()> {
+    ensure!(
+        args.exit_secret_key.is_none(),
+        "a split tree pool has no exit keys"
+    );
+
+    let mut nodes = pool.tree_unspent_node(rpc, args.funding, args.member)?;
+    let Some(txid) = process_tree_split(pool, rpc, &mut nodes, args.member)? else {
+        bail!("member {} has already been paid out", args.member);
+    };
+    if nodes.keys().any(|node| node.contains(args.member)) {
+        println!(
+            "member {} split out in {}, run exit again once it confirms",
+            args.member, txid
+        );
+    } else {
+        println!("member {} exited in {}", args.member, txid);
+    }
+    Ok(())
+}
+
+fn bump_fee(config: &NetworkConfig, args: BumpFeeArgs) -> Result<()> {
+    ensure!(
+        cfg!(feature = "p2a"),
+        "pool spends only have an anchor to pay through with the p2a feature"
+    );
+
+    let pool = load_pool(config, &args.pool_file)?;
+    let rpc = config.bitcoin_rpc()?;
+    let parent = rpc.get_tx(args.txid)?;
+    let child = op_ctv_payment_pool::pools::cpfp_child_at(
+        &rpc,
+        pool.anchor_addr(),
+        &parent,
+        args.fee_rate,
+    )?;
+    //the new child replaces the one already spending the anchor
+    rpc.submit_package(&[parent, child.clone()])?;
+    println!("new child {} pays for {}", child.compute_txid(), args.txid);
+    Ok(())
+}
+
+fn export(args: ExportArgs) -> Result<()> {
+    let pool = PaymentPool::load(&args.pool_file)?;
+    let users = users_arg(&pool, args.users);
+
+    println!("{}", pool.state_descriptor(&users)?);
+    if let (Some(member), Some(outpoint)) = (args.member, args.outpoint) {
+        println!(
+            "{}",
+            psbt_to_base64(&pool.exit_psbt(&users, member, outpoint)?)
+        );
+    }
+    Ok(())
+}
+
+fn verify(config: &NetworkConfig, args: VerifyArgs) -> Result<()> {
+    let pool = load_pool(config, &args.pool_file)?;
+    let users = users_arg(&pool, args.users);
+
+    let tx: Transaction = match (&args.tx_hex, args.txid) {
+        (Some(hex), _) => deserialize_hex(hex.trim())?,
+        (None, Some(txid)) => chain_backend(config, &args.chain)?.get_tx(txid)?,
+        (None, None) => bail!("pass --tx-hex or --txid"),
+    };
+
+    let spend = pool.verify_spend(&tx, &users)?;
+    println!("{} spends pool state {:?}", tx.compute_txid(), users);
+    println!("leaf: {}", spend.leaf_script);
+    println!("template hash: {}", spend.template_hash);
+    if let Some(signer) = spend.signer {
+        println!("signed by: {}", signer);
+    }
+    Ok(())
+}
+
+fn graph(config: &NetworkConfig, args: GraphArgs) -> Result<()> {
+    let pool = load_pool(config, &args.pool_file)?;
+    let graph = match args.funding {
+        Some(funding) => pool.path_graph(chain_backend(config, &args.chain)?.as_ref(), funding)?,
+        None => pool.state_graph(args.max_exits)?,
+    };
+    print!("{}", graph.render(args.format));
+    Ok(())
+}
+
+fn watch(config: &NetworkConfig, args: WatchArgs) -> Result<()> {
+    let pool = load_pool(config, &args.pool_file)?;
+    let chain = chain_backend(config, &args.chain)?;
+    let mut watchtower = Watchtower::new(&pool, chain.as_ref(), args.funding, &args.watch_file)?;
+
+    watchtower.run(Duration::from_secs(args.poll_interval), |event| {
+        //plain fields and txids, nothing in an event can fail to serialize
+        println!(
+            "{}",
+            serde_json::to_string(event).expect("pool events serialize")
+        );
+        if let PoolEvent::SpendPending {
+            txid,
+            fee_rate,
+            needs_fee_bump: true,
+            ..
+        } = event
+        {
+            warn!(
+                "{} only pays {} sat/kvB, pay for it with bump-fee --txid {} \n",
+                txid, fee_rate, txid
+            );
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+    use clap::Parser;
+    use op_ctv_payment_pool::ctv_scripts::SECP;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(subcommand)]
+        command: Command,
+    }
+
+    fn parse(args: &str) -> Result<Command, clap::Error> {
+        TestCli::try_parse_from(["pool"].into_iter().chain(args.split_whitespace()))
+            .map(|cli| cli.command)
+    }
+
+    fn config() -> NetworkConfig {
+        NetworkConfig {
+            network: Network::Regtest,
+            port: "18443",
+            fee_anchor_addr: "bcrt1pfeesnyr2tx",
+            wallet_name: "test".to_string(),
+        }
+    }
+
+    fn address() -> Address {
+        let (_, pubkey) = SECP.generate_keypair(&mut rand::thread_rng());
+        Address::p2tr(&SECP, pubkey.x_only_public_key().0, None, Network::Regtest)
+    }
+
+    #[test]
+    fn each_step_is_its_own_subcommand() {
+        let funding = format!("{}:0", Txid::all_zeros());
+
+        let Command::Exit(args) = parse(&format!(
+            "exit --pool-file pool.json --funding {} --member 2",
+            funding
+        ))
+        .unwrap() else {
+            panic!("not an exit");
+        };
+        assert_eq!(args.member, 2);
+        assert!(args.exit_secret_key.is_none());
+
+        //a member has to say what they agreed to before they can sign
+        assert!(parse("fund sign --pool-file pool.json --member 0 --wallet w").is_err());
+        let sign = format!(
+            "fund sign --pool-file pool.json --member 1 --wallet w --withdraw-address {} --deposit 20000 --users 4",
+            address()
+        );
+        let Command::Fund(FundCommand::Sign { round, member }) = parse(&sign).unwrap() else {
+            panic!("not fund sign");
+        };
+        assert_eq!(round.dir, PathBuf::from("funding_round"));
+        assert_eq!(member.member, 1);
+        assert_eq!(PoolParams::load(&member.params).unwrap().users, 4);
+
+        assert!(parse("exit --pool-file pool.json --member 2").is_err());
+        let graph = format!("graph --pool-file pool.json --funding {}", funding);
+        assert!(parse(&graph).is_ok());
+        assert!(parse(&format!("{} --max-exits 1", graph)).is_err());
+    }
+
+    #[test]
+    fn own_member_is_what_the_member_picked_with_the_pools_keys() {
+        let config = config();
+        let params = PoolParams {
+            users: 3,
+            ..Default::default()
+        };
+        let (_, musig_key) = SECP.generate_keypair(&mut rand::thread_rng());
+        let members = (0..3)
+            .map(|_| PoolMember::new(address(), Amount::from_sat(20000)).with_musig_key(musig_key))
+            .collect();
+        let anchor = Address::from_str(config.fee_anchor_addr)
+            .unwrap()
+            .require_network(config.network)
+            .unwrap();
+        let pool = PaymentPool::new(params, config.network, members, anchor).unwrap();
+
+        let picked = address();
+        let args = |member| MemberArgs {
+            member,
+            wallet: "w".to_string(),
+            withdraw_address: picked.as_unchecked().clone(),
+            deposit: 25000,
+            params: PoolParamsArgs::default(),
+        };
+
+        let own = own_member(&config, &pool, &args(1)).unwrap();
+        assert_eq!(own.withdraw_address, picked);
+        assert_eq!(own.deposit, Amount::from_sat(25000));
+        assert_eq!(own.musig_key, Some(musig_key));
+
+        let err = own_member(&config, &pool, &args(3)).unwrap_err();
+        assert_eq!(err.to_string(), "pool has no member 3");
+    }
+}
@@ -1,6 +1,11 @@
+use anyhow::{Context, Result};
 use bitcoin::{Amount, Network};
 use bitcoincore_rpc::{Auth, Client, Error, RpcApi};
-use std::{env, path::PathBuf};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "signet")]
+use std::env;
+use std::{fmt, fs, path::Path, path::PathBuf};
 use tracing::{error, info};
 
 // https://bitcoinops.org/en/bitcoin-core-28-wallet-integration-guide/
@@ -8,26 +13,247 @@ use tracing::{error, info};
 // regtest: bcrt1pfeesnyr2tx
 // testnet: tb1pfees9rn5nz
 
-//this could be 240 for P2A but we set for 1000 for now so it works on signet with hard coded fee
-pub const FEE_AMOUNT: Amount = Amount::from_sat(1000);
-pub const DUST_AMOUNT: Amount = Amount::from_sat(546);
 pub const DEFAULT_FEE_RATE: u64 = 5000;
 
-//send a bit more so we can cover the fees for the pool funding transaction
-pub const INIT_WALLET_AMOUNT_FEE: Amount = Amount::from_sat(2000);
-
-//must be 3 or more. You can do maybe up to 20, but it will take a very long time to compute all taproot addresses
-pub const POOL_USERS: usize = 10;
-
-//has to be more than FEE_AMOUNT + DUST_AMOUNT
-pub const AMOUNT_PER_USER: Amount = Amount::from_sat(11000);
+//a pool needs at least 3 users, the last pool state always lets the final 2 users exit together
+pub const MIN_POOL_USERS: usize = 3;
 
-#[cfg(feature = "signet")]
+//with p2a the pool spends are v3 with an anchor output and pay their fee through a child,
+//without it they are v2 and pay fee_amount themselves
+#[cfg(not(feature = "p2a"))]
 pub const TX_VERSION: i32 = 2;
 
-#[cfg(feature = "regtest")]
+#[cfg(feature = "p2a")]
 pub const TX_VERSION: i32 = 3;
 
+//how a pool spend pays its fee. anchor keeps fee_amount out of the spend for the anchor output,
+//ephemeral_anchor leaves a 0 sat anchor (ephemeral dust) and the child pays the whole package fee
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeStrategy {
+    #[default]
+    Anchor,
+    EphemeralAnchor,
+}
+
+//how the pool states are laid out. exact has a state for every subset of users so each exit is one
+//tx leaving everyone else in the next state, split_tree halves the pool in each spend so there are
+//only users - 1 states and a member gets out in log2(users) txs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolMode {
+    #[default]
+    Exact,
+    SplitTree,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolParams {
+    //must be 3 or more. You can do maybe up to 20, but it will take a very long time to compute all taproot addresses
+    pub users: usize,
+    //deposit for users who don't pick their own, has to be more than fee_amount + dust_amount
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount_per_user: Amount,
+    //this could be 240 for P2A but we set for 1000 for now so it works on signet with hard coded fee
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee_amount: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub dust_amount: Amount,
+    //send a bit more so we can cover the fees for the pool funding transaction
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub init_wallet_amount_fee: Amount,
+    //blocks a pool state has to sit unspent before anyone can split it between its members, 0 turns the split off
+    pub split_timeout: u16,
+    //ephemeral_anchor needs p2a, the v3 spend is what lets the 0 fee parent relay with its child
+    pub fee_strategy: FeeStrategy,
+    pub mode: PoolMode,
+}
+
+impl Default for PoolParams {
+    fn default() -> Self {
+        Self {
+            users: 10,
+            amount_per_user: Amount::from_sat(11000),
+            fee_amount: Amount::from_sat(1000),
+            dust_amount: Amount::from_sat(546),
+            init_wallet_amount_fee: Amount::from_sat(2000),
+            split_timeout: 1008,
+            fee_strategy: FeeStrategy::Anchor,
+            mode: PoolMode::Exact,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolParamsError {
+    TooFewUsers {
+        users: usize,
+    },
+    AmountTooSmall {
+        amount: Amount,
+        fee_amount: Amount,
+        dust_amount: Amount,
+    },
+    EphemeralAnchorWithoutP2a,
+}
+
+impl fmt::Display for PoolParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolParamsError::TooFewUsers { users } => write!(
+                f,
+                "pool must have at least {} users, got {}",
+                MIN_POOL_USERS, users
+            ),
+            PoolParamsError::AmountTooSmall {
+                amount,
+                fee_amount,
+                dust_amount,
+            } => write!(
+                f,
+                "deposit ({}) must be more than the fee amount ({}) + dust amount ({})",
+                amount.to_sat(),
+                fee_amount.to_sat(),
+                dust_amount.to_sat()
+            ),
+            PoolParamsError::EphemeralAnchorWithoutP2a => write!(
+                f,
+                "the ephemeral_anchor fee strategy needs a build with the p2a feature"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PoolParamsError {}
+
+impl PoolParams {
+    pub fn validate(&self) -> Result<(), PoolParamsError> {
+        if self.users < MIN_POOL_USERS {
+            return Err(PoolParamsError::TooFewUsers { users: self.users });
+        }
+
+        #[cfg(not(feature = "p2a"))]
+        if self.fee_strategy == FeeStrategy::EphemeralAnchor {
+            return Err(PoolParamsError::EphemeralAnchorWithoutP2a);
+        }
+
+        self.validate_deposit(self.amount_per_user)
+    }
+
+    //what a pool spend keeps back from its outputs for the fee, 0 when the child pays all of it
+    pub fn exit_fee(&self) -> Amount {
+        match self.fee_strategy {
+            FeeStrategy::Anchor => self.fee_amount,
+            FeeStrategy::EphemeralAnchor => Amount::ZERO,
+        }
+    }
+
+    //every member pays the exit fee out of their own deposit, so it has to leave more than dust
+    pub fn validate_deposit(&self, deposit: Amount) -> Result<(), PoolParamsError> {
+        if deposit <= self.exit_fee() + self.dust_amount {
+            return Err(PoolParamsError::AmountTooSmall {
+                amount: deposit,
+                fee_amount: self.exit_fee(),
+                dust_amount: self.dust_amount,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn from_toml_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read pool params file {}", path.display()))?;
+        let file: PoolParamsFile = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse pool params file {}", path.display()))?;
+
+        let params = file.apply(Self::default());
+        params.validate()?;
+        Ok(params)
+    }
+
+    //load params from an optional toml file, then let any cli flags override it
+    pub fn load(args: &PoolParamsArgs) -> Result<Self> {
+        let base = match &args.params_file {
+            Some(path) => Self::from_toml_file(path)?,
+            None => Self::default(),
+        };
+
+        let params = args.overrides.apply(base);
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+//every field is optional so a file or the cli only needs to set what it wants to change
+#[derive(Debug, Clone, Default, Deserialize, Args)]
+#[serde(deny_unknown_fields)]
+pub struct PoolParamsFile {
+    /// Number of users in the pool (must be 3 or more)
+    #[arg(long)]
+    pub users: Option<usize>,
+    /// Amount each user deposits into the pool unless they pick their own, in sats
+    #[arg(long)]
+    pub amount_per_user: Option<u64>,
+    /// Amount reserved for fees on each exit, in sats
+    #[arg(long)]
+    pub fee_amount: Option<u64>,
+    /// Dust limit for exit outputs, in sats
+    #[arg(long)]
+    pub dust_amount: Option<u64>,
+    /// Extra sats sent to each user wallet to cover the funding transaction fee
+    #[arg(long)]
+    pub init_wallet_amount_fee: Option<u64>,
+    /// Blocks a pool state can sit unspent before anyone can split it between its members, 0 turns the split off
+    #[arg(long)]
+    pub split_timeout: Option<u16>,
+    /// How pool spends pay their fee: a fee_amount anchor or a 0 sat ephemeral anchor paid for by the child
+    #[arg(long, value_enum)]
+    pub fee_strategy: Option<FeeStrategy>,
+    /// Pool layout: a state for every subset of users, or a split tree with log2(users) txs per exit
+    #[arg(long, value_enum)]
+    pub mode: Option<PoolMode>,
+}
+
+impl PoolParamsFile {
+    fn apply(&self, mut params: PoolParams) -> PoolParams {
+        if let Some(users) = self.users {
+            params.users = users;
+        }
+        if let Some(sats) = self.amount_per_user {
+            params.amount_per_user = Amount::from_sat(sats);
+        }
+        if let Some(sats) = self.fee_amount {
+            params.fee_amount = Amount::from_sat(sats);
+        }
+        if let Some(sats) = self.dust_amount {
+            params.dust_amount = Amount::from_sat(sats);
+        }
+        if let Some(sats) = self.init_wallet_amount_fee {
+            params.init_wallet_amount_fee = Amount::from_sat(sats);
+        }
+        if let Some(blocks) = self.split_timeout {
+            params.split_timeout = blocks;
+        }
+        if let Some(strategy) = self.fee_strategy {
+            params.fee_strategy = strategy;
+        }
+        if let Some(mode) = self.mode {
+            params.mode = mode;
+        }
+        params
+    }
+}
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct PoolParamsArgs {
+    /// TOML file with pool params, cli flags take priority over values in the file
+    #[arg(long = "params")]
+    pub params_file: Option<PathBuf>,
+    #[command(flatten)]
+    pub overrides: PoolParamsFile,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct NetworkConfig {
@@ -38,7 +264,7 @@ pub struct NetworkConfig {
 }
 
 impl NetworkConfig {
-    #[allow(clippy::needless_return)]
+    #[allow(clippy::needless_return, clippy::new_without_default)]
     pub fn new() -> Self {
         #[cfg(feature = "regtest")]
         {
@@ -141,3 +367,128 @@ impl NetworkConfig {
         Ok(bitcoin_rpc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_utils::temp_path;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        params: PoolParamsArgs,
+    }
+
+    //write contents to a toml file no other test uses
+    fn params_file(contents: &str) -> PathBuf {
+        let path = temp_path("params.toml");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn rejects_too_few_users() {
+        let params = PoolParams {
+            users: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(),
+            Err(PoolParamsError::TooFewUsers { users: 2 })
+        );
+    }
+
+    #[test]
+    fn deposit_has_to_cover_the_fee_and_dust() {
+        let params = PoolParams {
+            amount_per_user: Amount::from_sat(1546),
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(),
+            Err(PoolParamsError::AmountTooSmall {
+                amount: Amount::from_sat(1546),
+                fee_amount: Amount::from_sat(1000),
+                dust_amount: Amount::from_sat(546),
+            })
+        );
+        assert!(params.validate_deposit(Amount::from_sat(1547)).is_ok());
+    }
+
+    #[cfg(not(feature = "p2a"))]
+    #[test]
+    fn ephemeral_anchor_needs_p2a() {
+        let params = PoolParams {
+            fee_strategy: FeeStrategy::EphemeralAnchor,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(),
+            Err(PoolParamsError::EphemeralAnchorWithoutP2a)
+        );
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn ephemeral_anchor_keeps_nothing_back_for_the_fee() {
+        let params = PoolParams {
+            fee_strategy: FeeStrategy::EphemeralAnchor,
+            ..Default::default()
+        };
+        assert!(params.validate().is_ok());
+        assert_eq!(params.exit_fee(), Amount::ZERO);
+        //so a deposit only has to be more than dust
+        assert!(params.validate_deposit(Amount::from_sat(547)).is_ok());
+    }
+
+    #[test]
+    fn reads_params_from_a_toml_file() {
+        let path = params_file("users = 5\nfee_amount = 2000\nmode = \"split_tree\"\n");
+        let params = PoolParams::from_toml_file(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            params,
+            PoolParams {
+                users: 5,
+                fee_amount: Amount::from_sat(2000),
+                mode: PoolMode::SplitTree,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_a_toml_file_with_unknown_or_invalid_params() {
+        let path = params_file("users = 5\nfee = 2000\n");
+        assert!(PoolParams::from_toml_file(&path).is_err());
+        fs::remove_file(path).unwrap();
+
+        let path = params_file("users = 2\n");
+        let err = PoolParams::from_toml_file(&path).unwrap_err();
+        fs::remove_file(path).unwrap();
+        assert_eq!(
+            err.downcast_ref::<PoolParamsError>(),
+            Some(&PoolParamsError::TooFewUsers { users: 2 })
+        );
+    }
+
+    #[test]
+    fn cli_flags_take_priority_over_the_toml_file() {
+        let path = params_file("users = 5\nfee_amount = 2000\n");
+        let cli = TestCli::parse_from([
+            "test",
+            "--params",
+            path.to_str().unwrap(),
+            "--fee-amount",
+            "3000",
+        ]);
+        let params = PoolParams::load(&cli.params).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(params.users, 5);
+        assert_eq!(params.fee_amount, Amount::from_sat(3000));
+    }
+}
@@ -0,0 +1,419 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+use bitcoin::{
+    absolute,
+    hashes::Hash,
+    secp256k1::{schnorr, Message, PublicKey, SecretKey},
+    sighash::{Prevouts, SighashCache, TapSighashType},
+    taproot, transaction, Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+    XOnlyPublicKey,
+};
+use musig2::{AggNonce, KeyAggContext, PartialSignature, PubNonce, SecNonce};
+use tracing::info;
+
+use crate::{
+    config::TX_VERSION,
+    ctv_scripts::{SECP, UNSPENDABLE_PUBKEY},
+    members::{MemberSet, PoolMember},
+    payment_pool::PaymentPool,
+    pools::split_outputs,
+};
+
+//internal key of the pool state holding users. When every one of them has a musig key it is
+//their aggregate key so they can spend the state together, otherwise nobody can use the key path
+pub fn state_internal_key(members: &[PoolMember], users: &MemberSet) -> Result<XOnlyPublicKey> {
+    match key_agg_ctx(members, users)? {
+        Some(ctx) => {
+            let aggregated_key: PublicKey = ctx.aggregated_pubkey();
+            Ok(aggregated_key.x_only_public_key().0)
+        }
+        None => Ok(*UNSPENDABLE_PUBKEY),
+    }
+}
+
+//keys are aggregated in member order so everyone ends up with the same key
+fn key_agg_ctx(members: &[PoolMember], users: &MemberSet) -> Result<Option<KeyAggContext>> {
+    let keys: Option<Vec<PublicKey>> = users.iter().map(|u| members[u].musig_key).collect();
+
+    match keys {
+        Some(keys) => Ok(Some(KeyAggContext::new(keys)?)),
+        None => Ok(None),
+    }
+}
+
+//A key path spend of one pool state, signed by every member of the state with musig2. On chain
+//it looks like any other single sig taproot spend and can pay anywhere, so it covers everything
+//the members agree on (batch exits, moving to a new pool, payments out of the pool).
+//
+//1. every member calls nonce and shares the public nonce
+//2. with every public nonce in member order, every member calls sign and shares the partial signature
+//3. anyone calls finalize with all the partial signatures to get the signed tx
+#[derive(Debug, Clone)]
+pub struct CooperativeSpend {
+    users: MemberSet,
+    signer_keys: Vec<PublicKey>,
+    //tweaked with the state's merkle root so the aggregate signature is valid for the output key
+    key_agg_ctx: KeyAggContext,
+    output_key: XOnlyPublicKey,
+    unsigned_tx: Transaction,
+    sighash: [u8; 32],
+}
+
+impl CooperativeSpend {
+    //spend the pool state holding users at previous_output to outputs, whatever isn't paid out goes to fees
+    pub fn new(
+        pool: &PaymentPool,
+        users: &MemberSet,
+        previous_output: OutPoint,
+        outputs: Vec<TxOut>,
+    ) -> Result<Self> {
+        let spend_info = pool.spend_info(users)?;
+
+        let key_agg_ctx = key_agg_ctx(pool.members(), users)?
+            .ok_or_else(|| anyhow!("pool state {:?} has members without a musig key", users))?;
+        let signer_keys = users
+            .iter()
+            .filter_map(|u| pool.members()[u].musig_key)
+            .collect();
+
+        let aggregated_key: PublicKey = key_agg_ctx.aggregated_pubkey();
+        ensure!(
+            aggregated_key.x_only_public_key().0 == spend_info.internal_key(),
+            "pool state {:?} was not built with the members' musig keys",
+            users
+        );
+
+        let merkle_root = spend_info
+            .merkle_root()
+            .ok_or_else(|| anyhow!("pool state {:?} has no script tree", users))?;
+        let key_agg_ctx = key_agg_ctx.with_taproot_tweak(merkle_root.as_ref())?;
+
+        let pool_amount = pool.pool_amount(users);
+        let total_out: Amount = outputs.iter().map(|output| output.value).sum();
+        ensure!(
+            total_out <= pool_amount,
+            "outputs pay {} but pool state {:?} only holds {}",
+            total_out,
+            users,
+            pool_amount
+        );
+
+        let unsigned_tx = Transaction {
+            version: transaction::Version(TX_VERSION),
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                ..Default::default()
+            }],
+            output: outputs,
+        };
+
+        let output_key = spend_info.output_key();
+        let prevout = TxOut {
+            value: pool_amount,
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(output_key),
+        };
+        let sighash = SighashCache::new(&unsigned_tx).taproot_key_spend_signature_hash(
+            0,
+            &Prevouts::All(&[prevout]),
+            TapSighashType::Default,
+        )?;
+
+        Ok(Self {
+            users: users.clone(),
+            signer_keys,
+            key_agg_ctx,
+            output_key: XOnlyPublicKey::from(output_key),
+            unsigned_tx,
+            sighash: sighash.to_byte_array(),
+        })
+    }
+
+    //every member of the state takes their deposit out in one tx, the fee is split evenly between them
+    pub fn batch_exit(
+        pool: &PaymentPool,
+        users: &MemberSet,
+        previous_output: OutPoint,
+    ) -> Result<Self> {
+        let outputs = split_outputs(pool.params(), pool.members(), pool.anchor_addr(), users);
+
+        Self::new(pool, users, previous_output, outputs)
+    }
+
+    pub fn users(&self) -> &MemberSet {
+        &self.users
+    }
+
+    pub fn unsigned_tx(&self) -> &Transaction {
+        &self.unsigned_tx
+    }
+
+    //taproot key spend sighash every member signs
+    pub fn sighash(&self) -> [u8; 32] {
+        self.sighash
+    }
+
+    //round 1, the secret nonce must only ever be used for one signature
+    pub fn nonce(&self, seckey: SecretKey, nonce_seed: [u8; 32]) -> Result<SecNonce> {
+        self.signer_position(&seckey.public_key(&SECP))?;
+
+        let aggregated_key: PublicKey = self.key_agg_ctx.aggregated_pubkey();
+        Ok(SecNonce::build_with_seckey(nonce_seed, seckey)
+            .with_message(&self.sighash)
+            .with_aggregated_pubkey(aggregated_key)
+            .build())
+    }
+
+    //round 2, pub_nonces holds the public nonce of every member in member order
+    pub fn sign(
+        &self,
+        seckey: SecretKey,
+        secnonce: SecNonce,
+        pub_nonces: &[PubNonce],
+    ) -> Result<PartialSignature> {
+        self.signer_position(&seckey.public_key(&SECP))?;
+        let aggregated_nonce = self.aggregated_nonce(pub_nonces)?;
+
+        Ok(musig2::sign_partial(
+            &self.key_agg_ctx,
+            seckey,
+            secnonce,
+            &aggregated_nonce,
+            self.sighash,
+        )?)
+    }
+
+    //check one member's partial signature so a bad signer can be named before aggregating
+    pub fn verify_partial(
+        &self,
+        member: usize,
+        partial_signature: PartialSignature,
+        pub_nonces: &[PubNonce],
+    ) -> Result<()> {
+        let Some(position) = self.users.iter().position(|u| u == member) else {
+            bail!("user {} is not in the pool state {:?}", member, self.users);
+        };
+        let aggregated_nonce = self.aggregated_nonce(pub_nonces)?;
+
+        musig2::verify_partial(
+            &self.key_agg_ctx,
+            partial_signature,
+            &aggregated_nonce,
+            self.signer_keys[position],
+            &pub_nonces[position],
+            self.sighash,
+        )
+        .map_err(|_| anyhow!("invalid partial signature from user {}", member))
+    }
+
+    //aggregate every member's partial signature (in member order) into the key path witness
+    pub fn finalize(
+        &self,
+        pub_nonces: &[PubNonce],
+        partial_signatures: &[PartialSignature],
+    ) -> Result<Transaction> {
+        ensure!(
+            partial_signatures.len() == self.users.len(),
+            "expected {} partial signatures, got {}",
+            self.users.len(),
+            partial_signatures.len()
+        );
+
+        for (member, partial_signature) in self.users.iter().zip(partial_signatures) {
+            self.verify_partial(member, *partial_signature, pub_nonces)?;
+        }
+
+        let aggregated_nonce = self.aggregated_nonce(pub_nonces)?;
+        let signature: schnorr::Signature = musig2::aggregate_partial_signatures(
+            &self.key_agg_ctx,
+            &aggregated_nonce,
+            partial_signatures.iter().copied(),
+            self.sighash,
+        )?;
+
+        SECP.verify_schnorr(
+            &signature,
+            &Message::from_digest(self.sighash),
+            &self.output_key,
+        )?;
+
+        let mut tx = self.unsigned_tx.clone();
+        tx.input[0].witness = Witness::from_slice(&[taproot::Signature {
+            signature,
+            sighash_type: TapSighashType::Default,
+        }
+        .to_vec()]);
+
+        info!(
+            "cooperative spend of pool {:?} signed, txid: {} \n",
+            self.users,
+            tx.compute_txid()
+        );
+
+        Ok(tx)
+    }
+
+    fn signer_position(&self, key: &PublicKey) -> Result<usize> {
+        self.signer_keys
+            .iter()
+            .position(|signer_key| signer_key == key)
+            .ok_or_else(|| anyhow!("key {} is not a signer of pool state {:?}", key, self.users))
+    }
+
+    fn aggregated_nonce(&self, pub_nonces: &[PubNonce]) -> Result<AggNonce> {
+        ensure!(
+            pub_nonces.len() == self.users.len(),
+            "expected {} public nonces, got {}",
+            self.users.len(),
+            pub_nonces.len()
+        );
+        Ok(AggNonce::sum(pub_nonces))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::{
+        config::PoolParams,
+        test_utils::{test_anchor, test_members},
+    };
+
+    //a 3 user pool where every member has a musig key, with their secret keys
+    fn musig_pool() -> (PaymentPool, Vec<SecretKey>) {
+        let seckeys: Vec<_> = (0..3).map(|_| SecretKey::new(&mut thread_rng())).collect();
+        let members = test_members(&[20000; 3])
+            .into_iter()
+            .zip(&seckeys)
+            .map(|(member, seckey)| member.with_musig_key(seckey.public_key(&SECP)))
+            .collect();
+        let params = PoolParams {
+            users: 3,
+            ..Default::default()
+        };
+        let pool = PaymentPool::new(params, Network::Regtest, members, test_anchor()).unwrap();
+        (pool, seckeys)
+    }
+
+    //both rounds for every member of the spend
+    fn sign_all(
+        spend: &CooperativeSpend,
+        seckeys: &[SecretKey],
+    ) -> (Vec<PubNonce>, Vec<PartialSignature>) {
+        let secnonces: Vec<_> = spend
+            .users()
+            .iter()
+            .map(|u| spend.nonce(seckeys[u], rand::random()).unwrap())
+            .collect();
+        let pub_nonces: Vec<_> = secnonces.iter().map(|nonce| nonce.public_nonce()).collect();
+        let partial_signatures = spend
+            .users()
+            .iter()
+            .zip(secnonces)
+            .map(|(u, secnonce)| spend.sign(seckeys[u], secnonce, &pub_nonces).unwrap())
+            .collect();
+        (pub_nonces, partial_signatures)
+    }
+
+    #[test]
+    fn key_path_spend_verifies_against_the_state_output_key() {
+        let (pool, seckeys) = musig_pool();
+        let users = MemberSet::full(3);
+        let spend = CooperativeSpend::batch_exit(&pool, &users, OutPoint::null()).unwrap();
+        let (pub_nonces, partial_signatures) = sign_all(&spend, &seckeys);
+        let tx = spend.finalize(&pub_nonces, &partial_signatures).unwrap();
+
+        //the sighash is worked out again here from the pool's own output, not taken from the spend
+        let output_key = pool.spend_info(&users).unwrap().output_key();
+        let prevout = TxOut {
+            value: pool.pool_amount(&users),
+            script_pubkey: pool.funding_address().unwrap().script_pubkey(),
+        };
+        assert_eq!(
+            prevout.script_pubkey,
+            ScriptBuf::new_p2tr_tweaked(output_key)
+        );
+        let sighash = SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                TapSighashType::Default,
+            )
+            .unwrap();
+
+        let witness = &tx.input[0].witness;
+        assert_eq!(witness.len(), 1);
+        let signature = taproot::Signature::from_slice(&witness[0]).unwrap();
+        SECP.verify_schnorr(
+            &signature.signature,
+            &Message::from_digest(sighash.to_byte_array()),
+            &output_key.to_x_only_public_key(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_a_bad_partial_signature() {
+        let (pool, seckeys) = musig_pool();
+        let users = MemberSet::full(3);
+        let spend = CooperativeSpend::batch_exit(&pool, &users, OutPoint::null()).unwrap();
+        let (pub_nonces, mut partial_signatures) = sign_all(&spend, &seckeys);
+
+        spend
+            .verify_partial(0, partial_signatures[0], &pub_nonces)
+            .unwrap();
+
+        //member 1's signature passed off as member 0's
+        partial_signatures[0] = partial_signatures[1];
+        let err = spend
+            .verify_partial(0, partial_signatures[0], &pub_nonces)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid partial signature from user 0");
+
+        let err = spend
+            .finalize(&pub_nonces, &partial_signatures)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "invalid partial signature from user 0");
+    }
+
+    #[test]
+    fn needs_a_musig_key_from_every_member() {
+        let seckeys: Vec<_> = (0..3).map(|_| SecretKey::new(&mut thread_rng())).collect();
+        let members: Vec<_> = test_members(&[20000; 3])
+            .into_iter()
+            .zip(&seckeys)
+            .enumerate()
+            .map(|(u, (member, seckey))| match u {
+                1 => member,
+                _ => member.with_musig_key(seckey.public_key(&SECP)),
+            })
+            .collect();
+        let params = PoolParams {
+            users: 3,
+            ..Default::default()
+        };
+        let pool = PaymentPool::new(params, Network::Regtest, members, test_anchor()).unwrap();
+        let users = MemberSet::full(3);
+
+        //without member 1's key nobody can use the key path
+        assert_eq!(
+            state_internal_key(pool.members(), &users).unwrap(),
+            *UNSPENDABLE_PUBKEY
+        );
+        let err = CooperativeSpend::batch_exit(&pool, &users, OutPoint::null()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("pool state {:?} has members without a musig key", users)
+        );
+
+        //the state member 1 has left can still be spent by the other 2
+        let rest = users.without(1);
+        let spend = CooperativeSpend::batch_exit(&pool, &rest, OutPoint::null()).unwrap();
+        let (pub_nonces, partial_signatures) = sign_all(&spend, &seckeys);
+        spend.finalize(&pub_nonces, &partial_signatures).unwrap();
+    }
+}
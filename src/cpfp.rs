@@ -0,0 +1,451 @@
+use anyhow::{anyhow, bail, Result};
+
+use bitcoin::{
+    absolute, opcodes::all::OP_RETURN, script::Builder, transaction,
+    transaction::InputWeightPrediction, Address, Amount, OutPoint, Script, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Weight,
+};
+
+use crate::{
+    chain::{ChainBackend, Utxo},
+    config::TX_VERSION,
+};
+
+//a child of an unconfirmed v3 parent can't be bigger than this (TRUC, BIP-431)
+pub const TRUC_CHILD_MAX_VSIZE: u64 = 1000;
+
+//an unsigned child for a pool spend, prevouts are the outputs it spends in input order
+#[derive(Debug, Clone)]
+pub struct ChildPlan {
+    pub tx: Transaction,
+    pub prevouts: Vec<(OutPoint, TxOut)>,
+    pub fee: Amount,
+    //parent + child, the child's vsize is predicted for when it's signed
+    pub package_vsize: u64,
+}
+
+impl ChildPlan {
+    //what the parent and child pay together per kvB, parent_fee is whatever the parent pays itself
+    pub fn package_fee_rate(&self, parent_fee: Amount) -> u64 {
+        (self.fee + parent_fee).to_sat() * 1000 / self.package_vsize
+    }
+}
+
+//the output of parent paying to anchor_addr, a pool spend has exactly one
+pub fn anchor_output(parent: &Transaction, anchor_addr: &Address) -> Result<(OutPoint, TxOut)> {
+    let anchor_script = anchor_addr.script_pubkey();
+    let vout = parent
+        .output
+        .iter()
+        .position(|output| output.script_pubkey == anchor_script)
+        .ok_or_else(|| {
+            anyhow!(
+                "{} has no anchor output to {}",
+                parent.compute_txid(),
+                anchor_addr
+            )
+        })?;
+
+    Ok((
+        OutPoint {
+            txid: parent.compute_txid(),
+            vout: vout as u32,
+        },
+        parent.output[vout].clone(),
+    ))
+}
+
+//inputs - outputs of parent, looking its prevouts up on chain
+pub fn parent_fee(chain: &dyn ChainBackend, parent: &Transaction) -> Result<Amount> {
+    let mut input_value = Amount::ZERO;
+    for input in &parent.input {
+        let prev_tx = chain.get_tx(input.previous_output.txid)?;
+        let prevout = prev_tx
+            .output
+            .get(input.previous_output.vout as usize)
+            .ok_or_else(|| anyhow!("unknown parent input {}", input.previous_output))?;
+        input_value += prevout.value;
+    }
+
+    let output_value: Amount = parent.output.iter().map(|output| output.value).sum();
+    input_value
+        .checked_sub(output_value)
+        .ok_or_else(|| anyhow!("{} pays out more than it spends", parent.compute_txid()))
+}
+
+//what parent and the child spending its anchor pay together per kvB, like the node rates the
+//package. Just the parent's own fee rate while it has no child or no anchor
+pub fn package_fee_rate(
+    chain: &dyn ChainBackend,
+    anchor_addr: &Address,
+    parent: &Transaction,
+) -> Result<u64> {
+    let mut fee = parent_fee(chain, parent)?;
+    let mut vsize = parent.vsize() as u64;
+
+    if let Ok((anchor, _)) = anchor_output(parent, anchor_addr) {
+        if let Some(child) = chain.find_spend(anchor)? {
+            fee += parent_fee(chain, &child)?;
+            vsize += child.vsize() as u64;
+        }
+    }
+
+    Ok(fee.to_sat() * 1000 / vsize)
+}
+
+//the size an input spending script takes once it's signed. P2A is spent with an empty witness,
+//wallet outputs are sized for their largest signature. None for scripts we can't predict
+pub fn input_weight(script: &Script) -> Option<InputWeightPrediction> {
+    if *script == ScriptBuf::new_p2a() {
+        Some(InputWeightPrediction::new(0, [0usize; 0]))
+    } else if script.is_p2wpkh() {
+        Some(InputWeightPrediction::P2WPKH_MAX)
+    } else if script.is_p2tr() {
+        Some(InputWeightPrediction::P2TR_KEY_DEFAULT_SIGHASH)
+    } else if script.is_p2pkh() {
+        Some(InputWeightPrediction::P2PKH_COMPRESSED_MAX)
+    } else {
+        None
+    }
+}
+
+//fee_rate is in sat/kvB like DEFAULT_FEE_RATE
+fn fee_for(vsize: u64, fee_rate: u64) -> Amount {
+    Amount::from_sat((vsize * fee_rate).div_ceil(1000))
+}
+
+//Plan a child spending the parent's anchor and enough confirmed wallet utxos to bring the package
+//of parent + child to fee_rate. Utxos are picked largest first, the change goes back to the script
+//of the first one and is dropped into the fee when it would be dust. The anchor's own value goes
+//to the fee first, so a big enough anchor needs no wallet utxo at all
+pub fn plan_child(
+    parent: &Transaction,
+    parent_fee: Amount,
+    anchor: (OutPoint, TxOut),
+    utxos: Vec<Utxo>,
+    fee_rate: u64,
+) -> Result<ChildPlan> {
+    let anchor_weight = input_weight(&anchor.1.script_pubkey).ok_or_else(|| {
+        anyhow!(
+            "can't size a spend of anchor script {}",
+            anchor.1.script_pubkey
+        )
+    })?;
+
+    let op_return_script = Builder::new()
+        .push_opcode(OP_RETURN)
+        .push_slice(b"\xe2\x9a\x93 \xF0\x9F\xA5\xAA \xe2\x9a\x93")
+        .into_script();
+
+    let mut candidates: Vec<(Utxo, InputWeightPrediction)> = utxos
+        .into_iter()
+        .filter(|utxo| utxo.confirmations >= 1)
+        .filter_map(|utxo| {
+            let weight = input_weight(&utxo.txout.script_pubkey)?;
+            Some((utxo, weight))
+        })
+        .collect();
+    candidates.sort_by_key(|(utxo, _)| std::cmp::Reverse(utxo.txout.value));
+
+    let parent_vsize = parent.vsize() as u64;
+    //the fee the child has to pay when it weighs child_weight
+    let child_fee = |child_weight: Weight| {
+        let package_fee = fee_for(parent_vsize + child_weight.to_vbytes_ceil(), fee_rate);
+        package_fee.checked_sub(parent_fee).unwrap_or(Amount::ZERO)
+    };
+
+    let mut needed = Amount::ZERO;
+    for selected in 0..=candidates.len() {
+        let wallet_inputs = &candidates[..selected];
+        let input_value = anchor.1.value
+            + wallet_inputs
+                .iter()
+                .map(|(utxo, _)| utxo.txout.value)
+                .sum::<Amount>();
+        let inputs = || {
+            std::iter::once(anchor_weight).chain(wallet_inputs.iter().map(|(_, weight)| *weight))
+        };
+
+        let without_change = transaction::predict_weight(inputs(), [op_return_script.len()]);
+        if without_change.to_vbytes_ceil() > TRUC_CHILD_MAX_VSIZE {
+            break;
+        }
+        needed = child_fee(without_change);
+
+        //change only when it's worth more than the fee it costs and isn't dust
+        let mut change = None;
+        if let Some((first, _)) = wallet_inputs.first() {
+            let change_script = &first.txout.script_pubkey;
+            let with_change = transaction::predict_weight(
+                inputs(),
+                [op_return_script.len(), change_script.len()],
+            );
+            let fee = child_fee(with_change);
+            if input_value >= fee + change_script.minimal_non_dust() {
+                change = Some((
+                    fee,
+                    TxOut {
+                        value: input_value - fee,
+                        script_pubkey: change_script.clone(),
+                    },
+                    with_change,
+                ));
+            }
+        }
+
+        let (fee, change_output, child_weight) = match change {
+            Some((fee, output, weight)) => (fee, Some(output), weight),
+            None if input_value >= needed => (input_value, None, without_change),
+            None => continue,
+        };
+
+        let mut prevouts = vec![anchor.clone()];
+        prevouts.extend(
+            wallet_inputs
+                .iter()
+                .map(|(utxo, _)| (utxo.outpoint, utxo.txout.clone())),
+        );
+
+        let mut output = vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: op_return_script,
+        }];
+        output.extend(change_output);
+
+        let tx = Transaction {
+            version: transaction::Version(TX_VERSION),
+            lock_time: absolute::LockTime::ZERO,
+            input: prevouts
+                .iter()
+                .map(|(outpoint, _)| TxIn {
+                    previous_output: *outpoint,
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..Default::default()
+                })
+                .collect(),
+            output,
+        };
+
+        return Ok(ChildPlan {
+            tx,
+            prevouts,
+            fee,
+            package_vsize: parent_vsize + child_weight.to_vbytes_ceil(),
+        });
+    }
+
+    bail!(
+        "confirmed wallet utxos can't pay the {} sat child fee for {} at {} sat/kvB",
+        needed.to_sat(),
+        parent.compute_txid(),
+        fee_rate
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, key::Keypair, secp256k1::PublicKey, Txid, Witness};
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::{ctv_scripts::SECP, test_utils::test_address};
+
+    //a pool exit with a ctv leaf witness and a P2A anchor at vout 2
+    fn test_parent(anchor_value: Amount) -> Transaction {
+        Transaction {
+            version: transaction::Version(3),
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                witness: Witness::from_slice(&[vec![0; 34], vec![0xc0; 33]]),
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(60000),
+                    script_pubkey: test_address().script_pubkey(),
+                },
+                TxOut {
+                    value: Amount::from_sat(19000),
+                    script_pubkey: test_address().script_pubkey(),
+                },
+                TxOut {
+                    value: anchor_value,
+                    script_pubkey: ScriptBuf::new_p2a(),
+                },
+            ],
+        }
+    }
+
+    fn anchor_of(parent: &Transaction) -> (OutPoint, TxOut) {
+        (
+            OutPoint::new(parent.compute_txid(), 2),
+            parent.output[2].clone(),
+        )
+    }
+
+    fn p2wpkh_script() -> ScriptBuf {
+        let key = PublicKey::from_keypair(&Keypair::new(&SECP, &mut thread_rng()));
+        ScriptBuf::new_p2wpkh(&bitcoin::CompressedPublicKey(key).wpubkey_hash())
+    }
+
+    fn utxo(n: u32, script_pubkey: ScriptBuf, sats: u64, confirmations: u32) -> Utxo {
+        Utxo {
+            outpoint: OutPoint::new(Txid::from_byte_array([1; 32]), n),
+            txout: TxOut {
+                value: Amount::from_sat(sats),
+                script_pubkey,
+            },
+            confirmations,
+        }
+    }
+
+    //the child with the largest witness every input can have once signed
+    fn signed(plan: &ChildPlan) -> Transaction {
+        let mut tx = plan.tx.clone();
+        for (input, (_, prevout)) in tx.input.iter_mut().zip(&plan.prevouts) {
+            input.witness = if prevout.script_pubkey.is_p2wpkh() {
+                Witness::from_slice(&[vec![0; 72], vec![0; 33]])
+            } else if prevout.script_pubkey.is_p2tr() {
+                Witness::from_slice(&[vec![0; 64]])
+            } else {
+                Witness::new()
+            };
+        }
+        tx
+    }
+
+    fn value_in(plan: &ChildPlan) -> Amount {
+        plan.prevouts.iter().map(|(_, txout)| txout.value).sum()
+    }
+
+    fn value_out(tx: &Transaction) -> Amount {
+        tx.output.iter().map(|output| output.value).sum()
+    }
+
+    #[test]
+    fn child_brings_the_package_to_the_fee_rate() {
+        let parent = test_parent(Amount::ZERO);
+        let utxos = vec![
+            utxo(0, test_address().script_pubkey(), 20000, 3),
+            utxo(1, p2wpkh_script(), 50000, 1),
+        ];
+
+        let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 5000).unwrap();
+        let child = signed(&plan);
+
+        //the vsize is predicted exactly, and the fee is what the package needs at 5 sat/vB
+        assert_eq!(plan.package_vsize, (parent.vsize() + child.vsize()) as u64);
+        assert_eq!(plan.fee, fee_for(plan.package_vsize, 5000));
+        assert_eq!(plan.package_fee_rate(Amount::ZERO), 5000);
+
+        //the largest utxo alone covers it, the change goes back to its script
+        assert_eq!(plan.prevouts.len(), 2);
+        assert_eq!(plan.prevouts[1].1.value, Amount::from_sat(50000));
+        assert_eq!(
+            child.output[1].script_pubkey,
+            plan.prevouts[1].1.script_pubkey
+        );
+        assert_eq!(value_in(&plan) - value_out(&child), plan.fee);
+        assert!(child.output[0].script_pubkey.is_op_return());
+    }
+
+    #[test]
+    fn parent_fee_counts_towards_the_package() {
+        let parent = test_parent(Amount::ZERO);
+        let utxos = vec![utxo(0, p2wpkh_script(), 50000, 1)];
+        let parent_fee = Amount::from_sat(300);
+
+        let plan = plan_child(&parent, parent_fee, anchor_of(&parent), utxos, 5000).unwrap();
+
+        assert_eq!(plan.fee + parent_fee, fee_for(plan.package_vsize, 5000));
+        assert_eq!(
+            plan.package_vsize,
+            (parent.vsize() + signed(&plan).vsize()) as u64
+        );
+    }
+
+    #[test]
+    fn big_anchor_pays_without_a_wallet_utxo() {
+        let parent = test_parent(Amount::from_sat(5000));
+        let utxos = vec![utxo(0, p2wpkh_script(), 50000, 1)];
+
+        let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 5000).unwrap();
+
+        //no change output without a wallet input, the whole anchor goes to the fee
+        assert_eq!(plan.prevouts.len(), 1);
+        assert_eq!(plan.tx.output.len(), 1);
+        assert_eq!(plan.fee, Amount::from_sat(5000));
+        assert!(plan.package_fee_rate(Amount::ZERO) >= 5000);
+        assert_eq!(
+            plan.package_vsize,
+            (parent.vsize() + signed(&plan).vsize()) as u64
+        );
+    }
+
+    #[test]
+    fn change_that_would_be_dust_goes_to_the_fee() {
+        let parent = test_parent(Amount::ZERO);
+        //what the child pays with a p2tr input and no change, the plan below has a 43 vB p2tr
+        //change output
+        let needed = {
+            let utxos = vec![utxo(0, test_address().script_pubkey(), 100000, 1)];
+            let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 5000).unwrap();
+            let without_change = plan.package_vsize - 43;
+            fee_for(without_change, 5000)
+        };
+        let utxos = vec![utxo(
+            0,
+            test_address().script_pubkey(),
+            needed.to_sat() + 100,
+            1,
+        )];
+
+        let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 5000).unwrap();
+
+        assert_eq!(plan.tx.output.len(), 1);
+        assert_eq!(plan.fee, needed + Amount::from_sat(100));
+        assert!(plan.package_fee_rate(Amount::ZERO) >= 5000);
+    }
+
+    #[test]
+    fn skips_unconfirmed_and_unknown_utxos() {
+        let parent = test_parent(Amount::ZERO);
+        let p2sh = ScriptBuf::new_p2sh(&bitcoin::ScriptHash::all_zeros());
+        let utxos = vec![
+            utxo(0, p2wpkh_script(), 90000, 0),
+            utxo(1, p2sh, 80000, 6),
+            utxo(2, test_address().script_pubkey(), 10000, 1),
+        ];
+
+        let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 5000).unwrap();
+
+        assert_eq!(plan.prevouts.len(), 2);
+        assert_eq!(plan.prevouts[1].0.vout, 2);
+    }
+
+    #[test]
+    fn stops_at_the_truc_child_limit() {
+        let parent = test_parent(Amount::ZERO);
+        //each input is worth 60 sat more than it costs at 5 sat/vB, the package needs about 20 of
+        //them which is more than fits in 1000 vB
+        let utxos: Vec<_> = (0..200).map(|n| utxo(n, p2wpkh_script(), 400, 1)).collect();
+
+        let error = plan_child(
+            &parent,
+            Amount::ZERO,
+            anchor_of(&parent),
+            utxos.clone(),
+            5000,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("can't pay"));
+
+        //at 2 sat/vB the same utxos fit
+        let plan = plan_child(&parent, Amount::ZERO, anchor_of(&parent), utxos, 2000).unwrap();
+        let child = signed(&plan);
+        assert!(child.vsize() as u64 <= TRUC_CHILD_MAX_VSIZE);
+        assert!(plan.package_fee_rate(Amount::ZERO) >= 2000);
+        assert_eq!(value_in(&plan) - value_out(&child), plan.fee);
+    }
+}
@@ -1,21 +1,22 @@
 use bitcoin::{
+    absolute,
     consensus::Encodable,
-    hashes::{sha256, Hash},
+    hashes::{sha256, Hash, HashEngine},
+    hex::DisplayHex,
     key::Secp256k1,
-    opcodes::all::OP_NOP4,
+    opcodes::all::{OP_CHECKSIG, OP_DROP, OP_NOP4},
     script::Builder,
-    secp256k1::All,
-    taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo},
-    Address, Amount, Opcode, ScriptBuf, Sequence, Transaction, TxOut, XOnlyPublicKey,
+    secp256k1::{schnorr, All},
+    sighash::{Prevouts, SighashCache, TapSighash, TapSighashType},
+    taproot::{self, LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo},
+    transaction, Address, Amount, Opcode, ScriptBuf, Sequence, Transaction, TxOut, XOnlyPublicKey,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, ensure, Result};
 use once_cell::sync::Lazy;
+use std::fmt;
 
-use crate::{
-    config::{FEE_AMOUNT, TX_VERSION},
-    AMOUNT_PER_USER,
-};
+use crate::config::{PoolParams, TX_VERSION};
 
 // OP_SECURETHEBAG is the original name (well there was another name before this but thats deep lore) for OP_CHECKTEMPLATEVERIFY.
 // OP_NOP4 is the spare opcode that will be used for op_ctv cos of softfork reasons
@@ -24,10 +25,7 @@ const OP_SECURETHEBAG: Opcode = OP_NOP4;
 
 pub static SECP: Lazy<Secp256k1<All>> = Lazy::new(Secp256k1::new);
 
-pub static UNSPENDABLE_PUBKEY: Lazy<XOnlyPublicKey> = Lazy::new(|| {
-    let pool_nums = nums_from_tag(b"ctv_pool");
-    pool_nums
-});
+pub static UNSPENDABLE_PUBKEY: Lazy<XOnlyPublicKey> = Lazy::new(|| nums_from_tag(b"ctv_pool"));
 
 fn nums_from_tag(tag: &[u8]) -> XOnlyPublicKey {
     let mut ctr = 0u32;
@@ -50,50 +48,55 @@ pub fn ctv_script(ctv_hash: [u8; 32]) -> ScriptBuf {
         .into_script()
 }
 
-pub fn calc_ctv_hash(outputs: &[TxOut], timeout: Option<u32>) -> [u8; 32] {
-    let mut buffer = Vec::new();
-    buffer.extend(TX_VERSION.to_le_bytes()); // version
-    buffer.extend(0_i32.to_le_bytes()); // locktime
-    buffer.extend(1_u32.to_le_bytes()); // inputs len
-
-    let seq = if let Some(timeout_value) = timeout {
-        sha256::Hash::hash(&Sequence(timeout_value).0.to_le_bytes())
-    } else {
-        sha256::Hash::hash(&Sequence::ENABLE_RBF_NO_LOCKTIME.0.to_le_bytes())
-    };
-    buffer.extend(seq.to_byte_array()); // sequences
-
-    let outputs_len = outputs.len() as u32;
-    buffer.extend(outputs_len.to_le_bytes()); // outputs len
+//same as ctv_script but the member with signer as their key also has to sign, so nobody else can take this exit
+pub fn ctv_checksig_script(ctv_hash: [u8; 32], signer: XOnlyPublicKey) -> ScriptBuf {
+    Builder::new()
+        .push_slice(ctv_hash)
+        .push_opcode(OP_SECURETHEBAG)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&signer)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
 
-    let mut output_bytes: Vec<u8> = Vec::new();
-    for o in outputs {
-        o.consensus_encode(&mut output_bytes).unwrap();
+//leaf for an exit taken by a member, it needs their signature if they have an exit key
+pub fn exit_leaf_script(ctv_hash: [u8; 32], signer: Option<XOnlyPublicKey>) -> ScriptBuf {
+    match signer {
+        Some(signer) => ctv_checksig_script(ctv_hash, signer),
+        None => ctv_script(ctv_hash),
     }
-    buffer.extend(sha256::Hash::hash(&output_bytes).to_byte_array()); // outputs hash
+}
 
-    buffer.extend(0_u32.to_le_bytes()); // inputs index
+//template hash of a pool tx paying outputs, see CtvTemplate::pool_spend. timeout is the sequence
+//of the input, the pool exits use ENABLE_RBF_NO_LOCKTIME
+pub fn calc_ctv_hash(outputs: &[TxOut], timeout: Option<u32>) -> [u8; 32] {
+    let sequence = match timeout {
+        Some(timeout_value) => Sequence(timeout_value),
+        None => Sequence::ENABLE_RBF_NO_LOCKTIME,
+    };
 
-    let hash = sha256::Hash::hash(&buffer);
-    hash.to_byte_array()
+    CtvTemplate::pool_spend(outputs.to_vec(), sequence)
+        .hash()
+        .to_byte_array()
 }
 
-pub fn create_pool_address(ctv_hashes: Vec<[u8; 32]>) -> Result<TaprootSpendInfo> {
-
+//internal_key is UNSPENDABLE_PUBKEY unless the members of the state can spend it together by key path
+pub fn create_pool_address(
+    leaf_scripts: Vec<ScriptBuf>,
+    internal_key: XOnlyPublicKey,
+) -> Result<TaprootSpendInfo> {
     let secp = &*SECP;
-    let unspendable_pubkey = *UNSPENDABLE_PUBKEY;
 
-    let num_scripts = ctv_hashes.len();
+    let num_scripts = leaf_scripts.len();
     let depths = calculate_depths(num_scripts);
 
     let mut builder = TaprootBuilder::new();
 
-    for (depth, hash) in depths.iter().zip(ctv_hashes.iter()) {
-        let script = ctv_script(*hash);
+    for (depth, script) in depths.iter().zip(leaf_scripts) {
         builder = builder.add_leaf((*depth).try_into()?, script)?;
     }
 
-    let taproot_spend_info = builder.finalize(&secp, unspendable_pubkey).unwrap();
+    let taproot_spend_info = builder.finalize(secp, internal_key).unwrap();
 
     Ok(taproot_spend_info)
 }
@@ -118,47 +121,296 @@ fn calculate_depths(num_scripts: usize) -> Vec<usize> {
     depths
 }
 
-pub fn create_withdraw_ctv_hash(
+//outputs of an exit tx, the first output (the next pool or the other member in the final exit)
+//gets pool_exit_amount and the member leaving pays the fee out of withdraw_amount
+pub fn withdraw_outputs(
+    params: &PoolParams,
     pool_addr: &Address,
     withdraw_addr: &Address,
     anchor_addr: &Address,
     pool_exit_amount: Amount,
-) -> [u8; 32] {
-    let ctv_tx_out = [
+    withdraw_amount: Amount,
+) -> Vec<TxOut> {
+    let mut outputs = vec![
         TxOut {
             value: pool_exit_amount,
             script_pubkey: pool_addr.script_pubkey(),
         },
         TxOut {
-            value: AMOUNT_PER_USER - FEE_AMOUNT,
+            value: withdraw_amount - params.exit_fee(),
             script_pubkey: withdraw_addr.script_pubkey(),
         },
-        #[cfg(feature = "regtest")]
-        TxOut {
-            value: FEE_AMOUNT,
-            script_pubkey: anchor_addr.script_pubkey(),
-        },
     ];
+    outputs.extend(anchor_txout(anchor_addr, params.exit_fee()));
+    outputs
+}
+
+//the output pool spends pay their fee through with p2a, without it they pay the fee themselves
+pub fn anchor_txout(anchor_addr: &Address, value: Amount) -> Option<TxOut> {
+    cfg!(feature = "p2a").then(|| TxOut {
+        value,
+        script_pubkey: anchor_addr.script_pubkey(),
+    })
+}
+
+pub fn create_withdraw_ctv_hash(
+    params: &PoolParams,
+    pool_addr: &Address,
+    withdraw_addr: &Address,
+    anchor_addr: &Address,
+    pool_exit_amount: Amount,
+    withdraw_amount: Amount,
+) -> [u8; 32] {
+    let ctv_tx_out = withdraw_outputs(
+        params,
+        pool_addr,
+        withdraw_addr,
+        anchor_addr,
+        pool_exit_amount,
+        withdraw_amount,
+    );
 
     calc_ctv_hash(&ctv_tx_out, None)
 }
 
+//Everything a ctv hash commits to (BIP-119), kept in full instead of hashed so a tx that doesn't
+//match its leaf can be pinned down to the field that is wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtvTemplate {
+    pub version: transaction::Version,
+    pub lock_time: absolute::LockTime,
+    pub script_sigs: Vec<ScriptBuf>,
+    pub sequences: Vec<Sequence>,
+    pub outputs: Vec<TxOut>,
+    pub input_index: u32,
+}
+
+impl CtvTemplate {
+    pub fn from_tx(tx: &Transaction, input_index: u32) -> Self {
+        Self {
+            version: tx.version,
+            lock_time: tx.lock_time,
+            script_sigs: tx
+                .input
+                .iter()
+                .map(|input| input.script_sig.clone())
+                .collect(),
+            sequences: tx.input.iter().map(|input| input.sequence).collect(),
+            outputs: tx.output.clone(),
+            input_index,
+        }
+    }
+
+    //the template every pool tx is built from, spending the pool output as the only input
+    pub fn pool_spend(outputs: Vec<TxOut>, sequence: Sequence) -> Self {
+        Self {
+            version: transaction::Version(TX_VERSION),
+            lock_time: absolute::LockTime::ZERO,
+            script_sigs: vec![ScriptBuf::new()],
+            sequences: vec![sequence],
+            outputs,
+            input_index: 0,
+        }
+    }
+
+    pub fn hash(&self) -> TemplateHash {
+        let mut buffer = Vec::new();
+        self.version.consensus_encode(&mut buffer).unwrap();
+        self.lock_time.consensus_encode(&mut buffer).unwrap();
+
+        //the script sigs are only committed to when there is one that isn't empty
+        if self
+            .script_sigs
+            .iter()
+            .any(|script_sig| !script_sig.is_empty())
+        {
+            let mut script_sig_bytes = Vec::new();
+            for script_sig in &self.script_sigs {
+                script_sig.consensus_encode(&mut script_sig_bytes).unwrap();
+            }
+            buffer.extend(sha256::Hash::hash(&script_sig_bytes).to_byte_array());
+        }
+
+        buffer.extend((self.sequences.len() as u32).to_le_bytes());
+        let mut sequence_bytes = Vec::new();
+        for sequence in &self.sequences {
+            sequence.consensus_encode(&mut sequence_bytes).unwrap();
+        }
+        buffer.extend(sha256::Hash::hash(&sequence_bytes).to_byte_array());
+
+        buffer.extend((self.outputs.len() as u32).to_le_bytes());
+        let mut output_bytes = Vec::new();
+        for output in &self.outputs {
+            output.consensus_encode(&mut output_bytes).unwrap();
+        }
+        buffer.extend(sha256::Hash::hash(&output_bytes).to_byte_array());
+
+        buffer.extend(self.input_index.to_le_bytes());
+
+        TemplateHash(sha256::Hash::hash(&buffer).to_byte_array())
+    }
+}
+
+//BIP-119 DefaultCheckTemplateVerifyHash, the hash a ctv leaf commits to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TemplateHash([u8; 32]);
+
+impl TemplateHash {
+    //hash of tx spent at input_index. Like OP_CTV itself this doesn't check input_index is one of
+    //the tx's inputs, it's only committed to
+    pub fn from_tx(tx: &Transaction, input_index: u32) -> Self {
+        CtvTemplate::from_tx(tx, input_index).hash()
+    }
+
+    pub fn from_byte_array(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_byte_array(self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl fmt::Display for TemplateHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.as_hex())
+    }
+}
+
+//signs the script path sighash of an exit leaf
+pub type LeafSigner<'a> = dyn Fn(TapSighash) -> Result<schnorr::Signature> + 'a;
+
+//an unsigned spend of the pool output (prevout) through leaf_script, everything needed to build
+//its witness. finalize it directly or export it as a psbt for a wallet to sign
+#[derive(Debug, Clone)]
+pub struct LeafSpend {
+    pub unsigned_tx: Transaction,
+    pub spend_info: TaprootSpendInfo,
+    pub leaf_script: ScriptBuf,
+    pub prevout: TxOut,
+}
+
+impl LeafSpend {
+    //whether the leaf ends in OP_CHECKSIG and so needs a signature from its exit key
+    pub fn needs_signature(&self) -> bool {
+        self.leaf_script.as_bytes().last() == Some(&OP_CHECKSIG.to_u8())
+    }
+
+    pub fn finalize(self, signer: Option<&LeafSigner>) -> Result<Transaction> {
+        spend_ctv(
+            self.unsigned_tx,
+            self.spend_info,
+            self.leaf_script,
+            &self.prevout,
+            signer,
+        )
+    }
+}
+
+//spend the pool output (prevout) through leaf_script. Leaves from ctv_checksig_script need signer,
+//it only signs the witness so the txid is the same with or without the signature
 pub fn spend_ctv(
     mut unsigned_tx: Transaction,
     taproot_spend_info: TaprootSpendInfo,
-    ctv_hash: [u8; 32],
-) -> Transaction {
-    let ctv_script = ctv_script(ctv_hash);
+    leaf_script: ScriptBuf,
+    prevout: &TxOut,
+    signer: Option<&LeafSigner>,
+) -> Result<Transaction> {
+    let needs_signature = leaf_script.as_bytes().last() == Some(&OP_CHECKSIG.to_u8());
 
-    //TO DO - add a signature here for the spends, for now it works ok as an example,
-    //or maybe we dont even need them, it just means anyone with the descriptor can spend these...
+    let script_ver = (leaf_script, LeafVersion::TapScript);
+    let ctrl_block = taproot_spend_info
+        .control_block(&script_ver)
+        .ok_or_else(|| anyhow!("leaf is not in the pool state's taptree"))?;
 
-    for input in unsigned_tx.input.iter_mut() {
-        let script_ver = (ctv_script.clone(), LeafVersion::TapScript);
-        let ctrl_block = taproot_spend_info.control_block(&script_ver).unwrap();
+    let signature = if needs_signature {
+        let signer = signer.ok_or_else(|| anyhow!("exit leaf needs the member's signature"))?;
+        ensure!(
+            unsigned_tx.input.len() == 1,
+            "signed exits spend a single pool output"
+        );
+
+        let sighash = SighashCache::new(&unsigned_tx).taproot_script_spend_signature_hash(
+            0,
+            &Prevouts::All(&[prevout]),
+            TapLeafHash::from_script(&script_ver.0, script_ver.1),
+            TapSighashType::Default,
+        )?;
+
+        let signature = taproot::Signature {
+            signature: signer(sighash)?,
+            sighash_type: TapSighashType::Default,
+        };
+        Some(signature.to_vec())
+    } else {
+        None
+    };
 
-        input.witness.push(script_ver.0.into_bytes());
+    for input in unsigned_tx.input.iter_mut() {
+        if let Some(signature) = &signature {
+            input.witness.push(signature);
+        }
+        input.witness.push(script_ver.0.clone().into_bytes());
         input.witness.push(ctrl_block.serialize());
     }
-    unsigned_tx
+    Ok(unsigned_tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::encode::deserialize_hex;
+    use serde::Deserialize;
+
+    //bip-0119/vectors/ctvhash.json from the BIPs repo, the first entry describes the format
+    const CTVHASH_VECTORS: &str = include_str!("../tests/data/ctvhash.json");
+
+    #[derive(Deserialize)]
+    struct CtvHashVector {
+        hex_tx: String,
+        spend_index: Vec<u32>,
+        result: Vec<String>,
+    }
+
+    #[test]
+    fn template_hash_matches_the_bip119_vectors() {
+        let entries: Vec<serde_json::Value> = serde_json::from_str(CTVHASH_VECTORS).unwrap();
+
+        let (mut checked, mut with_script_sig, mut later_input) = (0, 0, 0);
+        for entry in entries.into_iter().filter(|entry| entry.is_object()) {
+            let vector: CtvHashVector = serde_json::from_value(entry).unwrap();
+            let tx: Transaction = deserialize_hex(&vector.hex_tx).unwrap();
+            let has_script_sig = tx.input.iter().any(|input| !input.script_sig.is_empty());
+
+            assert_eq!(vector.spend_index.len(), vector.result.len());
+            for (&input_index, expected) in vector.spend_index.iter().zip(&vector.result) {
+                let hash = CtvTemplate::from_tx(&tx, input_index).hash();
+                assert_eq!(hash.to_string(), *expected, "tx {}", vector.hex_tx);
+
+                checked += 1;
+                with_script_sig += has_script_sig as usize;
+                later_input += (input_index > 0) as usize;
+            }
+        }
+
+        //the vectors have to cover the script sigs hash and inputs other than the first
+        assert_eq!(checked, 400);
+        assert!(with_script_sig > 0, "no vector with a script sig");
+        assert!(later_input > 0, "no vector spending a later input");
+    }
+
+    //calc_ctv_hash builds the one input template every pool leaf commits to
+    #[test]
+    fn calc_ctv_hash_is_the_template_hash_of_a_pool_spend() {
+        let outputs = vec![TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_op_return([1, 2, 3]),
+        }];
+        let template = CtvTemplate::pool_spend(outputs.clone(), Sequence::ENABLE_RBF_NO_LOCKTIME);
+
+        assert_eq!(
+            calc_ctv_hash(&outputs, None),
+            template.hash().to_byte_array()
+        );
+    }
 }
@@ -0,0 +1,298 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+use bitcoin::{
+    hex::DisplayHex,
+    psbt::{Input, Psbt},
+    taproot::{LeafVersion, TapLeafHash, TapTree, TaprootSpendInfo},
+    OutPoint, Script, Transaction, Witness,
+};
+
+use crate::{
+    ctv_scripts::LeafSpend, members::MemberSet, payment_pool::PaymentPool, pool_file::state_tree,
+    verify::parse_ctv_leaf,
+};
+
+//characters a descriptor can use, the checksum depends on their position here (BIP-380)
+const INPUT_CHARSET: &str =
+    "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+//tr(internal_key,{raw(leaf),...}) with its checksum. Miniscript has no OP_CTV so every leaf is a
+//raw script, the descriptor describes the output for watching it but wallets can't import it to sign
+pub fn tr_descriptor(spend_info: &TaprootSpendInfo) -> Result<String> {
+    let tree = state_tree(spend_info)?;
+    let leaves: Vec<(u8, &Script)> = tree
+        .leaf_nodes()
+        .filter_map(|leaf| Some((leaf.depth(), leaf.script()?)))
+        .collect();
+
+    let mut next_leaf = 0;
+    let tree = descriptor_tree(&leaves, &mut next_leaf, 0)?;
+    ensure!(
+        next_leaf == leaves.len(),
+        "taptree has leaves outside the tree"
+    );
+
+    let descriptor = format!("tr({},{})", spend_info.internal_key(), tree);
+    let checksum = descriptor_checksum(&descriptor)?;
+    Ok(format!("{}#{}", descriptor, checksum))
+}
+
+fn state_taptree(spend_info: &TaprootSpendInfo) -> Result<TapTree> {
+    TapTree::try_from(state_tree(spend_info)?)
+        .map_err(|_| anyhow!("pool state taptree has hidden leaves"))
+}
+
+//leaves are in depth first order, so a leaf deeper than depth is somewhere under a {left,right} branch
+fn descriptor_tree(leaves: &[(u8, &Script)], next_leaf: &mut usize, depth: u8) -> Result<String> {
+    let Some(&(leaf_depth, script)) = leaves.get(*next_leaf) else {
+        bail!("taptree ended before every branch had a leaf");
+    };
+
+    if leaf_depth == depth {
+        *next_leaf += 1;
+        return Ok(format!("raw({})", script.as_bytes().to_lower_hex_string()));
+    }
+    ensure!(leaf_depth > depth, "taptree leaf depths are out of order");
+
+    let left = descriptor_tree(leaves, next_leaf, depth + 1)?;
+    let right = descriptor_tree(leaves, next_leaf, depth + 1)?;
+    Ok(format!("{{{},{}}}", left, right))
+}
+
+//the 8 character checksum after the # of a descriptor (BIP-380)
+fn descriptor_checksum(descriptor: &str) -> Result<String> {
+    let mut checksum = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+
+    for ch in descriptor.chars() {
+        let position = INPUT_CHARSET
+            .find(ch)
+            .ok_or_else(|| anyhow!("invalid descriptor character {:?}", ch))?
+            as u64;
+
+        checksum = polymod(checksum, position & 31);
+        class = class * 3 + (position >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            checksum = polymod(checksum, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        checksum = polymod(checksum, class);
+    }
+    for _ in 0..8 {
+        checksum = polymod(checksum, 0);
+    }
+    checksum ^= 1;
+
+    Ok((0..8)
+        .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect())
+}
+
+fn polymod(checksum: u64, value: u64) -> u64 {
+    const GENERATOR: [u64; 5] = [
+        0xf5dee51989,
+        0xa9fdca3312,
+        0x1bab10e32d,
+        0x3706b1677a,
+        0x644d626ffd,
+    ];
+
+    let top = checksum >> 35;
+    let mut checksum = ((checksum & 0x7ffffffff) << 5) ^ value;
+    for (bit, generator) in GENERATOR.iter().enumerate() {
+        if (top >> bit) & 1 == 1 {
+            checksum ^= generator;
+        }
+    }
+    checksum
+}
+
+impl LeafSpend {
+    //a psbt with the leaf being spent, its control block and the pool output, enough for a wallet
+    //to check the spend and for finalize_psbt to build the witness. The pool doesn't know where an
+    //exit key comes from, so the member adds it to tap_key_origins with its real origin if their
+    //signer needs one
+    pub fn to_psbt(&self) -> Result<Psbt> {
+        ensure!(
+            self.unsigned_tx.input.len() == 1,
+            "pool spends have a single input"
+        );
+
+        let mut psbt = Psbt::from_unsigned_tx(self.unsigned_tx.clone())?;
+        let script_ver = (self.leaf_script.clone(), LeafVersion::TapScript);
+        let control_block = self
+            .spend_info
+            .control_block(&script_ver)
+            .ok_or_else(|| anyhow!("leaf is not in the pool state's taptree"))?;
+        parse_ctv_leaf(&self.leaf_script).ok_or_else(|| anyhow!("leaf is not a pool CTV leaf"))?;
+
+        let input = &mut psbt.inputs[0];
+        input.witness_utxo = Some(self.prevout.clone());
+        input.tap_internal_key = Some(self.spend_info.internal_key());
+        input.tap_merkle_root = self.spend_info.merkle_root();
+        input.tap_scripts.insert(control_block, script_ver);
+
+        Ok(psbt)
+    }
+}
+
+//build the witness of every input from its leaf in tap_scripts and, for exit leaves with a key,
+//the signature in tap_script_sigs, then extract the tx
+pub fn finalize_psbt(mut psbt: Psbt) -> Result<Transaction> {
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        if input.final_script_witness.is_some() {
+            continue;
+        }
+
+        ensure!(
+            input.tap_scripts.len() == 1,
+            "input {} has {} tap scripts, expected the one leaf it spends",
+            index,
+            input.tap_scripts.len()
+        );
+        let (control_block, (leaf_script, leaf_version)) = input.tap_scripts.iter().next().unwrap();
+        let (_, exit_key) = parse_ctv_leaf(leaf_script)
+            .ok_or_else(|| anyhow!("input {} does not spend a pool CTV leaf", index))?;
+
+        let mut witness = Witness::new();
+        if let Some(exit_key) = exit_key {
+            let leaf_hash = TapLeafHash::from_script(leaf_script, *leaf_version);
+            let signature = input
+                .tap_script_sigs
+                .get(&(exit_key, leaf_hash))
+                .ok_or_else(|| {
+                    anyhow!("input {} is missing the signature of {}", index, exit_key)
+                })?;
+            witness.push(signature.to_vec());
+        }
+        witness.push(leaf_script.as_bytes());
+        witness.push(control_block.serialize());
+
+        //a finalized input only keeps the utxo and the final witness (BIP-174)
+        *input = Input {
+            witness_utxo: input.witness_utxo.take(),
+            final_script_witness: Some(witness),
+            ..Default::default()
+        };
+    }
+
+    Ok(psbt.extract_tx_unchecked_fee_rate())
+}
+
+impl PaymentPool {
+    //descriptor of the pool state holding users
+    pub fn state_descriptor(&self, users: &MemberSet) -> Result<String> {
+        tr_descriptor(&self.spend_info(users)?)
+    }
+
+    //exit_spend as a psbt. When the exit pays into the next pool state the first output carries
+    //that state's internal key and taptree so a wallet can see where the rest of the pool goes
+    pub fn exit_psbt(
+        &self,
+        previous_pool: &MemberSet,
+        spender_index: usize,
+        previous_output: OutPoint,
+    ) -> Result<Psbt> {
+        let mut psbt = self
+            .exit_spend(previous_pool, spender_index, previous_output)?
+            .to_psbt()?;
+
+        if previous_pool.len() > 2 {
+            let spend_info = self.spend_info(&previous_pool.without(spender_index))?;
+            let output = &mut psbt.outputs[0];
+            output.tap_internal_key = Some(spend_info.internal_key());
+            output.tap_tree = Some(state_taptree(&spend_info)?);
+        }
+
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::PoolParams, test_utils::test_pool};
+
+    //the part before the # and the checksum after it
+    fn split_checksum(descriptor: &str) -> (&str, &str) {
+        descriptor.split_once('#').unwrap()
+    }
+
+    #[test]
+    fn checksum_matches_known_descriptors() {
+        //BIP-380
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+
+        //from rust-miniscript's descriptor tests
+        assert_eq!(
+            descriptor_checksum(
+                "tr(03cc8a4bc64d897bddc5fbc2f670f7a8ba0b386779106cf1223c6fc5d7cd6fc115)"
+            )
+            .unwrap(),
+            "6qm9h8ym"
+        );
+        let p1 = "020000000000000000000000000000000000000000000000000000000000000001";
+        let p2 = "020000000000000000000000000000000000000000000000000000000000000002";
+        let p3 = "020000000000000000000000000000000000000000000000000000000000000003";
+        let p4 = "020000000000000000000000000000000000000000000000000000000000000004";
+        let p5 = "f54a5851e9372b87810a8e60cdd2e7cfd80b6e31";
+        let tree = format!(
+            "tr({},{{pk({}),{{pk({}),or_d(pk({}),pkh({}))}}}})",
+            p1, p2, p3, p4, p5
+        );
+        assert_eq!(descriptor_checksum(&tree).unwrap(), "fdhmu4fj");
+    }
+
+    #[test]
+    fn checksum_rejects_characters_outside_the_charset() {
+        assert!(descriptor_checksum("raw(deadbeef)\u{e9}").is_err());
+    }
+
+    #[test]
+    fn descriptor_has_every_leaf_and_a_valid_checksum() {
+        let pool = test_pool(
+            PoolParams {
+                users: 4,
+                ..Default::default()
+            },
+            &[20000; 4],
+        );
+        let users = MemberSet::full(4);
+        let spend_info = pool.spend_info(&users).unwrap();
+
+        let descriptor = pool.state_descriptor(&users).unwrap();
+        let (body, checksum) = split_checksum(&descriptor);
+        assert_eq!(descriptor_checksum(body).unwrap(), checksum);
+        assert!(body.starts_with(&format!("tr({},{{", spend_info.internal_key())));
+
+        //4 exits and the timeout split
+        assert_eq!(body.matches("raw(").count(), 5);
+        for (script, _) in spend_info.script_map().keys() {
+            assert!(body.contains(&format!("raw({})", script.as_bytes().to_lower_hex_string())));
+        }
+    }
+
+    #[test]
+    fn exit_psbt_has_no_made_up_key_origins() {
+        let pool = test_pool(
+            PoolParams {
+                users: 3,
+                ..Default::default()
+            },
+            &[20000; 3],
+        );
+        let psbt = pool
+            .exit_psbt(&MemberSet::full(3), 1, OutPoint::null())
+            .unwrap();
+
+        assert!(psbt.inputs[0].tap_key_origins.is_empty());
+        assert_eq!(psbt.inputs[0].tap_scripts.len(), 1);
+        assert!(psbt.outputs[0].tap_tree.is_some());
+    }
+}
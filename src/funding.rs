@@ -0,0 +1,1561 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt, fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use bitcoin::{
+    absolute, ecdsa,
+    hashes::Hash,
+    psbt::{raw::ProprietaryKey, Input, Output, Psbt},
+    script::Instruction,
+    secp256k1::{schnorr, Message},
+    sighash::{Prevouts, SighashCache},
+    taproot::{self, LeafVersion},
+    transaction, Address, Amount, CompressedPublicKey, OutPoint, PublicKey, ScriptBuf, Sequence,
+    Transaction, TxIn, TxOut, Weight, XOnlyPublicKey,
+};
+use tracing::{info, warn};
+
+use crate::{
+    chain::{psbt_from_base64, psbt_to_base64, ChainBackend, Utxo},
+    config::{PoolMode, PoolParams},
+    cpfp::input_weight,
+    ctv_scripts::{LeafSpend, SECP},
+    members::{MemberSet, PoolMember},
+    payment_pool::PaymentPool,
+    split_tree::{tree_children, tree_path, tree_payout},
+    verify::{verify_ctv_spend, CtvVerifyError},
+};
+
+//Funding round: the coordinator publishes a proposal psbt paying the pool's funding address, every
+//member answers with a contribution psbt holding only their own inputs and change, the coordinator
+//joins them into the unsigned funding psbt, every member signs their own inputs of it with their
+//own wallet and the coordinator finalizes once all of them have. Each member pays their deposit
+//plus the fee for the weight they add and an even share of the rest of the tx.
+
+//proprietary psbt keys, so a psbt says which round settings and member it belongs to
+const PSBT_PREFIX: &[u8] = b"ctvpool";
+const FEE_RATE_KEY: u8 = 0;
+const MEMBERS_KEY: u8 = 1;
+const MEMBER_KEY: u8 = 2;
+
+//how long the coordinator waits by default for each member's message and for every contribution
+pub const ROUND_TIMEOUT: Duration = Duration::from_secs(600);
+
+//how often the coordinator checks for new connections while it waits for contributions
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+fn proprietary_key(subtype: u8) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_PREFIX.to_vec(),
+        subtype,
+        key: vec![],
+    }
+}
+
+fn set_global(psbt: &mut Psbt, subtype: u8, value: u64) {
+    psbt.proprietary
+        .insert(proprietary_key(subtype), value.to_le_bytes().to_vec());
+}
+
+fn global(psbt: &Psbt, subtype: u8, name: &str) -> Result<u64> {
+    let value = psbt
+        .proprietary
+        .get(&proprietary_key(subtype))
+        .ok_or_else(|| anyhow!("psbt has no {}", name))?;
+    let bytes: [u8; 8] = value
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("psbt {} isn't 8 bytes", name))?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+//the member a contribution or signed psbt comes from
+pub fn psbt_member(psbt: &Psbt) -> Result<usize> {
+    Ok(global(psbt, MEMBER_KEY, "member index")? as usize)
+}
+
+//scripts up to 252 bytes have a 1 byte length
+fn output_weight(script: &ScriptBuf) -> Weight {
+    Weight::from_non_witness_data_size(8 + 1 + script.len() as u64)
+}
+
+//Weight a member adds to the funding tx with their inputs and change, plus an even share of the
+//version, locktime, counts, segwit marker and pool output. Inputs are sized for their largest
+//signature so the real tx can only be lighter
+fn contribution_weight(
+    prevouts: &[&TxOut],
+    change: &[&TxOut],
+    members: usize,
+    pool_script: &ScriptBuf,
+) -> Result<Weight> {
+    let mut own = Weight::ZERO;
+    for prevout in prevouts {
+        let prediction = input_weight(&prevout.script_pubkey).ok_or_else(|| {
+            anyhow!(
+                "can't size a spend of {}, use a segwit or p2pkh output",
+                prevout.script_pubkey
+            )
+        })?;
+        //outpoint and sequence, the script sig length is part of the prediction
+        own += prediction.weight() + Weight::from_non_witness_data_size(32 + 4 + 4);
+    }
+    for output in change {
+        own += output_weight(&output.script_pubkey);
+    }
+
+    //version, locktime, 1 byte counts and the segwit marker and flag
+    let shared = Weight::from_non_witness_data_size(4 + 4 + 1 + 1)
+        + Weight::from_witness_data_size(2)
+        + output_weight(pool_script);
+    Ok(own + Weight::from_wu(shared.to_wu().div_ceil(members as u64)))
+}
+
+//fee_rate is in sat/kvB like DEFAULT_FEE_RATE
+fn fee_for(weight: Weight, fee_rate: u64) -> Amount {
+    Amount::from_sat((weight.to_vbytes_ceil() * fee_rate).div_ceil(1000))
+}
+
+//the round's settings every member reads from the proposal
+struct Proposal {
+    pool_output: TxOut,
+    fee_rate: u64,
+    members: usize,
+}
+
+impl Proposal {
+    fn read(proposal: &Psbt) -> Result<Self> {
+        let tx = &proposal.unsigned_tx;
+        ensure!(
+            tx.input.is_empty() && tx.output.len() == 1,
+            "a funding proposal has no inputs and only the pool output"
+        );
+        let members = global(proposal, MEMBERS_KEY, "member count")? as usize;
+        ensure!(members > 0, "funding proposal has no members");
+        Ok(Self {
+            pool_output: tx.output[0].clone(),
+            fee_rate: global(proposal, FEE_RATE_KEY, "fee rate")?,
+            members,
+        })
+    }
+}
+
+//Member side: pick utxos (largest first) to pay deposit and the member's fee share at the round's
+//fee rate, change goes to change_addr unless it would be dust
+pub fn contribute(
+    proposal: &Psbt,
+    member: usize,
+    deposit: Amount,
+    utxos: Vec<Utxo>,
+    change_addr: &Address,
+) -> Result<Psbt> {
+    let round = Proposal::read(proposal)?;
+    ensure!(
+        member < round.members,
+        "member {} isn't in a {} member round",
+        member,
+        round.members
+    );
+
+    let mut candidates: Vec<Utxo> = utxos
+        .into_iter()
+        .filter(|utxo| input_weight(&utxo.txout.script_pubkey).is_some())
+        .collect();
+    candidates.sort_by_key(|utxo| std::cmp::Reverse(utxo.txout.value));
+
+    let pool_script = &round.pool_output.script_pubkey;
+    let mut change = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_addr.script_pubkey(),
+    };
+
+    let mut needed = deposit;
+    for selected in 1..=candidates.len() {
+        let inputs = &candidates[..selected];
+        let prevouts: Vec<&TxOut> = inputs.iter().map(|utxo| &utxo.txout).collect();
+        let input_value: Amount = inputs.iter().map(|utxo| utxo.txout.value).sum();
+
+        let with_change = fee_for(
+            contribution_weight(&prevouts, &[&change], round.members, pool_script)?,
+            round.fee_rate,
+        );
+        let without_change = fee_for(
+            contribution_weight(&prevouts, &[], round.members, pool_script)?,
+            round.fee_rate,
+        );
+        needed = deposit + without_change;
+
+        let outputs =
+            if input_value >= deposit + with_change + change.script_pubkey.minimal_non_dust() {
+                change.value = input_value - deposit - with_change;
+                vec![change.clone()]
+            } else if input_value >= needed {
+                //the leftover is dust, it goes to the fee
+                vec![]
+            } else {
+                continue;
+            };
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: utxo.outpoint,
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    ..Default::default()
+                })
+                .collect(),
+            output: outputs,
+        };
+
+        let mut contribution = Psbt::from_unsigned_tx(tx)?;
+        for (input, utxo) in contribution.inputs.iter_mut().zip(inputs) {
+            input.witness_utxo = Some(utxo.txout.clone());
+        }
+        set_global(&mut contribution, MEMBER_KEY, member as u64);
+        return Ok(contribution);
+    }
+
+    bail!(
+        "member {} has no utxos covering their {} sat deposit and fee",
+        member,
+        needed.to_sat()
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FundingVerifyError {
+    NotAMember {
+        member: usize,
+        members: usize,
+    },
+    //the pool's entry for the member isn't the withdraw address, deposit and keys they picked
+    MemberMismatch {
+        member: usize,
+    },
+    //the pool's params (fees, timeout, layout..) aren't the ones the member agreed to
+    ParamsMismatch {
+        agreed: PoolParams,
+        found: PoolParams,
+    },
+    //the pool tree couldn't be built from the pool's params and members
+    Rebuild(String),
+    PoolOutputMissing {
+        address: Address,
+    },
+    PoolAmountMismatch {
+        expected: Amount,
+        found: Amount,
+    },
+    //an exit of the member from this pool state doesn't pay them their deposit
+    ExitMismatch {
+        member: usize,
+        users: MemberSet,
+    },
+    //member's exit from this pool state doesn't spend the state's pool output
+    ExitInvalid {
+        member: usize,
+        users: MemberSet,
+        reason: String,
+    },
+}
+
+impl fmt::Display for FundingVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FundingVerifyError::NotAMember { member, members } => write!(
+                f,
+                "member {} isn't in a {} member pool",
+                member, members
+            ),
+            FundingVerifyError::MemberMismatch { member } => write!(
+                f,
+                "the pool's member {} doesn't have the withdraw address, deposit and keys they picked",
+                member
+            ),
+            FundingVerifyError::ParamsMismatch { agreed, found } => write!(
+                f,
+                "the pool's params {:?} aren't the ones the member agreed to {:?}",
+                found, agreed
+            ),
+            FundingVerifyError::Rebuild(e) => write!(f, "can't rebuild the pool: {}", e),
+            FundingVerifyError::PoolOutputMissing { address } => {
+                write!(f, "funding psbt doesn't pay the pool address {}", address)
+            }
+            FundingVerifyError::PoolAmountMismatch { expected, found } => write!(
+                f,
+                "funding psbt pays {} sat to the pool, the members' deposits add up to {} sat",
+                found.to_sat(),
+                expected.to_sat()
+            ),
+            FundingVerifyError::ExitMismatch { member, users } => write!(
+                f,
+                "member {}'s exit from pool state {:?} doesn't pay them their deposit",
+                member, users
+            ),
+            FundingVerifyError::ExitInvalid {
+                member,
+                users,
+                reason,
+            } => write!(
+                f,
+                "member {}'s exit from pool state {:?} doesn't spend the pool output: {}",
+                member, users, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FundingVerifyError {}
+
+//Member side: Rebuild the whole pool tree from scratch out of the params the member agreed to
+//(which the pool's have to match) and every member's withdraw address, deposit and keys, ignoring
+//any states the pool already has (they may come from the coordinator's pool file). The funding psbt has to pay the rebuilt funding address
+//exactly the sum of the deposits, and every pool state the member is in has to have an exit that
+//spends that state's output (tracked down from the psbt's pool output) and pays
+//own.withdraw_address their deposit less the exit fee. In a split tree every split on the
+//member's path has to spend the one above it the same way and the last one has to pay them
+pub fn verify_funding_psbt(
+    pool: &PaymentPool,
+    agreed: &PoolParams,
+    member: usize,
+    own: &PoolMember,
+    unsigned: &Psbt,
+) -> Result<(), FundingVerifyError> {
+    let members = pool.members();
+    if member >= members.len() {
+        return Err(FundingVerifyError::NotAMember {
+            member,
+            members: members.len(),
+        });
+    }
+    if members[member] != *own {
+        return Err(FundingVerifyError::MemberMismatch { member });
+    }
+    //the exit fee and everything else the exits are built from comes from the params, so they
+    //can't be the coordinator's word
+    if pool.params() != agreed {
+        return Err(FundingVerifyError::ParamsMismatch {
+            agreed: *agreed,
+            found: *pool.params(),
+        });
+    }
+
+    let rebuilt = PaymentPool::new(
+        *agreed,
+        pool.network(),
+        members.to_vec(),
+        pool.anchor_addr().clone(),
+    )
+    .map_err(|e| FundingVerifyError::Rebuild(format!("{:#}", e)))?;
+    let address = rebuilt
+        .funding_address()
+        .map_err(|e| FundingVerifyError::Rebuild(format!("{:#}", e)))?;
+
+    let pool_script = address.script_pubkey();
+    let pool_output = unsigned
+        .unsigned_tx
+        .output
+        .iter()
+        .find(|output| output.script_pubkey == pool_script)
+        .ok_or_else(|| FundingVerifyError::PoolOutputMissing {
+            address: address.clone(),
+        })?;
+    if pool_output.value != rebuilt.funding_amount() {
+        return Err(FundingVerifyError::PoolAmountMismatch {
+            expected: rebuilt.funding_amount(),
+            found: pool_output.value,
+        });
+    }
+
+    let own_script = own.withdraw_address.script_pubkey();
+    if pool.params().mode == PoolMode::SplitTree {
+        return verify_tree_path(&rebuilt, member, &own_script, pool_output);
+    }
+
+    verify_exits(&rebuilt, member, &own_script, pool_output)
+}
+
+//every exit of the member has to spend the pool output it's built for, checked from the funding
+//output down: the full state's output is the psbt's pool output and every smaller state's is the
+//output 0 of the exit from its parent, which that exit's template commits to. states are visited
+//biggest first so a parent's output is always known before its children need it
+fn verify_exits(
+    rebuilt: &PaymentPool,
+    member: usize,
+    own_script: &ScriptBuf,
+    pool_output: &TxOut,
+) -> Result<(), FundingVerifyError> {
+    let users = rebuilt.params().users;
+    let own_exit = rebuilt.members()[member].deposit - rebuilt.params().exit_fee();
+    let mut states: Vec<MemberSet> = rebuilt.with_states(|states| {
+        states
+            .keys()
+            .filter(|users| users.contains(member))
+            .cloned()
+            .collect()
+    });
+    states.sort_by_key(|state| Reverse(state.len()));
+
+    let mut outputs = HashMap::from([(MemberSet::full(users), pool_output.clone())]);
+    for state in states {
+        let prevout = match outputs.get(&state) {
+            Some(prevout) => prevout.clone(),
+            None => {
+                //any member that isn't in the state leaves a parent that holds it
+                let leaver = (0..users)
+                    .find(|user| !state.contains(*user))
+                    .expect("only the full state holds every member");
+                let mut parent = state.clone();
+                parent.insert(leaver);
+                let exit = verified_exit(rebuilt, &parent, leaver, &outputs[&parent])?;
+                exit.output[0].clone()
+            }
+        };
+
+        let exit = verified_exit(rebuilt, &state, member, &prevout)?;
+        let pays_member = exit
+            .output
+            .iter()
+            .any(|output| output.script_pubkey == *own_script && output.value >= own_exit);
+        if !pays_member {
+            return Err(FundingVerifyError::ExitMismatch {
+                member,
+                users: state,
+            });
+        }
+        outputs.insert(state, prevout);
+    }
+
+    Ok(())
+}
+
+//build spender's exit from the state and check it really spends prevout
+fn verified_exit(
+    rebuilt: &PaymentPool,
+    users: &MemberSet,
+    spender: usize,
+    prevout: &TxOut,
+) -> Result<Transaction, FundingVerifyError> {
+    rebuilt
+        .exit_spend(users, spender, OutPoint::null())
+        .map_err(|e| format!("{:#}", e))
+        .and_then(|spend| verified_spend(spend, prevout))
+        .map_err(|reason| FundingVerifyError::ExitInvalid {
+            member: spender,
+            users: users.clone(),
+            reason,
+        })
+}
+
+//finalize spend and check it really spends prevout: the leaf has to be in the taptree of
+//prevout's output key and the tx has to match the template the leaf commits to. only an exit key
+//can sign, so a leaf that needs a signature gets a placeholder and a bad signature is all that may
+//fail, verify_ctv_spend checks everything else before it
+fn verified_spend(spend: LeafSpend, prevout: &TxOut) -> Result<Transaction, String> {
+    if spend
+        .spend_info
+        .control_block(&(spend.leaf_script.clone(), LeafVersion::TapScript))
+        .is_none()
+    {
+        return Err("the leaf isn't in the state's taptree".to_string());
+    }
+
+    let needs_signature = spend.needs_signature();
+    let placeholder = |_| Ok(schnorr::Signature::from_slice(&[1; 64])?);
+    let tx = spend
+        .finalize(Some(&placeholder))
+        .map_err(|e| format!("{:#}", e))?;
+    match verify_ctv_spend(&tx, 0, std::slice::from_ref(prevout), &[]) {
+        Ok(_) => Ok(tx),
+        Err(CtvVerifyError::InvalidSignature) if needs_signature => Ok(tx),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+//in a split tree the member is paid by the last split on their path. every split has to spend
+//the output of the one above it, starting at the psbt's pool output, so the half the member is in
+//is whatever the checked split commits to and the last one has to pay them
+fn verify_tree_path(
+    rebuilt: &PaymentPool,
+    member: usize,
+    own_script: &ScriptBuf,
+    pool_output: &TxOut,
+) -> Result<(), FundingVerifyError> {
+    let own_payout = tree_payout(rebuilt.params(), rebuilt.members(), member);
+
+    let mut prevout = pool_output.clone();
+    let mut last = MemberSet::full(rebuilt.params().users);
+    for node in tree_path(rebuilt.params().users, member) {
+        let split = rebuilt
+            .tree_split_spend(&node, OutPoint::null())
+            .map_err(|e| format!("{:#}", e))
+            .and_then(|spend| verified_spend(spend, &prevout))
+            .map_err(|reason| FundingVerifyError::ExitInvalid {
+                member,
+                users: node.clone(),
+                reason,
+            })?;
+
+        //the left half is output 0, the right one 1
+        let (left, _) = tree_children(&node);
+        prevout = split.output[usize::from(!left.contains(member))].clone();
+        last = node;
+    }
+
+    if prevout.script_pubkey != *own_script || prevout.value < own_payout {
+        return Err(FundingVerifyError::ExitMismatch {
+            member,
+            users: last,
+        });
+    }
+
+    Ok(())
+}
+
+//Member side: refuse to sign unless verify_funding_psbt passes and the unsigned funding psbt
+//still has every input and change output of the member's contribution, then sign their inputs
+//with their wallet
+pub fn sign_contribution(
+    chain: &dyn ChainBackend,
+    pool: &PaymentPool,
+    agreed: &PoolParams,
+    own: &PoolMember,
+    unsigned: &Psbt,
+    contribution: &Psbt,
+) -> Result<Psbt> {
+    let member = psbt_member(contribution)?;
+    let tx = &unsigned.unsigned_tx;
+
+    verify_funding_psbt(pool, agreed, member, own, unsigned)?;
+
+    let own_inputs: Vec<usize> = contribution
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|own| {
+            tx.input
+                .iter()
+                .position(|input| input.previous_output == own.previous_output)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "funding psbt is missing member {}'s input {}",
+                        member,
+                        own.previous_output
+                    )
+                })
+        })
+        .collect::<Result<_>>()?;
+    for own in &contribution.unsigned_tx.output {
+        ensure!(
+            tx.output.contains(own),
+            "funding psbt is missing member {}'s change output",
+            member
+        );
+    }
+
+    let mut signed = chain.sign_wallet_psbt(unsigned.clone())?;
+    for &index in &own_inputs {
+        ensure!(
+            is_finalized(&signed.inputs[index]),
+            "wallet didn't sign member {}'s input {}",
+            member,
+            tx.input[index].previous_output
+        );
+    }
+
+    set_global(&mut signed, MEMBER_KEY, member as u64);
+    Ok(signed)
+}
+
+fn is_finalized(input: &Input) -> bool {
+    input.final_script_witness.is_some() || input.final_script_sig.is_some()
+}
+
+//Check the witness or script sig of input index of tx is a valid signature for prevouts[index],
+//for every script a contribution can spend (see input_weight). The sighash commits to the tx
+//so the signature only counts for this funding tx. Prevouts are the ones the contributions said
+//they spend, a member lying about their own prevout only gets the funding tx rejected by the node
+fn verify_signed_input(tx: &Transaction, index: usize, prevouts: &[TxOut]) -> Result<()> {
+    let input = &tx.input[index];
+    let script = &prevouts[index].script_pubkey;
+    let mut sighashes = SighashCache::new(tx);
+    let witness: Vec<&[u8]> = input.witness.iter().collect();
+
+    if script.is_p2tr() {
+        let [signature] = witness[..] else {
+            bail!(
+                "a key path spend has one witness item, not {}",
+                witness.len()
+            );
+        };
+        let signature = taproot::Signature::from_slice(signature)?;
+        let sighash = sighashes.taproot_key_spend_signature_hash(
+            index,
+            &Prevouts::All(prevouts),
+            signature.sighash_type,
+        )?;
+        let output_key = XOnlyPublicKey::from_slice(&script.as_bytes()[2..34])?;
+        SECP.verify_schnorr(
+            &signature.signature,
+            &Message::from_digest(sighash.to_byte_array()),
+            &output_key,
+        )?;
+    } else if script.is_p2wpkh() {
+        let [signature, pubkey] = witness[..] else {
+            bail!("a p2wpkh spend has 2 witness items, not {}", witness.len());
+        };
+        let pubkey = CompressedPublicKey::from_slice(pubkey)?;
+        ensure!(
+            ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash()) == *script,
+            "the witness key isn't the one {} pays to",
+            script
+        );
+        let signature = ecdsa::Signature::from_slice(signature)?;
+        let sighash = sighashes.p2wpkh_signature_hash(
+            index,
+            script,
+            prevouts[index].value,
+            signature.sighash_type,
+        )?;
+        SECP.verify_ecdsa(
+            &Message::from_digest(sighash.to_byte_array()),
+            &signature.signature,
+            &pubkey.0,
+        )?;
+    } else if script.is_p2pkh() {
+        let pushes = input
+            .script_sig
+            .instructions()
+            .map(|instruction| match instruction {
+                Ok(Instruction::PushBytes(bytes)) => Ok(bytes.as_bytes()),
+                _ => Err(anyhow!(
+                    "a p2pkh script sig only pushes the signature and key"
+                )),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let [signature, pubkey] = pushes[..] else {
+            bail!("a p2pkh script sig has 2 pushes, not {}", pushes.len());
+        };
+        let pubkey = PublicKey::from_slice(pubkey)?;
+        ensure!(
+            ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()) == *script,
+            "the script sig key isn't the one {} pays to",
+            script
+        );
+        let signature = ecdsa::Signature::from_slice(signature)?;
+        let sighash =
+            sighashes.legacy_signature_hash(index, script, signature.sighash_type.to_u32())?;
+        SECP.verify_ecdsa(
+            &Message::from_digest(sighash.to_byte_array()),
+            &signature.signature,
+            &pubkey.inner,
+        )?;
+    } else {
+        bail!("can't check a spend of {}", script);
+    }
+
+    Ok(())
+}
+
+//coordinator side of the round
+#[derive(Debug, Clone)]
+pub struct FundingRound {
+    proposal: Psbt,
+    pool_script: ScriptBuf,
+    deposits: Vec<Amount>,
+    fee_rate: u64,
+    contributions: Vec<Option<Psbt>>,
+    unsigned: Option<Psbt>,
+    signed: Vec<Option<Psbt>>,
+}
+
+impl FundingRound {
+    pub fn new(pool: &PaymentPool, fee_rate: u64) -> Result<Self> {
+        let pool_output = TxOut {
+            value: pool.funding_amount(),
+            script_pubkey: pool.funding_address()?.script_pubkey(),
+        };
+        let deposits: Vec<Amount> = pool.members().iter().map(|member| member.deposit).collect();
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![pool_output.clone()],
+        };
+        let mut proposal = Psbt::from_unsigned_tx(tx)?;
+        set_global(&mut proposal, FEE_RATE_KEY, fee_rate);
+        set_global(&mut proposal, MEMBERS_KEY, deposits.len() as u64);
+
+        Ok(Self {
+            proposal,
+            pool_script: pool_output.script_pubkey,
+            fee_rate,
+            contributions: vec![None; deposits.len()],
+            signed: vec![None; deposits.len()],
+            deposits,
+            unsigned: None,
+        })
+    }
+
+    //pick a round up again from its published proposal, e.g. when every step runs in its own process
+    pub fn from_proposal(pool: &PaymentPool, proposal: &Psbt) -> Result<Self> {
+        let round = Self::new(pool, Proposal::read(proposal)?.fee_rate)?;
+        ensure!(
+            round.proposal == *proposal,
+            "the proposal isn't for this pool"
+        );
+        Ok(round)
+    }
+
+    //what the coordinator publishes to start the round
+    pub fn proposal(&self) -> &Psbt {
+        &self.proposal
+    }
+
+    //Check a member's contribution pays their deposit and fee share, returns the member. Only
+    //one contribution per member and none once the unsigned funding psbt is out
+    pub fn add_contribution(&mut self, contribution: Psbt) -> Result<usize> {
+        ensure!(
+            self.unsigned.is_none(),
+            "the funding psbt is already out for signing"
+        );
+        let member = psbt_member(&contribution)?;
+        ensure!(
+            member < self.deposits.len(),
+            "member {} isn't in a {} member round",
+            member,
+            self.deposits.len()
+        );
+        ensure!(
+            self.contributions[member].is_none(),
+            "member {} already contributed",
+            member
+        );
+
+        let tx = &contribution.unsigned_tx;
+        ensure!(!tx.input.is_empty(), "member {} added no inputs", member);
+
+        let mut prevouts = Vec::new();
+        for (input, psbt_input) in tx.input.iter().zip(&contribution.inputs) {
+            let prevout = psbt_input.witness_utxo.as_ref().ok_or_else(|| {
+                anyhow!(
+                    "member {}'s input {} has no witness utxo",
+                    member,
+                    input.previous_output
+                )
+            })?;
+            let taken = self
+                .contributed_outpoints()
+                .any(|outpoint| outpoint == input.previous_output);
+            ensure!(
+                !taken,
+                "member {}'s input {} is already in the round",
+                member,
+                input.previous_output
+            );
+            prevouts.push(prevout);
+        }
+        for output in &tx.output {
+            ensure!(
+                output.value >= output.script_pubkey.minimal_non_dust(),
+                "member {}'s change output is dust",
+                member
+            );
+        }
+
+        let change: Vec<&TxOut> = tx.output.iter().collect();
+        let weight =
+            contribution_weight(&prevouts, &change, self.deposits.len(), &self.pool_script)?;
+        let owed = self.deposits[member] + fee_for(weight, self.fee_rate);
+        let input_value: Amount = prevouts.iter().map(|prevout| prevout.value).sum();
+        let output_value: Amount = tx.output.iter().map(|output| output.value).sum();
+        ensure!(
+            input_value >= output_value + owed,
+            "member {} puts in {} sat but owes {} sat with their change of {} sat",
+            member,
+            input_value.to_sat(),
+            owed.to_sat(),
+            output_value.to_sat()
+        );
+
+        info!(
+            "member {} contributed {} inputs, {} sat toward the pool and fee \n",
+            member,
+            tx.input.len(),
+            (input_value - output_value).to_sat()
+        );
+        self.contributions[member] = Some(contribution);
+        Ok(member)
+    }
+
+    fn contributed_outpoints(&self) -> impl Iterator<Item = OutPoint> + '_ {
+        self.contributions.iter().flatten().flat_map(|psbt| {
+            psbt.unsigned_tx
+                .input
+                .iter()
+                .map(|input| input.previous_output)
+        })
+    }
+
+    pub fn missing_contributions(&self) -> Vec<usize> {
+        missing(&self.contributions)
+    }
+
+    //The funding psbt every member signs: the pool output first, then every member's inputs and
+    //change in member order. Needs every contribution, after this no contribution can change
+    pub fn unsigned_psbt(&mut self) -> Result<Psbt> {
+        if let Some(unsigned) = &self.unsigned {
+            return Ok(unsigned.clone());
+        }
+        let missing = self.missing_contributions();
+        ensure!(
+            missing.is_empty(),
+            "still waiting for contributions from members {:?}",
+            missing
+        );
+
+        let mut tx = self.proposal.unsigned_tx.clone();
+        let mut inputs = Vec::new();
+        let mut outputs = vec![Output::default()];
+        for contribution in self.contributions.iter().flatten() {
+            tx.input
+                .extend(contribution.unsigned_tx.input.iter().cloned());
+            tx.output
+                .extend(contribution.unsigned_tx.output.iter().cloned());
+            inputs.extend(contribution.inputs.iter().map(|input| Input {
+                witness_utxo: input.witness_utxo.clone(),
+                ..Default::default()
+            }));
+            outputs.extend(contribution.outputs.iter().map(|_| Output::default()));
+        }
+
+        let mut unsigned = Psbt::from_unsigned_tx(tx)?;
+        unsigned.inputs = inputs;
+        unsigned.outputs = outputs;
+        set_global(&mut unsigned, FEE_RATE_KEY, self.fee_rate);
+        set_global(&mut unsigned, MEMBERS_KEY, self.deposits.len() as u64);
+
+        self.unsigned = Some(unsigned.clone());
+        Ok(unsigned)
+    }
+
+    //indexes of member's inputs in the funding tx
+    fn member_inputs(&self, member: usize) -> std::ops::Range<usize> {
+        let input_count =
+            |c: &Option<Psbt>| c.as_ref().map_or(0, |psbt| psbt.unsigned_tx.input.len());
+        let start: usize = self.contributions[..member].iter().map(input_count).sum();
+        start..start + input_count(&self.contributions[member])
+    }
+
+    //A member's signed funding psbt, it has to be the unsigned one with every input of the
+    //contribution registered for the member final and signed for the prevout it spends. Signing
+    //someone else's inputs takes their keys, so the member index in the psbt can't be faked
+    pub fn add_signature(&mut self, signed: Psbt) -> Result<usize> {
+        let unsigned = self
+            .unsigned
+            .as_ref()
+            .ok_or_else(|| anyhow!("the funding psbt isn't out for signing yet"))?;
+        let member = psbt_member(&signed)?;
+        ensure!(
+            member < self.deposits.len(),
+            "member {} isn't in a {} member round",
+            member,
+            self.deposits.len()
+        );
+        ensure!(
+            signed.unsigned_tx == unsigned.unsigned_tx,
+            "member {} signed a different funding tx",
+            member
+        );
+        let prevouts = unsigned
+            .inputs
+            .iter()
+            .map(|input| {
+                input
+                    .witness_utxo
+                    .clone()
+                    .expect("every contribution input has one")
+            })
+            .collect::<Vec<_>>();
+        let mut tx = signed.unsigned_tx.clone();
+        for index in self.member_inputs(member) {
+            let input = &signed.inputs[index];
+            let outpoint = tx.input[index].previous_output;
+            ensure!(
+                is_finalized(input),
+                "member {} didn't sign their input {}",
+                member,
+                outpoint
+            );
+
+            tx.input[index].witness = input.final_script_witness.clone().unwrap_or_default();
+            tx.input[index].script_sig = input.final_script_sig.clone().unwrap_or_default();
+            verify_signed_input(&tx, index, &prevouts).with_context(|| {
+                format!("member {}'s signature for {} is invalid", member, outpoint)
+            })?;
+        }
+
+        info!("member {} signed the funding tx \n", member);
+        self.signed[member] = Some(signed);
+        Ok(member)
+    }
+
+    pub fn missing_signatures(&self) -> Vec<usize> {
+        missing(&self.signed)
+    }
+
+    //the funding tx, once every member signed. Only each member's own inputs are taken from
+    //their signed psbt
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut funding = self
+            .unsigned
+            .clone()
+            .ok_or_else(|| anyhow!("the funding psbt isn't out for signing yet"))?;
+        let missing = self.missing_signatures();
+        ensure!(
+            missing.is_empty(),
+            "still waiting for signatures from members {:?}",
+            missing
+        );
+
+        for (member, signed) in self.signed.iter().enumerate() {
+            let signed = signed.as_ref().expect("checked above");
+            for index in self.member_inputs(member) {
+                funding.inputs[index].final_script_witness =
+                    signed.inputs[index].final_script_witness.clone();
+                funding.inputs[index].final_script_sig =
+                    signed.inputs[index].final_script_sig.clone();
+            }
+        }
+
+        let fee = funding.fee()?;
+        let tx = funding.extract_tx()?;
+        info!(
+            "funding tx {} pays {} sat for {} vB \n",
+            tx.compute_txid(),
+            fee.to_sat(),
+            tx.vsize()
+        );
+        Ok(tx)
+    }
+}
+
+fn missing(psbts: &[Option<Psbt>]) -> Vec<usize> {
+    psbts
+        .iter()
+        .enumerate()
+        .filter(|(_, psbt)| psbt.is_none())
+        .map(|(member, _)| member)
+        .collect()
+}
+
+//The files of a round exchanged through a shared directory, each one a base64 psbt like
+//bitcoin-cli uses
+pub enum RoundFile {
+    Proposal,
+    Contribution(usize),
+    Unsigned,
+    Signed(usize),
+}
+
+impl RoundFile {
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        match self {
+            RoundFile::Proposal => dir.join("proposal.psbt"),
+            RoundFile::Contribution(member) => dir.join(format!("contribution_{}.psbt", member)),
+            RoundFile::Unsigned => dir.join("unsigned.psbt"),
+            RoundFile::Signed(member) => dir.join(format!("signed_{}.psbt", member)),
+        }
+    }
+}
+
+pub fn write_psbt(path: &Path, psbt: &Psbt) -> Result<()> {
+    fs::write(path, psbt_to_base64(psbt))
+        .with_context(|| format!("failed to write psbt {}", path.display()))
+}
+
+pub fn read_psbt(path: &Path) -> Result<Psbt> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read psbt {}", path.display()))?;
+    psbt_from_base64(&contents).with_context(|| format!("failed to parse psbt {}", path.display()))
+}
+
+//Socket transport, one base64 psbt per line: the coordinator sends the proposal to every member
+//that connects and reads back their contribution, once everyone is in it sends each of them the
+//unsigned funding psbt and reads back their signature. A bad message gets an error line back
+fn send_line(stream: &mut TcpStream, line: &str) -> Result<()> {
+    stream.write_all(line.as_bytes())?;
+    stream.write_all(b"\n")?;
+    Ok(stream.flush()?)
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    ensure!(reader.read_line(&mut line)? > 0, "connection closed");
+    let line = line.trim().to_string();
+    if let Some(error) = line.strip_prefix("error: ") {
+        bail!("{}", error);
+    }
+    Ok(line)
+}
+
+//Run the round over the sockets members connect to listener on. Every member has timeout to
+//answer each message and the whole round has timeout to get every contribution, so a member that
+//left or was rejected (they can reconnect and try again) can't stall the round forever. When the
+//round fails the members still connected get the error
+pub fn coordinate_round(
+    listener: &TcpListener,
+    round: &mut FundingRound,
+    timeout: Duration,
+) -> Result<Transaction> {
+    let mut connections: Vec<Option<BufReader<TcpStream>>> =
+        (0..round.deposits.len()).map(|_| None).collect();
+
+    let funding_tx = run_round(listener, round, timeout, &mut connections);
+    if let Err(e) = &funding_tx {
+        for reader in connections.iter_mut().flatten() {
+            let _ = send_line(
+                reader.get_mut(),
+                &format!("error: funding round aborted: {:#}", e),
+            );
+        }
+    }
+    funding_tx
+}
+
+fn run_round(
+    listener: &TcpListener,
+    round: &mut FundingRound,
+    timeout: Duration,
+    connections: &mut [Option<BufReader<TcpStream>>],
+) -> Result<Transaction> {
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true)?;
+
+    while !round.missing_contributions().is_empty() {
+        //checked on every connection too, members that keep connecting can't hold the round open
+        let time_left = deadline.saturating_duration_since(Instant::now());
+        ensure!(
+            !time_left.is_zero(),
+            "members {:?} didn't contribute within {:?}",
+            round.missing_contributions(),
+            timeout
+        );
+
+        let (mut stream, peer) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL.min(time_left));
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        //some platforms hand out the accepted stream non blocking like the listener. A contribution
+        //only gets what is left of the round's timeout
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(time_left))?;
+        stream.set_write_timeout(Some(time_left))?;
+
+        send_line(&mut stream, &psbt_to_base64(round.proposal()))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let contribution = read_line(&mut reader)
+            .and_then(|line| round.add_contribution(psbt_from_base64(&line)?));
+        match contribution {
+            Ok(member) => connections[member] = Some(reader),
+            Err(e) => {
+                warn!("contribution from {} rejected: {:#} \n", peer, e);
+                let _ = send_line(&mut stream, &format!("error: {:#}", e));
+            }
+        }
+    }
+
+    let unsigned = psbt_to_base64(&round.unsigned_psbt()?);
+    for (member, reader) in connections.iter_mut().enumerate() {
+        let reader = reader.as_mut().expect("every member contributed");
+        send_line(reader.get_mut(), &unsigned)?;
+        let signed = read_line(reader)
+            .and_then(|line| round.add_signature(psbt_from_base64(&line)?))
+            .with_context(|| format!("member {} didn't sign the funding tx", member))?;
+        ensure!(
+            signed == member,
+            "member {} sent a signature for member {}",
+            member,
+            signed
+        );
+    }
+
+    round.finalize()
+}
+
+//Run the whole socket round in this process, with join_member(member) running member's side on
+//its own thread (e.g. join_round with their wallet). When a member fails the error names them
+pub fn local_round(
+    listener: &TcpListener,
+    round: &mut FundingRound,
+    timeout: Duration,
+    join_member: impl Fn(usize) -> Result<()> + Sync,
+) -> Result<Transaction> {
+    let join_member = &join_member;
+    thread::scope(|scope| {
+        let members: Vec<_> = (0..round.deposits.len())
+            .map(|member| scope.spawn(move || join_member(member)))
+            .collect();
+
+        let funding_tx = coordinate_round(listener, round, timeout);
+
+        //a failed round closes every connection so the member threads all finish
+        let mut member_errors = Vec::new();
+        for (member, handle) in members.into_iter().enumerate() {
+            match handle.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => member_errors.push(format!("member {}: {:#}", member, e)),
+                Err(_) => member_errors.push(format!("member {} panicked", member)),
+            }
+        }
+        if member_errors.is_empty() {
+            return funding_tx;
+        }
+        let member_errors = member_errors.join(", ");
+        match funding_tx {
+            Ok(_) => bail!("funding round members failed: {}", member_errors),
+            Err(e) => Err(e.context(format!("members failed: {}", member_errors))),
+        }
+    })
+}
+
+//Member side of the socket round, contribute answers the proposal and sign gets the unsigned
+//funding psbt along with the member's own contribution
+pub fn join_round(
+    addr: impl ToSocketAddrs,
+    contribute: impl FnOnce(&Psbt) -> Result<Psbt>,
+    sign: impl FnOnce(&Psbt, &Psbt) -> Result<Psbt>,
+) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let proposal = psbt_from_base64(&read_line(&mut reader)?)?;
+    let contribution = contribute(&proposal)?;
+    send_line(&mut stream, &psbt_to_base64(&contribution))?;
+
+    let unsigned = psbt_from_base64(&read_line(&mut reader)?)?;
+    send_line(
+        &mut stream,
+        &psbt_to_base64(&sign(&unsigned, &contribution)?),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        key::{Keypair, TapTweak},
+        secp256k1::SecretKey,
+        EcdsaSighashType, Network, TapSighashType, Txid, Witness,
+    };
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::test_utils::{test_address, test_pool};
+
+    //a member's wallet, member 1 has a p2wpkh utxo and the others a p2tr one
+    struct Wallet {
+        keypair: Keypair,
+        address: Address,
+        utxo: Utxo,
+    }
+
+    impl Wallet {
+        fn new(member: usize) -> Self {
+            let keypair = Keypair::new(&SECP, &mut thread_rng());
+            let address = if member == 1 {
+                Address::p2wpkh(&CompressedPublicKey(keypair.public_key()), Network::Regtest)
+            } else {
+                Address::p2tr(&SECP, keypair.x_only_public_key().0, None, Network::Regtest)
+            };
+            let utxo = Utxo {
+                outpoint: OutPoint::new(Txid::from_byte_array([member as u8 + 1; 32]), 0),
+                txout: TxOut {
+                    value: Amount::from_sat(100000),
+                    script_pubkey: address.script_pubkey(),
+                },
+                confirmations: 1,
+            };
+            Self {
+                keypair,
+                address,
+                utxo,
+            }
+        }
+
+        fn contribute(&self, proposal: &Psbt, member: usize, pool: &PaymentPool) -> Psbt {
+            let deposit = pool.members()[member].deposit;
+            contribute(
+                proposal,
+                member,
+                deposit,
+                vec![self.utxo.clone()],
+                &self.address,
+            )
+            .unwrap()
+        }
+
+        //the witness signing input index of unsigned
+        fn witness(&self, unsigned: &Psbt, index: usize) -> Witness {
+            let prevouts: Vec<TxOut> = unsigned
+                .inputs
+                .iter()
+                .map(|input| input.witness_utxo.clone().unwrap())
+                .collect();
+            let mut sighashes = SighashCache::new(&unsigned.unsigned_tx);
+
+            if self.address.script_pubkey().is_p2wpkh() {
+                let sighash = sighashes
+                    .p2wpkh_signature_hash(
+                        index,
+                        &prevouts[index].script_pubkey,
+                        prevouts[index].value,
+                        EcdsaSighashType::All,
+                    )
+                    .unwrap();
+                let signature = ecdsa::Signature::sighash_all(SECP.sign_ecdsa(
+                    &Message::from_digest(sighash.to_byte_array()),
+                    &SecretKey::from_keypair(&self.keypair),
+                ));
+                Witness::p2wpkh(&signature, &self.keypair.public_key())
+            } else {
+                let sighash = sighashes
+                    .taproot_key_spend_signature_hash(
+                        index,
+                        &Prevouts::All(&prevouts),
+                        TapSighashType::Default,
+                    )
+                    .unwrap();
+                let tweaked = self.keypair.tap_tweak(&SECP, None).to_keypair();
+                let signature = taproot::Signature {
+                    signature: SECP
+                        .sign_schnorr(&Message::from_digest(sighash.to_byte_array()), &tweaked),
+                    sighash_type: TapSighashType::Default,
+                };
+                Witness::p2tr_key_spend(&signature)
+            }
+        }
+
+        //unsigned with this wallet's inputs signed, sent as member
+        fn sign(&self, unsigned: &Psbt, member: usize) -> Psbt {
+            let mut signed = unsigned.clone();
+            for index in 0..unsigned.inputs.len() {
+                if unsigned.unsigned_tx.input[index].previous_output == self.utxo.outpoint {
+                    signed.inputs[index].final_script_witness = Some(self.witness(unsigned, index));
+                }
+            }
+            set_global(&mut signed, MEMBER_KEY, member as u64);
+            signed
+        }
+    }
+
+    fn three_users() -> PoolParams {
+        PoolParams {
+            users: 3,
+            ..Default::default()
+        }
+    }
+
+    //a round with every contribution in and the unsigned funding psbt out
+    fn signing_round(pool: &PaymentPool, wallets: &[Wallet]) -> (FundingRound, Psbt) {
+        let mut round = FundingRound::new(pool, 5000).unwrap();
+        for (member, wallet) in wallets.iter().enumerate() {
+            let contribution = wallet.contribute(round.proposal(), member, pool);
+            round.add_contribution(contribution).unwrap();
+        }
+        let unsigned = round.unsigned_psbt().unwrap();
+        (round, unsigned)
+    }
+
+    #[test]
+    fn round_takes_every_members_signature() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let (mut round, unsigned) = signing_round(&pool, &wallets);
+
+        for (member, wallet) in wallets.iter().enumerate() {
+            assert_eq!(
+                round.add_signature(wallet.sign(&unsigned, member)).unwrap(),
+                member
+            );
+        }
+
+        let funding_tx = round.finalize().unwrap();
+        assert_eq!(funding_tx.output[0].value, pool.funding_amount());
+        assert!(funding_tx
+            .input
+            .iter()
+            .all(|input| !input.witness.is_empty()));
+    }
+
+    #[test]
+    fn rejects_a_signature_that_doesnt_verify() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let (mut round, unsigned) = signing_round(&pool, &wallets);
+
+        //a witness the size of a signature, like a wallet that didn't really sign
+        let mut made_up = unsigned.clone();
+        made_up.inputs[0].final_script_witness = Some(Witness::from_slice(&[[1u8; 64]]));
+        set_global(&mut made_up, MEMBER_KEY, 0);
+        assert!(round.add_signature(made_up).is_err());
+
+        //member 1's p2wpkh signature for the wrong input
+        let mut moved = wallets[1].sign(&unsigned, 1);
+        moved.inputs[2].final_script_witness = moved.inputs[1].final_script_witness.take();
+        set_global(&mut moved, MEMBER_KEY, 2);
+        assert!(round.add_signature(moved).is_err());
+
+        assert_eq!(round.missing_signatures(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn rejects_a_member_signing_for_someone_else() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let (mut round, unsigned) = signing_round(&pool, &wallets);
+
+        //member 2 claims to be member 0 and signs member 0's input with their own key
+        let mut forged = unsigned.clone();
+        forged.inputs[0].final_script_witness = Some(wallets[2].witness(&unsigned, 0));
+        set_global(&mut forged, MEMBER_KEY, 0);
+        assert!(round.add_signature(forged).is_err());
+
+        //and their own psbt doesn't sign member 0's input
+        assert!(round.add_signature(wallets[2].sign(&unsigned, 0)).is_err());
+        assert_eq!(round.missing_signatures(), [0, 1, 2]);
+    }
+
+    //the same three users in both pool layouts
+    fn both_modes() -> [PoolParams; 2] {
+        [
+            three_users(),
+            PoolParams {
+                mode: PoolMode::SplitTree,
+                ..three_users()
+            },
+        ]
+    }
+
+    #[test]
+    fn members_accept_an_honest_funding_psbt() {
+        for params in both_modes() {
+            let pool = test_pool(params, &[20000, 30000, 40000]);
+            let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+            let (_, unsigned) = signing_round(&pool, &wallets);
+
+            for (member, own) in pool.members().iter().enumerate() {
+                verify_funding_psbt(&pool, &params, member, own, &unsigned).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_pool() {
+        for params in both_modes() {
+            let pool = test_pool(params, &[20000; 3]);
+            let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+            let (_, unsigned) = signing_round(&pool, &wallets);
+
+            //same deposits, but member 1 is paid somewhere else
+            let mut members = pool.members().to_vec();
+            members[1].withdraw_address = test_address();
+            let tampered =
+                PaymentPool::new(params, pool.network(), members, pool.anchor_addr().clone())
+                    .unwrap();
+            let tampered_output = TxOut {
+                value: tampered.funding_amount(),
+                script_pubkey: tampered.funding_address().unwrap().script_pubkey(),
+            };
+
+            //a psbt paying the tampered pool doesn't pay the one member 0 rebuilds
+            let mut paying_tampered = unsigned.clone();
+            paying_tampered.unsigned_tx.output[0] = tampered_output.clone();
+            let own = &pool.members()[0];
+            assert!(matches!(
+                verify_funding_psbt(&pool, &params, 0, own, &paying_tampered),
+                Err(FundingVerifyError::PoolOutputMissing { .. })
+            ));
+
+            //and member 0's exits, which do pay them, can't spend the tampered pool's output
+            let own_script = own.withdraw_address.script_pubkey();
+            let exits = match params.mode {
+                PoolMode::Exact => verify_exits(&pool, 0, &own_script, &tampered_output),
+                PoolMode::SplitTree => verify_tree_path(&pool, 0, &own_script, &tampered_output),
+            };
+            assert!(
+                matches!(exits, Err(FundingVerifyError::ExitInvalid { .. })),
+                "{:?}",
+                exits
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_params_the_member_didnt_agree_to() {
+        let agreed = three_users();
+        //the coordinator's pool file takes half the deposit as the exit fee
+        let greedy = PoolParams {
+            fee_amount: Amount::from_sat(10000),
+            ..agreed
+        };
+        let pool = test_pool(greedy, &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let (_, unsigned) = signing_round(&pool, &wallets);
+
+        assert_eq!(
+            verify_funding_psbt(&pool, &agreed, 0, &pool.members()[0], &unsigned),
+            Err(FundingVerifyError::ParamsMismatch {
+                agreed,
+                found: greedy
+            })
+        );
+    }
+
+    #[test]
+    fn socket_round_funds_the_pool() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let mut round = FundingRound::new(&pool, 5000).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let funding_tx = thread::scope(|scope| {
+            let members: Vec<_> = wallets
+                .iter()
+                .enumerate()
+                .map(|(member, wallet)| {
+                    let pool = &pool;
+                    scope.spawn(move || {
+                        join_round(
+                            addr,
+                            |proposal| Ok(wallet.contribute(proposal, member, pool)),
+                            |unsigned, _| Ok(wallet.sign(unsigned, member)),
+                        )
+                    })
+                })
+                .collect();
+
+            let funding_tx = coordinate_round(&listener, &mut round, Duration::from_secs(10));
+            for handle in members {
+                handle.join().unwrap().unwrap();
+            }
+            funding_tx
+        })
+        .unwrap();
+
+        assert_eq!(funding_tx.input.len(), 3);
+        assert_eq!(funding_tx.output[0].value, pool.funding_amount());
+    }
+
+    #[test]
+    fn socket_round_gives_up_on_a_member_who_never_contributes() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..2).map(Wallet::new).collect();
+        let mut round = FundingRound::new(&pool, 5000).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            let members: Vec<_> = wallets
+                .iter()
+                .enumerate()
+                .map(|(member, wallet)| {
+                    let pool = &pool;
+                    scope.spawn(move || {
+                        join_round(
+                            addr,
+                            |proposal| Ok(wallet.contribute(proposal, member, pool)),
+                            |unsigned, _| Ok(wallet.sign(unsigned, member)),
+                        )
+                    })
+                })
+                .collect();
+
+            let error =
+                coordinate_round(&listener, &mut round, Duration::from_millis(500)).unwrap_err();
+            assert!(error.to_string().contains("members [2]"), "{:#}", error);
+
+            //the members who did contribute hear the round is off instead of waiting forever
+            for handle in members {
+                let error = handle.join().unwrap().unwrap_err();
+                assert!(error.to_string().contains("aborted"), "{:#}", error);
+            }
+        });
+    }
+
+    #[test]
+    fn local_round_names_the_member_that_failed() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let wallets: Vec<_> = (0..3).map(Wallet::new).collect();
+        let mut round = FundingRound::new(&pool, 5000).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let error = local_round(
+            &listener,
+            &mut round,
+            Duration::from_millis(500),
+            |member| {
+                ensure!(member != 2, "wallet is locked");
+                join_round(
+                    addr,
+                    |proposal| Ok(wallets[member].contribute(proposal, member, &pool)),
+                    |unsigned, _| Ok(wallets[member].sign(unsigned, member)),
+                )
+            },
+        )
+        .unwrap_err();
+
+        let error = format!("{:#}", error);
+        assert!(error.contains("member 2: wallet is locked"), "{}", error);
+        assert!(error.contains("members [2] didn't contribute"), "{}", error);
+    }
+
+    #[test]
+    fn socket_round_ends_on_time_while_a_member_is_silent() {
+        let pool = test_pool(three_users(), &[20000; 3]);
+        let mut round = FundingRound::new(&pool, 5000).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_secs(1);
+
+        thread::scope(|scope| {
+            //connects late in the round and never answers the proposal
+            scope.spawn(move || {
+                thread::sleep(Duration::from_millis(600));
+                let mut stream = TcpStream::connect(addr).unwrap();
+                let _ = std::io::copy(&mut stream, &mut std::io::sink());
+            });
+
+            let start = Instant::now();
+            let error = coordinate_round(&listener, &mut round, timeout).unwrap_err();
+            assert!(
+                error.to_string().contains("members [0, 1, 2]"),
+                "{:#}",
+                error
+            );
+            //the read only gets what is left of the round, not a whole timeout of its own
+            assert!(
+                start.elapsed() < Duration::from_millis(1400),
+                "{:?}",
+                start.elapsed()
+            );
+        });
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{ensure, Result};
+use std::{collections::HashSet, fmt::Write};
+
+use bitcoin::{Address, Amount, OutPoint, Txid};
+use clap::ValueEnum;
+use itertools::Itertools;
+
+use crate::{
+    chain::ChainBackend, config::PoolMode, members::MemberSet, payment_pool::PaymentPool,
+    pools::split_outputs, recover::PoolSpend,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+//one pool state, outpoint is only known for states taken on chain
+#[derive(Debug, Clone)]
+pub struct StateNode {
+    pub users: MemberSet,
+    pub amount: Amount,
+    pub address: Address,
+    pub outpoint: Option<OutPoint>,
+}
+
+//one way out of a pool state. to is None when the spend closes the pool, payouts are what each
+//member gets paid by it (nothing for a cooperative spend, it can pay anywhere)
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub from: MemberSet,
+    pub to: Option<MemberSet>,
+    pub spend: TransitionKind,
+    pub payouts: Vec<(usize, Amount)>,
+    pub txid: Option<Txid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionKind {
+    Exit { member: usize },
+    FinalExit,
+    Split,
+    Cooperative,
+}
+
+//the pool's states and the exits between them, ready to render as DOT or Mermaid
+#[derive(Debug, Clone, Default)]
+pub struct PoolGraph {
+    pub states: Vec<StateNode>,
+    pub transitions: Vec<Transition>,
+}
+
+impl PaymentPool {
+    //every state reachable from the funding state in at most max_exits exits (all of them for
+    //None) and every exit between them. Splits aren't drawn, every state above 2 members has one.
+    //The funding address commits to every state, so this derives the whole pool either way
+    pub fn state_graph(&self, max_exits: Option<usize>) -> Result<PoolGraph> {
+        ensure!(
+            self.params().mode == PoolMode::Exact,
+            "only exact pools can be drawn, a split tree has no exits to draw"
+        );
+
+        let mut graph = PoolGraph::default();
+        let mut level = vec![MemberSet::full(self.params().users)];
+        let mut exits = 0;
+
+        while !level.is_empty() {
+            let mut next_level = HashSet::new();
+            for users in level {
+                graph.states.push(self.state_node(&users, None)?);
+
+                if max_exits.is_some_and(|max_exits| exits >= max_exits) {
+                    continue;
+                }
+                if users.len() == 2 {
+                    graph.transitions.push(self.transition(
+                        &users,
+                        TransitionKind::FinalExit,
+                        None,
+                    ));
+                    continue;
+                }
+                for member in users.iter() {
+                    graph.transitions.push(self.transition(
+                        &users,
+                        TransitionKind::Exit { member },
+                        None,
+                    ));
+                    next_level.insert(users.without(member));
+                }
+            }
+
+            //same order every run
+            level = next_level
+                .into_iter()
+                .sorted_by_key(|users| users.to_vec())
+                .collect();
+            exits += 1;
+        }
+
+        Ok(graph)
+    }
+
+    //only the states and spends the pool has actually gone through on chain, following its
+    //spends from the funding outpoint like recover does
+    pub fn path_graph(
+        &self,
+        chain: &dyn ChainBackend,
+        funding_outpoint: OutPoint,
+    ) -> Result<PoolGraph> {
+        self.check_funding_output(chain, funding_outpoint)?;
+
+        let mut graph = PoolGraph::default();
+        let mut state = self.funding_state(funding_outpoint);
+        loop {
+            graph
+                .states
+                .push(self.state_node(&state.users, Some(state.outpoint))?);
+
+            let Some((spend, next_state)) =
+                self.follow_spend(chain, &state.users, state.outpoint)?
+            else {
+                break;
+            };
+
+            let (kind, txid) = match spend {
+                PoolSpend::Exit { member, txid } => (TransitionKind::Exit { member }, txid),
+                PoolSpend::FinalExit { txid } => (TransitionKind::FinalExit, txid),
+                PoolSpend::Split { txid } => (TransitionKind::Split, txid),
+                PoolSpend::Cooperative { txid } => (TransitionKind::Cooperative, txid),
+            };
+            graph
+                .transitions
+                .push(self.transition(&state.users, kind, Some(txid)));
+
+            let Some(next_state) = next_state else {
+                break;
+            };
+            state = next_state;
+        }
+
+        Ok(graph)
+    }
+
+    fn state_node(&self, users: &MemberSet, outpoint: Option<OutPoint>) -> Result<StateNode> {
+        Ok(StateNode {
+            users: users.clone(),
+            amount: self.pool_amount(users),
+            address: Address::p2tr_tweaked(self.spend_info(users)?.output_key(), self.network()),
+            outpoint,
+        })
+    }
+
+    //the payouts are the same as the outputs of the leaf the spend takes
+    fn transition(
+        &self,
+        users: &MemberSet,
+        spend: TransitionKind,
+        txid: Option<Txid>,
+    ) -> Transition {
+        let members = self.members();
+        let exit_fee = self.params().exit_fee();
+
+        let (to, payouts) = match spend {
+            TransitionKind::Exit { member } => (
+                Some(users.without(member)),
+                vec![(member, members[member].deposit - exit_fee)],
+            ),
+            TransitionKind::FinalExit => {
+                let (first, second) = users.iter().collect_tuple().unwrap();
+                (
+                    None,
+                    vec![
+                        (first, members[first].deposit),
+                        (second, members[second].deposit - exit_fee),
+                    ],
+                )
+            }
+            TransitionKind::Split => {
+                let outputs = split_outputs(self.params(), members, self.anchor_addr(), users);
+                (
+                    None,
+                    users
+                        .iter()
+                        .zip(outputs)
+                        .map(|(member, output)| (member, output.value))
+                        .collect(),
+                )
+            }
+            TransitionKind::Cooperative => (None, Vec::new()),
+        };
+
+        Transition {
+            from: users.clone(),
+            to,
+            spend,
+            payouts,
+            txid,
+        }
+    }
+}
+
+impl PoolGraph {
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Mermaid => self.to_mermaid(),
+        }
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph pool {\n    rankdir=TB;\n    node [shape=box];\n");
+
+        for state in &self.states {
+            let style = if state.outpoint.is_some() {
+                ", style=bold"
+            } else {
+                ""
+            };
+            writeln!(
+                dot,
+                "    {} [label=\"{}\"{}];",
+                node_id(&state.users),
+                state_lines(state).join("\\n"),
+                style
+            )
+            .unwrap();
+        }
+
+        for transition in &self.transitions {
+            let to = match &transition.to {
+                Some(users) => node_id(users),
+                None => {
+                    let closed = closed_id(&transition.from);
+                    writeln!(dot, "    {} [label=\"closed\", shape=plaintext];", closed).unwrap();
+                    closed
+                }
+            };
+            writeln!(
+                dot,
+                "    {} -> {} [label=\"{}\"];",
+                node_id(&transition.from),
+                to,
+                transition_lines(transition).join("\\n")
+            )
+            .unwrap();
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = String::from("flowchart TD\n");
+
+        for state in &self.states {
+            writeln!(
+                mermaid,
+                "    {}[\"{}\"]",
+                node_id(&state.users),
+                state_lines(state).join("<br/>")
+            )
+            .unwrap();
+        }
+
+        for transition in &self.transitions {
+            let to = match &transition.to {
+                Some(users) => node_id(users),
+                None => {
+                    let closed = closed_id(&transition.from);
+                    writeln!(mermaid, "    {}((closed))", closed).unwrap();
+                    closed
+                }
+            };
+            writeln!(
+                mermaid,
+                "    {} -->|\"{}\"| {}",
+                node_id(&transition.from),
+                transition_lines(transition).join("<br/>"),
+                to
+            )
+            .unwrap();
+        }
+
+        for state in self.states.iter().filter(|state| state.outpoint.is_some()) {
+            writeln!(
+                mermaid,
+                "    style {} stroke-width:3px",
+                node_id(&state.users)
+            )
+            .unwrap();
+        }
+
+        mermaid
+    }
+}
+
+//s_0_2_3 for the state holding members 0, 2 and 3
+fn node_id(users: &MemberSet) -> String {
+    format!("s_{}", users.iter().join("_"))
+}
+
+//every closing spend gets its own end node so the graph doesn't pull every state into one point
+fn closed_id(users: &MemberSet) -> String {
+    format!("closed_{}", users.iter().join("_"))
+}
+
+fn state_lines(state: &StateNode) -> Vec<String> {
+    let mut lines = vec![
+        format!("{:?}", state.users),
+        format!("{} sat", state.amount.to_sat()),
+        state.address.to_string(),
+    ];
+    lines.extend(state.outpoint.map(|outpoint| outpoint.to_string()));
+    lines
+}
+
+fn transition_lines(transition: &Transition) -> Vec<String> {
+    let mut lines = vec![match transition.spend {
+        TransitionKind::Exit { member } => format!("member {} exits", member),
+        TransitionKind::FinalExit => "final exit".to_string(),
+        TransitionKind::Split => "split".to_string(),
+        TransitionKind::Cooperative => "cooperative close".to_string(),
+    }];
+    lines.extend(
+        transition
+            .payouts
+            .iter()
+            .map(|(member, amount)| format!("{} sat to {}", amount.to_sat(), member)),
+    );
+    lines.extend(transition.txid.map(|txid| txid.to_string()));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PoolParams,
+        pools::process_pool_spend,
+        test_utils::{funded_chain, test_pool},
+    };
+
+    fn users(users: usize) -> PoolParams {
+        PoolParams {
+            users,
+            ..Default::default()
+        }
+    }
+
+    fn set(members: &[usize]) -> MemberSet {
+        members.iter().copied().collect()
+    }
+
+    #[test]
+    fn stops_after_max_exits() {
+        let pool = test_pool(users(4), &[20000; 4]);
+
+        let graph = pool.state_graph(Some(0)).unwrap();
+        assert_eq!(graph.states.len(), 1);
+        assert!(graph.transitions.is_empty());
+
+        //the full pool and the 4 states one exit away, only the full pool's exits are drawn
+        let graph = pool.state_graph(Some(1)).unwrap();
+        assert_eq!(graph.states.len(), 5);
+        assert!(graph.states[1..].iter().all(|state| state.users.len() == 3));
+        assert_eq!(graph.transitions.len(), 4);
+        assert!(graph
+            .transitions
+            .iter()
+            .all(|transition| transition.from == MemberSet::full(4)));
+
+        //without a limit it goes down to the final exits
+        let graph = pool.state_graph(None).unwrap();
+        assert_eq!(graph.states.len(), 1 + 4 + 6);
+        assert_eq!(graph.transitions.len(), 4 + 4 * 3 + 6);
+    }
+
+    #[test]
+    fn three_user_pool_has_every_state_and_exit() {
+        let pool = test_pool(users(3), &[11000, 25000, 15000]);
+        let members = pool.members();
+        let exit_fee = pool.params().exit_fee();
+        let graph = pool.state_graph(None).unwrap();
+
+        let states: Vec<_> = graph
+            .states
+            .iter()
+            .map(|state| state.users.clone())
+            .collect();
+        assert_eq!(
+            states,
+            [set(&[0, 1, 2]), set(&[0, 1]), set(&[0, 2]), set(&[1, 2])]
+        );
+        for state in &graph.states {
+            assert_eq!(state.amount, pool.pool_amount(&state.users));
+            assert!(state.outpoint.is_none());
+        }
+
+        assert_eq!(graph.transitions.len(), 6);
+        for (transition, member) in graph.transitions[..3].iter().zip(0..) {
+            assert_eq!(transition.spend, TransitionKind::Exit { member });
+            assert_eq!(transition.to, Some(set(&[0, 1, 2]).without(member)));
+            assert_eq!(
+                transition.payouts,
+                [(member, members[member].deposit - exit_fee)]
+            );
+        }
+
+        //the member who takes the exit pays the fee, the other one keeps their whole deposit
+        let final_exit = &graph.transitions[3];
+        assert_eq!(final_exit.spend, TransitionKind::FinalExit);
+        assert_eq!(final_exit.from, set(&[0, 1]));
+        assert_eq!(final_exit.to, None);
+        assert_eq!(
+            final_exit.payouts,
+            [(0, members[0].deposit), (1, members[1].deposit - exit_fee)]
+        );
+    }
+
+    #[test]
+    fn renders_node_ids_closed_nodes_and_payouts() {
+        let pool = test_pool(users(3), &[11000, 25000, 15000]);
+        let exit_fee = pool.params().exit_fee();
+        let graph = pool.state_graph(None).unwrap();
+        let payout = (pool.members()[0].deposit - exit_fee).to_sat();
+
+        //ids are plain identifiers both formats take without quoting
+        for state in &graph.states {
+            let id = node_id(&state.users);
+            assert!(id.starts_with("s_"), "{}", id);
+            assert!(id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+        }
+
+        let dot = graph.to_dot();
+        assert!(dot.starts_with("digraph pool {\n"), "{}", dot);
+        assert!(dot.ends_with("}\n"), "{}", dot);
+        assert!(dot.contains(&format!(
+            "    s_0_1_2 -> s_1_2 [label=\"member 0 exits\\n{} sat to 0\"];",
+            payout
+        )));
+        assert!(dot.contains("    closed_1_2 [label=\"closed\", shape=plaintext];"));
+        assert!(dot.contains("    s_1_2 -> closed_1_2 [label=\"final exit\\n"));
+        assert!(!dot.contains("style=bold"));
+
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.starts_with("flowchart TD\n"), "{}", mermaid);
+        assert!(mermaid.contains(&format!(
+            "    s_0_1_2 -->|\"member 0 exits<br/>{} sat to 0\"| s_1_2",
+            payout
+        )));
+        assert!(mermaid.contains("    closed_1_2((closed))"));
+        assert!(mermaid.contains("    s_1_2 -->|\"final exit<br/>"));
+        assert!(!mermaid.contains("style "));
+    }
+
+    #[test]
+    fn path_graph_marks_the_states_taken_on_chain() {
+        let pool = test_pool(users(4), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let full = MemberSet::full(4);
+        let txid =
+            process_pool_spend(&pool, &chain, &full, 1, funding_outpoint.txid, None).unwrap();
+        chain.mine(1);
+
+        let graph = pool.path_graph(&chain, funding_outpoint).unwrap();
+        assert_eq!(graph.states.len(), 2);
+        assert_eq!(graph.states[0].users, full);
+        assert_eq!(graph.states[0].outpoint, Some(funding_outpoint));
+        assert_eq!(graph.states[1].users, full.without(1));
+        assert_eq!(graph.states[1].outpoint, Some(OutPoint { txid, vout: 0 }));
+
+        assert_eq!(graph.transitions.len(), 1);
+        assert_eq!(
+            graph.transitions[0].spend,
+            TransitionKind::Exit { member: 1 }
+        );
+        assert_eq!(graph.transitions[0].txid, Some(txid));
+
+        let dot = graph.to_dot();
+        assert_eq!(dot.matches("style=bold").count(), 2);
+        assert!(dot.contains(&txid.to_string()));
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.contains("    style s_0_1_2_3 stroke-width:3px"));
+        assert!(mermaid.contains("    style s_0_2_3 stroke-width:3px"));
+    }
+}
@@ -0,0 +1,77 @@
+pub mod chain;
+pub mod config;
+pub mod cooperative;
+pub mod cpfp;
+pub mod ctv_scripts;
+pub mod export;
+pub mod funding;
+pub mod graph;
+pub mod members;
+pub mod payment_pool;
+pub mod pool_file;
+pub mod pools;
+pub mod recover;
+pub mod rpc_helper;
+pub mod split_tree;
+#[cfg(test)]
+mod test_utils;
+pub mod verify;
+pub mod watchtower;
+
+pub use chain::{ChainBackend, Esplora};
+pub use config::{FeeStrategy, NetworkConfig, PoolMode, PoolParams, PoolParamsError};
+pub use cooperative::CooperativeSpend;
+pub use ctv_scripts::{CtvTemplate, LeafSpend, TemplateHash};
+pub use members::{MemberSet, PoolMember};
+pub use payment_pool::PaymentPool;
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, Amount, Network, OutPoint, Txid};
+
+    use super::*;
+    use crate::test_utils::{test_address, test_anchor};
+
+    //a pool's whole life through the crate root, the way a service depending on the crate uses it
+    #[test]
+    fn pool_api_builds_every_exit_from_withdraw_addresses() {
+        let params = PoolParams {
+            users: 4,
+            ..Default::default()
+        };
+        let members: Vec<_> = (0..4)
+            .map(|_| PoolMember::new(test_address(), Amount::from_sat(20000)))
+            .collect();
+        let pool =
+            PaymentPool::new(params, Network::Regtest, members.clone(), test_anchor()).unwrap();
+
+        assert!(pool.funding_address().unwrap().script_pubkey().is_p2tr());
+        assert_eq!(pool.funding_amount(), Amount::from_sat(80000));
+
+        let funding_outpoint = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let exit_order = [2, 0, 3, 1];
+        let exits = pool
+            .exit_transactions(funding_outpoint, &exit_order, None)
+            .unwrap();
+        assert_eq!(exits.len(), 3);
+
+        //each exit is the one exit_tx builds for the state it spends and pays the member leaving
+        let mut remaining = MemberSet::full(4);
+        let mut previous_output = funding_outpoint;
+        for (exit, &member) in exits.iter().zip(&exit_order) {
+            let exit_tx = pool
+                .exit_tx(&remaining, member, previous_output, None)
+                .unwrap();
+            assert_eq!(&exit_tx, exit);
+            pool.verify_spend(exit, &remaining).unwrap();
+            assert!(exit
+                .output
+                .iter()
+                .any(|output| output.script_pubkey
+                    == members[member].withdraw_address.script_pubkey()));
+
+            remaining.remove(member);
+            previous_output = OutPoint::new(exit.compute_txid(), 0);
+        }
+    }
+}
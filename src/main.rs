@@ -1,29 +1,81 @@
-use anyhow::Result;
-use bitcoin::Address;
-use bitcoincore_rpc::RpcApi;
-use config::{NetworkConfig, AMOUNT_PER_USER, DUST_AMOUNT, FEE_AMOUNT, POOL_USERS};
-use ctv_scripts::create_pool_address;
-use pools::{
-    create_all_pools, create_entry_pool_withdraw_hashes, create_exit_pool, process_pool_spend,
+use anyhow::{bail, Result};
+use bitcoin::{
+    hashes::Hash,
+    secp256k1::{Keypair, Message, SecretKey},
+    sighash::TapSighash,
+    Address, OutPoint, Transaction, Txid,
 };
-use rpc_helper::{send_funding_transaction, simulate_psbt_signing};
-use std::{collections::HashMap, str::FromStr};
+use bitcoincore_rpc::{Client, RpcApi};
+use clap::Parser;
+use op_ctv_payment_pool::{
+    chain::ChainBackend,
+    config::PoolMode,
+    config::PoolParamsArgs,
+    ctv_scripts::SECP,
+    funding::{local_round, FundingRound},
+    payment_pool::ExitSigner,
+    pools::{
+        process_cooperative_close, process_pool_spend, process_pool_split, process_tree_split,
+    },
+    rpc_helper::{member_wallets, send_funding_transaction},
+    MemberSet, NetworkConfig, PaymentPool, PoolParams,
+};
+use rand::{seq::SliceRandom, thread_rng};
+use std::{collections::HashMap, net::TcpListener, path::Path, path::PathBuf, time::Duration};
 use tracing::info;
 
-mod config;
-mod ctv_scripts;
-mod pools;
-mod rpc_helper;
+mod commands;
+
+//without a subcommand the flags run the whole demo in one process
+#[derive(Parser)]
+#[command(
+    about = "ctv payment pool example",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<commands::Command>,
+    #[command(flatten)]
+    params: PoolParamsArgs,
+    /// Deposit for each user in sats, e.g. 11000,25000,15000. Defaults to --amount-per-user for everyone
+    #[arg(long, value_delimiter = ',')]
+    deposits: Option<Vec<u64>>,
+    /// Pool file to save the pool tree to, or to load it from if it already exists
+    #[arg(long)]
+    pool_file: Option<PathBuf>,
+    /// Order users leave the pool in, e.g. 3,0,2,1. Defaults to a random order
+    #[arg(long, value_delimiter = ',')]
+    exit_order: Option<Vec<usize>>,
+    /// Give every user a musig key and close the pool with one key path spend signed by everyone
+    /// instead of exiting one by one
+    #[arg(long)]
+    cooperative_close: bool,
+    /// Give every user an exit key, each exit then needs a signature from the user leaving
+    #[arg(long)]
+    signed_exits: bool,
+    /// Stop after this many users have exited and split the rest of the pool between the users
+    /// still in it with the timelocked split leaf
+    #[arg(long)]
+    split_after: Option<usize>,
+    /// Funding outpoint (txid:vout) of the pool in --pool-file. Finds where the pool is on chain,
+    /// e.g. after a run stopped midway through the exits, and exits the users still in it
+    #[arg(long, requires = "pool_file")]
+    recover: Option<OutPoint>,
+    /// Directory the funding round's psbt files are exchanged in
+    #[arg(long, default_value = "funding_round")]
+    funding_dir: PathBuf,
+    /// Run the funding round over a local socket (e.g. 127.0.0.1:18500) instead of psbt files
+    #[arg(long)]
+    funding_socket: Option<String>,
+}
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt().with_target(false).init();
 
-    if POOL_USERS < 3 {
-        panic!("Pool must have at least 3 users");
-    }
+    let cli = Cli::parse();
 
-    if AMOUNT_PER_USER <= FEE_AMOUNT + DUST_AMOUNT {
-        panic!("Amount per user must be more than the FEE_AMOUNT + DUST_AMOUNT const");
+    if let Some(command) = cli.command {
+        return commands::run(command);
     }
 
     let config = NetworkConfig::new();
@@ -33,99 +85,333 @@ fn main() -> Result<()> {
         .get_new_address(None, None)?
         .require_network(config.network)?;
 
-    #[cfg(feature = "regtest")]
-    if rpc.get_balance(None, None)? < (AMOUNT_PER_USER) * POOL_USERS.try_into()? {
-        let _ = rpc.generate_to_address(101, &mining_address);
+    //the secret keys only live for this run, they are never written to the pool file
+    let mut musig_seckeys: Vec<SecretKey> = Vec::new();
+    let mut exit_keypairs: Vec<Keypair> = Vec::new();
+
+    let pool = match cli.pool_file.as_deref().filter(|path| path.exists()) {
+        //reuse the pool from a previous run
+        Some(path) => {
+            if cli.cooperative_close || cli.signed_exits {
+                bail!("--cooperative-close and --signed-exits need a new pool, the members' secret keys aren't in the pool file");
+            }
+            commands::load_pool(&config, path)?
+        }
+        None => {
+            if cli.recover.is_some() {
+                bail!("--recover needs the pool file the pool was created with");
+            }
+            let params = PoolParams::load(&cli.params)?;
+            info!("Creating pool with {} users \n", params.users);
+
+            let withdraw_addresses = (0..params.users)
+                .map(|_| {
+                    Ok(rpc
+                        .get_new_address(None, None)?
+                        .require_network(config.network)?)
+                })
+                .collect::<Result<Vec<Address>>>()?;
+
+            if cli.cooperative_close {
+                musig_seckeys = (0..params.users)
+                    .map(|_| SecretKey::new(&mut thread_rng()))
+                    .collect();
+            }
+            if cli.signed_exits {
+                exit_keypairs = (0..params.users)
+                    .map(|_| Keypair::new(&SECP, &mut thread_rng()))
+                    .collect();
+            }
+            let musig_keys = cli.cooperative_close.then(|| {
+                musig_seckeys
+                    .iter()
+                    .map(|seckey| seckey.public_key(&SECP))
+                    .collect()
+            });
+            let exit_keys = cli.signed_exits.then(|| {
+                exit_keypairs
+                    .iter()
+                    .map(|keypair| keypair.x_only_public_key().0)
+                    .collect()
+            });
+
+            commands::build_pool(
+                &config,
+                params,
+                withdraw_addresses,
+                cli.deposits,
+                exit_keys,
+                musig_keys,
+            )?
+        }
+    };
+    let params = *pool.params();
+
+    //the tree has no per member exits to sign, recover or split on a timeout
+    if params.mode == PoolMode::SplitTree
+        && (cli.signed_exits || cli.split_after.is_some() || cli.recover.is_some())
+    {
+        bail!("--signed-exits, --split-after and --recover only work for exact pools");
+    }
+
+    if pool
+        .members()
+        .iter()
+        .any(|member| member.exit_key.is_some())
+        && !cli.signed_exits
+    {
+        bail!(
+            "the pool file has exit keys from the run that created it, create a new pool to exit"
+        );
     }
 
-    let anchor_addr = Address::from_str(config.fee_anchor_addr)?.require_network(config.network)?;
+    //every user signs their own exit, the demo just holds all their keys
+    let exit_signer = |member: usize, sighash: TapSighash| {
+        let message = Message::from_digest(sighash.to_byte_array());
+        Ok(SECP.sign_schnorr(&message, &exit_keypairs[member]))
+    };
+    let signer: Option<&ExitSigner> = if cli.signed_exits {
+        Some(&exit_signer)
+    } else {
+        None
+    };
 
-    info!("Creating pool with {} users \n", POOL_USERS);
+    //pick up a pool funded on an earlier run
+    if let Some(funding_outpoint) = cli.recover {
+        if cli.split_after.is_some() {
+            bail!("--recover only exits the users left in the pool, it can't split it");
+        }
 
-    let withdraw_addresses: Vec<Address> = (0..POOL_USERS)
-        .map(|_| {
-            rpc.get_new_address(None, None)
-                .unwrap()
-                .require_network(config.network)
-                .unwrap()
-        })
-        .collect();
+        let Some(state) = pool.recover(&rpc, funding_outpoint)?.state else {
+            info!("pool is already closed, nobody is left to exit \n");
+            return Ok(());
+        };
 
-    let init_wallets_txid = send_funding_transaction(&rpc, &config);
+        //users who already left are skipped in the given order
+        let given = cli.exit_order.map(|order| {
+            order
+                .into_iter()
+                .filter(|&user| state.users.contains(user))
+                .collect()
+        });
+        let exit_order = exit_order(given, &state.users)?;
+
+        info!("exit order: {:?} \n", exit_order);
+
+        let mut remaining = state.users;
+        exit_users(
+            &pool,
+            &rpc,
+            &mut remaining,
+            state.outpoint.txid,
+            &exit_order[..exit_order.len() - 1],
+            &mining_address,
+            signer,
+        )?;
+        return Ok(());
+    }
 
     #[cfg(feature = "regtest")]
-    let _ = rpc.generate_to_address(1, &mining_address);
-
-    ////////////////////////////////////////////////////////////////////////////
-    /////////////////////////////CREATE LAST POOL //////////////////////////////
-    ////////////////////////////////////////////////////////////////////////////
-    let mut pools = Vec::new();
-    //The last pool will always be the same, regardless of how many users are in the pool (it will allow 2 users to withdraw)
-    let exit_pool = create_exit_pool(&withdraw_addresses, &anchor_addr)?;
-    pools.push(exit_pool);
-
-    /////////////////////////////////////////////////////////////////////////////
-    /////////////////////////////CREATE ALL OTHER POOLS//////////////////////////
-    ////////////////////////////////////////////////////////////////////////////
-
-    create_all_pools(&withdraw_addresses, &anchor_addr, &config, &mut pools);
-
-    let total_taproot_spend_info: usize = pools.iter().map(|pool| pool.len()).sum();
-
-    info!(
-        "total taproot addresses across all pools: {} for {} users \n",
-        total_taproot_spend_info, POOL_USERS
-    );
-
-    ////////////////////////////////////////////////////////////////////////////
-    //////////////////////CREATE FIRST POOL/////////////////////////////////////
-    ////////////////////////////////////////////////////////////////////////////
-
-    let pool_0 = create_entry_pool_withdraw_hashes(
-        &withdraw_addresses,
-        pools.last().unwrap(),
-        &anchor_addr,
-        &config,
-        (AMOUNT_PER_USER) * (POOL_USERS - 1).try_into()?,
-    );
-    let pool_0_spend_info = create_pool_address(pool_0.clone())?;
-    let mut pool_0_map = HashMap::new();
-    pool_0_map.insert(vec![0], pool_0_spend_info.clone());
-    pools.push(pool_0_map);
+    if rpc.get_balance(None, None)? < pool.funding_amount() {
+        mine(&rpc, 101, &mining_address)?;
+    }
+
+    //every member pays into the pool from their own wallet
+    let wallets = member_wallets(&config, params.users)?;
+    let init_wallets_txid =
+        send_funding_transaction(&rpc, &wallets, &config, &params, pool.members())?;
+    info!("member wallets funded in {} \n", init_wallets_txid);
+
+    mine(&rpc, 1, &mining_address)?;
+
+    let exit_order = exit_order(cli.exit_order, &MemberSet::full(params.users))?;
+
+    if let Some(exits) = cli.split_after {
+        if params.split_timeout == 0 {
+            bail!("--split-after needs a split timeout, the pool has none");
+        }
+        if exits + 3 > params.users {
+            bail!(
+                "a {} user pool can only be split after at most {} exits",
+                params.users,
+                params.users - 3
+            );
+        }
+    }
+
+    //check the order before funding the pool
+    pool.exit_transactions(OutPoint::null(), &exit_order, signer)?;
+
+    if let Some(path) = &cli.pool_file {
+        pool.save(path)?;
+    }
 
     //////////////////////////////////////////////////////////////////////////////////
     /////////////////////////////FUND POOL WITH PSBT//////////////////////////////////
     /////////////////////////////////////////////////////////////////////////////////
 
-    //the first pools address
-    let pool_0_addr = Address::p2tr_tweaked(pool_0_spend_info.output_key(), config.network);
+    let fee_rate = commands::round_fee_rate(&config, None)?;
 
-    //here we will simulate the pool psbt funding transaction
-    let pool_funding_txid = simulate_psbt_signing(&rpc, init_wallets_txid, &pool_0_addr)?;
+    let funding_tx = match &cli.funding_socket {
+        Some(addr) => fund_over_socket(&pool, &wallets, fee_rate, addr)?,
+        None => fund_with_files(&pool, &wallets, fee_rate, &cli.funding_dir)?,
+    };
+    let pool_funding_txid = rpc.broadcast(&funding_tx)?;
 
     info!("PSBT Pool funding txid: {} \n", pool_funding_txid);
 
-    #[cfg(feature = "regtest")]
-    let _ = rpc.generate_to_address(1, &mining_address);
+    mine(&rpc, 1, &mining_address)?;
 
     ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
-    ////we are going to test spending, but for the PoC we will just spend in the order of addresses so for example, for a 10 user pool it will be///
-    /////////////////////Alice -> Bob -> Carol -> Danny -> Eve -> Frank -> George -> Helen -> Igor && Jao///////////////////////////////////////////
+    ////any user can leave at any time, so we spend in a random order unless one is given, for example for a 10 user pool it could be//////////////
+    /////////////////////Danny -> Alice -> Jao -> Eve -> Carol -> Igor -> George -> Bob -> Frank && Helen///////////////////////////////////////////
     ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-    let mut current_txid = pool_funding_txid;
-    for i in 0..=(POOL_USERS - 2) {
-        current_txid = process_pool_spend(
-            &pools,
-            &config,
+    if cli.cooperative_close {
+        let close_txid = process_cooperative_close(
+            &pool,
             &rpc,
-            i,
-            &withdraw_addresses,
-            current_txid,
-            &anchor_addr,
-            &mining_address,
+            &MemberSet::full(params.users),
+            &musig_seckeys,
+            pool_funding_txid,
         )?;
+        mine(&rpc, 1, &mining_address)?;
+        info!("pool closed cooperatively in {} \n", close_txid);
+        return Ok(());
     }
 
+    info!("exit order: {:?} \n", exit_order);
+
+    if params.mode == PoolMode::SplitTree {
+        //the funding round always puts the pool output first
+        let mut nodes = HashMap::from([(
+            MemberSet::full(params.users),
+            OutPoint::new(pool_funding_txid, 0),
+        )]);
+        for &member in &exit_order {
+            //each split has to confirm before the next one spends it
+            let mut splits = 0;
+            while process_tree_split(&pool, &rpc, &mut nodes, member)?.is_some() {
+                mine(&rpc, 1, &mining_address)?;
+                splits += 1;
+            }
+            info!("user {} paid out after {} tree splits \n", member, splits);
+        }
+        return Ok(());
+    }
+
+    let exits = cli.split_after.unwrap_or(params.users - 1);
+
+    let mut remaining = MemberSet::full(params.users);
+    let current_txid = exit_users(
+        &pool,
+        &rpc,
+        &mut remaining,
+        pool_funding_txid,
+        &exit_order[..exits],
+        &mining_address,
+        signer,
+    )?;
+
+    if cli.split_after.is_some() {
+        //the last exit is confirmed, the split needs split_timeout confirmations on top of it
+        mine(&rpc, params.split_timeout.into(), &mining_address)?;
+        let split_txid = process_pool_split(&pool, &rpc, &remaining, current_txid)?;
+        mine(&rpc, 1, &mining_address)?;
+        info!("pool split between {:?} in {} \n", remaining, split_txid);
+    }
+
+    Ok(())
+}
+
+//the order users leave in, the given one or a random one. It has to list every user in users once
+fn exit_order(given: Option<Vec<usize>>, users: &MemberSet) -> Result<Vec<usize>> {
+    let order = match given {
+        Some(order) => order,
+        None => {
+            let mut order = users.to_vec();
+            order.shuffle(&mut thread_rng());
+            order
+        }
+    };
+    if order.len() != users.len() || !order.iter().all(|&user| users.contains(user)) {
+        bail!("exit order has to list every user in the pool {:?}", users);
+    }
+    Ok(order)
+}
+
+//exit users one after the other from the pool state holding remaining in current_txid, returns
+//the txid of the last exit
+fn exit_users(
+    pool: &PaymentPool,
+    rpc: &Client,
+    remaining: &mut MemberSet,
+    mut current_txid: Txid,
+    users: &[usize],
+    mining_address: &Address,
+    signer: Option<&ExitSigner>,
+) -> Result<Txid> {
+    for &spender_index in users {
+        current_txid =
+            process_pool_spend(pool, rpc, remaining, spender_index, current_txid, signer)?;
+        mine(rpc, 1, mining_address)?;
+        remaining.remove(spender_index);
+    }
+
+    Ok(current_txid)
+}
+
+//the demo confirms every spend right away on regtest
+#[cfg(feature = "regtest")]
+fn mine(rpc: &Client, blocks: u64, address: &Address) -> Result<()> {
+    rpc.generate_to_address(blocks, address)?;
     Ok(())
 }
+
+//a signet node can't make blocks without the signet key, the spends wait for the signet's own
+#[cfg(not(feature = "regtest"))]
+fn mine(_rpc: &Client, _blocks: u64, _address: &Address) -> Result<()> {
+    Ok(())
+}
+
+//the round through psbt files in dir, one fund subcommand step after the other with every member
+//only reading the files the coordinator wrote and writing their own
+fn fund_with_files(
+    pool: &PaymentPool,
+    wallets: &[Client],
+    fee_rate: u64,
+    dir: &Path,
+) -> Result<Transaction> {
+    commands::propose_round(pool, dir, fee_rate)?;
+    for (member, wallet) in wallets.iter().enumerate() {
+        let own = &pool.members()[member];
+        commands::contribute_to_round(pool, wallet, own, member, dir)?;
+    }
+    commands::join_contributions(pool, dir)?;
+    //each member checks the funding psbt against the withdraw address and deposit they picked
+    for (member, wallet) in wallets.iter().enumerate() {
+        let own = &pool.members()[member];
+        commands::sign_round(pool, wallet, own, pool.params(), member, dir)?;
+    }
+
+    info!("funding round psbts are in {} \n", dir.display());
+    commands::finalize_round(pool, dir)
+}
+
+//the round over a local socket, every member connects from their own thread
+fn fund_over_socket(
+    pool: &PaymentPool,
+    wallets: &[Client],
+    fee_rate: u64,
+    addr: &str,
+) -> Result<Transaction> {
+    let mut round = FundingRound::new(pool, fee_rate)?;
+    let listener = TcpListener::bind(addr)?;
+    info!("funding round listening on {} \n", addr);
+
+    //every member is a thread of this process, they answer right away
+    local_round(&listener, &mut round, Duration::from_secs(60), |member| {
+        let own = &pool.members()[member];
+        commands::connect_member(addr, pool, &wallets[member], own, pool.params(), member)
+    })
+}
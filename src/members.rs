@@ -0,0 +1,291 @@
+use bitcoin::{secp256k1::PublicKey, Address, Amount, XOnlyPublicKey};
+use std::fmt;
+
+//a user in the pool, they can only ever withdraw what they deposited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMember {
+    pub withdraw_address: Address,
+    pub deposit: Amount,
+    //key for cooperative spends, when every member of a pool state has one the state's internal
+    //key is their musig2 aggregate instead of the unspendable key
+    pub musig_key: Option<PublicKey>,
+    //key the member signs their exits with, usually the key behind their withdraw address. Without
+    //one anybody who knows the pool's leaves can push the member out
+    pub exit_key: Option<XOnlyPublicKey>,
+}
+
+impl PoolMember {
+    pub fn new(withdraw_address: Address, deposit: Amount) -> Self {
+        Self {
+            withdraw_address,
+            deposit,
+            musig_key: None,
+            exit_key: None,
+        }
+    }
+
+    pub fn with_musig_key(mut self, musig_key: PublicKey) -> Self {
+        self.musig_key = Some(musig_key);
+        self
+    }
+
+    pub fn with_exit_key(mut self, exit_key: XOnlyPublicKey) -> Self {
+        self.exit_key = Some(exit_key);
+        self
+    }
+}
+
+//sum of the deposits of the given members, this is what a pool state holding them locks up
+pub fn total_deposits(members: &[PoolMember], users: &MemberSet) -> Amount {
+    users.iter().map(|u| members[u].deposit).sum()
+}
+
+//set of pool members by index. Sets of members below 128 are a single u128, anything bigger falls
+//back to a vec of words. The small form is always used when it fits so equal sets hash the same
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum MemberSet {
+    Small(u128),
+    Large(Vec<u64>),
+}
+
+const SMALL_BITS: usize = 128;
+
+impl MemberSet {
+    pub fn new() -> Self {
+        MemberSet::Small(0)
+    }
+
+    //every member of a pool with this many users
+    pub fn full(users: usize) -> Self {
+        (0..users).collect()
+    }
+
+    pub fn contains(&self, member: usize) -> bool {
+        match self {
+            MemberSet::Small(bits) => member < SMALL_BITS && bits & (1 << member) != 0,
+            MemberSet::Large(words) => words
+                .get(member / 64)
+                .is_some_and(|word| word & (1 << (member % 64)) != 0),
+        }
+    }
+
+    pub fn insert(&mut self, member: usize) {
+        match self {
+            MemberSet::Small(bits) if member < SMALL_BITS => *bits |= 1 << member,
+            MemberSet::Small(bits) => {
+                let words = vec![*bits as u64, (*bits >> 64) as u64];
+                *self = MemberSet::Large(words);
+                self.insert(member);
+            }
+            MemberSet::Large(words) => {
+                if words.len() <= member / 64 {
+                    words.resize(member / 64 + 1, 0);
+                }
+                words[member / 64] |= 1 << (member % 64);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: usize) {
+        match self {
+            MemberSet::Small(bits) => {
+                if member < SMALL_BITS {
+                    *bits &= !(1 << member);
+                }
+            }
+            MemberSet::Large(words) => {
+                if let Some(word) = words.get_mut(member / 64) {
+                    *word &= !(1 << (member % 64));
+                }
+                self.shrink();
+            }
+        }
+    }
+
+    //the set left behind when member exits
+    pub fn without(&self, member: usize) -> Self {
+        let mut set = self.clone();
+        set.remove(member);
+        set
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            MemberSet::Small(bits) => bits.count_ones() as usize,
+            MemberSet::Large(words) => words.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    //members in ascending order, read straight from the bits
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        Members {
+            set: self,
+            index: 0,
+            word: self.word(0).unwrap_or(0),
+        }
+    }
+
+    //the 64 members starting at index * 64, None past the end of the set
+    fn word(&self, index: usize) -> Option<u64> {
+        match self {
+            MemberSet::Small(bits) if index < SMALL_BITS / 64 => {
+                Some((bits >> (index * 64)) as u64)
+            }
+            MemberSet::Small(_) => None,
+            MemberSet::Large(words) => words.get(index).copied(),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<usize> {
+        self.iter().collect()
+    }
+
+    //go back to the small form once nothing above it is set
+    fn shrink(&mut self) {
+        if let MemberSet::Large(words) = self {
+            while words.last() == Some(&0) {
+                words.pop();
+            }
+            if words.len() <= SMALL_BITS / 64 {
+                let low = words.first().copied().unwrap_or(0) as u128;
+                let high = words.get(1).copied().unwrap_or(0) as u128;
+                *self = MemberSet::Small(low | (high << 64));
+            }
+        }
+    }
+}
+
+struct Members<'a> {
+    set: &'a MemberSet,
+    index: usize,
+    //bits of the current word not returned yet
+    word: u64,
+}
+
+impl Iterator for Members<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if self.word != 0 {
+                let bit = self.word.trailing_zeros() as usize;
+                //clear the lowest set bit
+                self.word &= self.word - 1;
+                return Some(self.index * 64 + bit);
+            }
+            self.index += 1;
+            self.word = self.set.word(self.index)?;
+        }
+    }
+}
+
+impl Default for MemberSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromIterator<usize> for MemberSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = MemberSet::new();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl fmt::Debug for MemberSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    };
+
+    fn hash(set: &MemberSet) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        set.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn full_holds_every_user() {
+        let set = MemberSet::full(5);
+        assert_eq!(set.to_vec(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(set.len(), 5);
+        assert!(MemberSet::full(0).is_empty());
+
+        let big = MemberSet::full(200);
+        assert!(matches!(big, MemberSet::Large(_)));
+        assert_eq!(big.len(), 200);
+        assert_eq!(big.to_vec(), (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn contains_only_inserted_members() {
+        let set: MemberSet = [0, 63, 64, 127].into_iter().collect();
+        for member in [0, 63, 64, 127] {
+            assert!(set.contains(member));
+        }
+        for member in [1, 62, 65, 126, 128, 1000] {
+            assert!(!set.contains(member));
+        }
+
+        let big: MemberSet = [5, 128, 300].into_iter().collect();
+        assert!(big.contains(128) && big.contains(300) && big.contains(5));
+        assert!(!big.contains(129) && !big.contains(1000));
+    }
+
+    #[test]
+    fn without_leaves_the_original_alone() {
+        let set = MemberSet::full(4);
+        let rest = set.without(2);
+        assert_eq!(rest.to_vec(), vec![0, 1, 3]);
+        assert_eq!(set.len(), 4);
+        assert_eq!(rest.without(7), rest);
+    }
+
+    #[test]
+    fn iterates_in_order_across_words() {
+        let members = vec![0, 1, 63, 64, 65, 127, 128, 191, 192, 500];
+        let set: MemberSet = members.iter().copied().collect();
+        assert_eq!(set.to_vec(), members);
+        assert_eq!(set.len(), members.len());
+    }
+
+    #[test]
+    fn large_set_shrunk_below_128_equals_the_small_one() {
+        let small: MemberSet = [3, 127].into_iter().collect();
+        assert!(matches!(small, MemberSet::Small(_)));
+
+        let mut shrunk: MemberSet = [3, 127, 128].into_iter().collect();
+        assert!(matches!(shrunk, MemberSet::Large(_)));
+        shrunk.remove(128);
+
+        assert!(matches!(shrunk, MemberSet::Small(_)));
+        assert_eq!(shrunk, small);
+        assert_eq!(hash(&shrunk), hash(&small));
+    }
+
+    #[test]
+    fn large_sets_built_in_any_order_are_equal() {
+        let a: MemberSet = [1, 129, 256].into_iter().collect();
+        let mut b: MemberSet = [256, 1, 129, 400].into_iter().collect();
+        b.remove(400);
+
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(a, a.without(129));
+    }
+}
@@ -0,0 +1,508 @@
+use anyhow::{bail, ensure, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use bitcoin::{
+    secp256k1::schnorr, sighash::TapSighash, taproot::TaprootSpendInfo, Address, Amount, Network,
+    OutPoint, ScriptBuf, Transaction, TxOut,
+};
+use tracing::info;
+
+use crate::{
+    config::{PoolMode, PoolParams},
+    cooperative::state_internal_key,
+    ctv_scripts::{create_pool_address, CtvTemplate, LeafSigner, LeafSpend},
+    members::{total_deposits, MemberSet, PoolMember},
+    pools::{
+        create_all_pools, create_entry_pool_leaves, create_exit_pool, create_pool_state_leaves,
+        create_pool_state_templates, send_final_exit, send_from_pool, send_split,
+    },
+    split_tree::{
+        is_tree_node, tree_node_amount, tree_split_leaf, tree_split_outputs, tree_split_template,
+        validate_tree_deposits,
+    },
+    verify::{verify_ctv_spend, CtvSpend},
+};
+
+//signs an exit for the member at the given index, only needed for members with an exit key
+pub type ExitSigner<'a> = dyn Fn(usize, TapSighash) -> Result<schnorr::Signature> + 'a;
+
+//A payment pool for a fixed set of users. Every pool state is keyed by its sorted members, the
+//funding pool holds every user and the last states hold 2 users who exit together.
+//
+//States are derived recursively and memoised, so a pool built with new_lazy only computes the
+//states that something asks for. This is a cache, not a way around the size of the tree: a state
+//commits to the address of every state it can exit to, so the funding address of an exact pool
+//needs every subset of users and can't be built for 30+ users either way. Only a caller that never
+//needs the funding address (e.g. one that only spends the current state of a pool that already
+//shrank) derives less. Big pools need PoolMode::SplitTree, which has users - 1 states
+#[derive(Debug)]
+pub struct PaymentPool {
+    params: PoolParams,
+    network: Network,
+    members: Vec<PoolMember>,
+    anchor_addr: Address,
+    states: Mutex<HashMap<MemberSet, TaprootSpendInfo>>,
+    //states put back with from_parts that haven't been checked against the members and params yet
+    unchecked: Mutex<HashSet<MemberSet>>,
+}
+
+impl PaymentPool {
+    //build every pool state up front
+    pub fn new(
+        params: PoolParams,
+        network: Network,
+        members: Vec<PoolMember>,
+        anchor_addr: Address,
+    ) -> Result<Self> {
+        let pool = Self::new_lazy(params, network, members, anchor_addr)?;
+        let members = &pool.members;
+
+        //deriving the root of a split tree derives every node under it
+        if params.mode == PoolMode::SplitTree {
+            pool.funding_spend_info()?;
+            info!(
+                "total taproot addresses in the split tree: {} for {} users \n",
+                pool.derived_states(),
+                params.users
+            );
+            return Ok(pool);
+        }
+
+        ////////////////////////////////////////////////////////////////////////////
+        /////////////////////////////CREATE LAST POOL //////////////////////////////
+        ////////////////////////////////////////////////////////////////////////////
+        let mut pools = Vec::new();
+        //The last pool will always be the same, regardless of how many users are in the pool (it will allow 2 users to withdraw)
+        let exit_pool = create_exit_pool(&params, members, &pool.anchor_addr)?;
+        pools.push(exit_pool);
+
+        /////////////////////////////////////////////////////////////////////////////
+        /////////////////////////////CREATE ALL OTHER POOLS//////////////////////////
+        ////////////////////////////////////////////////////////////////////////////
+
+        create_all_pools(&params, members, &pool.anchor_addr, network, &mut pools)?;
+
+        ////////////////////////////////////////////////////////////////////////////
+        //////////////////////CREATE FIRST POOL/////////////////////////////////////
+        ////////////////////////////////////////////////////////////////////////////
+
+        let pool_0 = create_entry_pool_leaves(
+            &params,
+            members,
+            pools.last().unwrap(),
+            &pool.anchor_addr,
+            network,
+        );
+        let all_users = MemberSet::full(params.users);
+        let pool_0_spend_info =
+            create_pool_address(pool_0, state_internal_key(members, &all_users)?)?;
+        let mut pool_0_map = HashMap::new();
+        pool_0_map.insert(all_users, pool_0_spend_info);
+        pools.push(pool_0_map);
+
+        let total_taproot_spend_info: usize = pools.iter().map(|pool| pool.len()).sum();
+
+        info!(
+            "total taproot addresses across all pools: {} for {} users \n",
+            total_taproot_spend_info, params.users
+        );
+
+        pool.states
+            .lock()
+            .unwrap()
+            .extend(pools.into_iter().flatten());
+
+        Ok(pool)
+    }
+
+    //don't compute anything yet, pool states are derived and cached the first time they are needed
+    pub fn new_lazy(
+        params: PoolParams,
+        network: Network,
+        members: Vec<PoolMember>,
+        anchor_addr: Address,
+    ) -> Result<Self> {
+        params.validate()?;
+
+        if members.len() != params.users {
+            bail!(
+                "expected {} pool members, got {}",
+                params.users,
+                members.len()
+            );
+        }
+
+        for member in &members {
+            params.validate_deposit(member.deposit)?;
+        }
+        if params.mode == PoolMode::SplitTree {
+            validate_tree_deposits(&params, &members)?;
+        }
+
+        Ok(Self {
+            params,
+            network,
+            members,
+            anchor_addr,
+            states: Mutex::new(HashMap::new()),
+            unchecked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    //put a pool back together from states that were already built, e.g. ones loaded from a pool file.
+    //any state that is missing is derived when it is needed, and every given state is checked
+    //against the members and params the first time it is used (see check_stored_state)
+    pub(crate) fn from_parts(
+        params: PoolParams,
+        network: Network,
+        members: Vec<PoolMember>,
+        anchor_addr: Address,
+        states: HashMap<MemberSet, TaprootSpendInfo>,
+    ) -> Result<Self> {
+        let pool = Self::new_lazy(params, network, members, anchor_addr)?;
+        *pool.unchecked.lock().unwrap() = states.keys().cloned().collect();
+        *pool.states.lock().unwrap() = states;
+        Ok(pool)
+    }
+
+    pub fn params(&self) -> &PoolParams {
+        &self.params
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn members(&self) -> &[PoolMember] {
+        &self.members
+    }
+
+    pub fn anchor_addr(&self) -> &Address {
+        &self.anchor_addr
+    }
+
+    //number of pool states computed so far
+    pub fn derived_states(&self) -> usize {
+        self.states.lock().unwrap().len()
+    }
+
+    //run f over every pool state computed so far
+    pub fn with_states<R>(&self, f: impl FnOnce(&HashMap<MemberSet, TaprootSpendInfo>) -> R) -> R {
+        f(&self.states.lock().unwrap())
+    }
+
+    //spend info for the pool state holding exactly these members.
+    //derives the state (and every state it can exit to) if it hasn't been computed yet
+    pub fn spend_info(&self, users: &MemberSet) -> Result<TaprootSpendInfo> {
+        let cached = self.states.lock().unwrap().get(users).cloned();
+        if let Some(spend_info) = cached {
+            if self.unchecked.lock().unwrap().contains(users) {
+                self.check_stored_state(users, &spend_info)?;
+            }
+            return Ok(spend_info);
+        }
+
+        if users.len() < 2 {
+            bail!("a pool state needs at least 2 members, got {:?}", users);
+        }
+
+        if let Some(unknown) = users.iter().find(|&u| u >= self.params.users) {
+            bail!(
+                "user {} is not in a {} user pool",
+                unknown,
+                self.params.users
+            );
+        }
+
+        let spend_info = self.build_state(users, |next_users| {
+            let spend_info = self.spend_info(next_users)?;
+            Ok(Address::p2tr_tweaked(spend_info.output_key(), self.network))
+        })?;
+
+        self.states
+            .lock()
+            .unwrap()
+            .insert(users.clone(), spend_info.clone());
+
+        Ok(spend_info)
+    }
+
+    //A pool file only has to agree with itself to load, so a stored state is built again from the
+    //members and params the first time it is used, on top of the stored states it can move to, and
+    //has to give the stored output key. The states under it are checked once they are used in turn,
+    //so spending checks the states on its path and nothing else. The internal key is rebuilt too, a
+    //stored state with an attacker's leaf or key path can't be spent from
+    fn check_stored_state(&self, users: &MemberSet, spend_info: &TaprootSpendInfo) -> Result<()> {
+        let rebuilt = self.build_state(users, |next_users| {
+            let stored = self
+                .states
+                .lock()
+                .unwrap()
+                .get(next_users)
+                .map(|next| next.output_key());
+            let output_key = match stored {
+                Some(output_key) => output_key,
+                None => self.spend_info(next_users)?.output_key(),
+            };
+            Ok(Address::p2tr_tweaked(output_key, self.network))
+        })?;
+
+        ensure!(
+            rebuilt.output_key() == spend_info.output_key(),
+            "pool state {:?} doesn't match the pool's members and params",
+            users
+        );
+        self.unchecked.lock().unwrap().remove(users);
+        Ok(())
+    }
+
+    //build the pool state holding users from its members and params, next_addr gives the address
+    //of every state it can move to
+    pub(crate) fn build_state(
+        &self,
+        users: &MemberSet,
+        mut next_addr: impl FnMut(&MemberSet) -> Result<Address>,
+    ) -> Result<TaprootSpendInfo> {
+        let leaves = match self.params.mode {
+            PoolMode::Exact => create_pool_state_leaves(
+                &self.params,
+                &self.members,
+                &self.anchor_addr,
+                users,
+                next_addr,
+            )?,
+            PoolMode::SplitTree => {
+                if !is_tree_node(self.params.users, users) {
+                    bail!("{:?} is not a node of the split tree", users);
+                }
+                let outputs = tree_split_outputs(
+                    &self.params,
+                    &self.members,
+                    &self.anchor_addr,
+                    users,
+                    &mut next_addr,
+                )?;
+                vec![tree_split_leaf(&outputs)]
+            }
+        };
+        create_pool_address(leaves, state_internal_key(&self.members, users)?)
+    }
+
+    pub fn funding_spend_info(&self) -> Result<TaprootSpendInfo> {
+        self.spend_info(&MemberSet::full(self.params.users))
+    }
+
+    //the address the funding psbt has to pay the full pool amount to
+    pub fn funding_address(&self) -> Result<Address> {
+        Ok(Address::p2tr_tweaked(
+            self.funding_spend_info()?.output_key(),
+            self.network,
+        ))
+    }
+
+    //amount locked in the pool state holding these members
+    pub fn pool_amount(&self, members: &MemberSet) -> Amount {
+        state_amount(&self.params, &self.members, members)
+    }
+
+    //amount the funding psbt has to pay to the funding address
+    pub fn funding_amount(&self) -> Amount {
+        self.members.iter().map(|member| member.deposit).sum()
+    }
+
+    //build the exit tx for spender_index leaving the pool state holding the remaining members,
+    //spending the pool output at previous_output. When only 2 members remain they exit together.
+    //signer has to sign for spender_index if they have an exit key
+    pub fn exit_tx(
+        &self,
+        previous_pool: &MemberSet,
+        spender_index: usize,
+        previous_output: OutPoint,
+        signer: Option<&ExitSigner>,
+    ) -> Result<Transaction> {
+        let leaf_signer = signer.map(|signer| {
+            move |sighash: TapSighash| -> Result<schnorr::Signature> {
+                signer(spender_index, sighash)
+            }
+        });
+        let leaf_signer = leaf_signer.as_ref().map(|signer| signer as &LeafSigner);
+
+        if previous_pool.len() > 2 {
+            info!(
+                "init withdrawal from pool {:?} to pool {:?} \n",
+                previous_pool,
+                previous_pool.without(spender_index)
+            );
+        }
+
+        self.exit_spend(previous_pool, spender_index, previous_output)?
+            .finalize(leaf_signer)
+    }
+
+    //the exit of spender_index from previous_pool before its witness is built
+    pub fn exit_spend(
+        &self,
+        previous_pool: &MemberSet,
+        spender_index: usize,
+        previous_output: OutPoint,
+    ) -> Result<LeafSpend> {
+        if self.params.mode == PoolMode::SplitTree {
+            bail!("a split tree pool has no exit leaves, members leave through tree_exit_transactions");
+        }
+
+        if !previous_pool.contains(spender_index) {
+            bail!(
+                "user {} is not in the pool state {:?}",
+                spender_index,
+                previous_pool
+            );
+        }
+
+        let previous_pool_spend_info = self.spend_info(previous_pool)?;
+
+        if previous_pool.len() == 2 {
+            return Ok(send_final_exit(
+                &self.params,
+                &self.members,
+                &self.anchor_addr,
+                previous_pool,
+                spender_index,
+                &previous_pool_spend_info,
+                previous_output,
+            ));
+        }
+
+        let recipient_pool = previous_pool.without(spender_index);
+
+        let pool_exit_amount = self.pool_amount(&recipient_pool);
+
+        Ok(send_from_pool(
+            &self.params,
+            self.network,
+            &self.spend_info(&recipient_pool)?,
+            &previous_pool_spend_info,
+            &self.members[spender_index],
+            &self.anchor_addr,
+            pool_exit_amount,
+            previous_output,
+        ))
+    }
+
+    //the template every leaf of the pool state holding users commits to
+    pub fn state_templates(&self, users: &MemberSet) -> Result<Vec<CtvTemplate>> {
+        if self.params.mode == PoolMode::SplitTree {
+            return Ok(vec![tree_split_template(self.tree_split_outputs(users)?)]);
+        }
+
+        create_pool_state_templates(
+            &self.params,
+            &self.members,
+            &self.anchor_addr,
+            users,
+            |remaining_users| {
+                let spend_info = self.spend_info(remaining_users)?;
+                Ok(Address::p2tr_tweaked(spend_info.output_key(), self.network))
+            },
+        )
+    }
+
+    //check tx spends the pool state holding users (as its only input) through one of the state's
+    //leaves, without a node. A CtvVerifyError says which field of the tx is wrong
+    pub fn verify_spend(&self, tx: &Transaction, users: &MemberSet) -> Result<CtvSpend> {
+        let spend_info = self.spend_info(users)?;
+        let prevout = TxOut {
+            value: self.pool_amount(users),
+            script_pubkey: ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+        };
+
+        Ok(verify_ctv_spend(
+            tx,
+            0,
+            &[prevout],
+            &self.state_templates(users)?,
+        )?)
+    }
+
+    //split the pool state holding users between all of them through its timelocked leaf, the tx
+    //is only valid once the pool output at previous_output has params.split_timeout confirmations
+    pub fn split_tx(&self, users: &MemberSet, previous_output: OutPoint) -> Result<Transaction> {
+        self.split_spend(users, previous_output)?.finalize(None)
+    }
+
+    //the timeout split of the pool state holding users before its witness is built
+    pub fn split_spend(&self, users: &MemberSet, previous_output: OutPoint) -> Result<LeafSpend> {
+        if self.params.mode == PoolMode::SplitTree {
+            bail!("split tree nodes have no timeout split, anyone can split them at any time");
+        }
+
+        let spend_info = self.spend_info(users)?;
+
+        send_split(
+            &self.params,
+            &self.members,
+            &self.anchor_addr,
+            users,
+            &spend_info,
+            previous_output,
+        )
+    }
+
+    //every exit tx from the funding outpoint to the final split, with users leaving in exit_order.
+    //exit_order must list every user once, the last 2 users in it exit together in the final tx.
+    //In a split tree it's every node split, in the order the users in exit_order need them.
+    //CTV doesn't commit to the witness, so each txid is known in advance (signed or not) and the
+    //whole chain can be built before anything is broadcast
+    pub fn exit_transactions(
+        &self,
+        funding_outpoint: OutPoint,
+        exit_order: &[usize],
+        signer: Option<&ExitSigner>,
+    ) -> Result<Vec<Transaction>> {
+        let mut sorted_order = exit_order.to_vec();
+        sorted_order.sort();
+        if sorted_order != (0..self.params.users).collect::<Vec<_>>() {
+            bail!(
+                "exit order {:?} must list every user of the {} user pool once",
+                exit_order,
+                self.params.users
+            );
+        }
+
+        if self.params.mode == PoolMode::SplitTree {
+            return self.tree_split_transactions(funding_outpoint, exit_order);
+        }
+
+        let mut txs = Vec::new();
+        let mut remaining = MemberSet::full(self.params.users);
+        let mut previous_output = funding_outpoint;
+
+        for &spender_index in &exit_order[..self.params.users - 1] {
+            let tx = self.exit_tx(&remaining, spender_index, previous_output, signer)?;
+            //the next pool is always the first output of an exit tx
+            previous_output = OutPoint {
+                txid: tx.compute_txid(),
+                vout: 0,
+            };
+            txs.push(tx);
+            remaining.remove(spender_index);
+        }
+
+        Ok(txs)
+    }
+}
+
+//amount locked in the pool state holding users, what is left of their deposits after the splits
+//above it in a split tree
+pub(crate) fn state_amount(
+    params: &PoolParams,
+    members: &[PoolMember],
+    users: &MemberSet,
+) -> Amount {
+    match params.mode {
+        PoolMode::Exact => total_deposits(members, users),
+        PoolMode::SplitTree => tree_node_amount(params, members, users),
+    }
+}
@@ -0,0 +1,511 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+};
+
+use bitcoin::{
+    address::NetworkUnchecked,
+    secp256k1::PublicKey,
+    taproot::{LeafVersion, NodeInfo, TapNodeHash, TaprootSpendInfo},
+    Address, Amount, Network, ScriptBuf, XOnlyPublicKey,
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::{PoolMode, PoolParams},
+    ctv_scripts::SECP,
+    members::{MemberSet, PoolMember},
+    payment_pool::{state_amount, PaymentPool},
+    split_tree::{is_tree_node, validate_tree_deposits},
+};
+
+//bump this whenever the layout of the pool file changes, old files are rejected instead of misread
+pub const POOL_FILE_VERSION: u32 = 7;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolFile {
+    version: u32,
+    network: Network,
+    params: PoolParams,
+    anchor_addr: Address<NetworkUnchecked>,
+    members: Vec<MemberRecord>,
+    //smallest states first, a lazily built pool only has the states it derived so far
+    states: Vec<PoolStateRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileVersion {
+    version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MemberRecord {
+    withdraw_address: Address<NetworkUnchecked>,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    deposit: Amount,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    musig_key: Option<PublicKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_key: Option<XOnlyPublicKey>,
+}
+
+//everything needed to spend one pool state without rebuilding the tree
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolStateRecord {
+    members: Vec<usize>,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    amount: Amount,
+    internal_key: XOnlyPublicKey,
+    output_key: XOnlyPublicKey,
+    leaves: Vec<LeafRecord>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LeafRecord {
+    script: ScriptBuf,
+    //sibling hashes from the leaf up to the root
+    merkle_path: Vec<TapNodeHash>,
+}
+
+impl PoolStateRecord {
+    fn new(amount: Amount, users: &MemberSet, spend_info: &TaprootSpendInfo) -> Self {
+        let mut leaves = Vec::new();
+        for ((script, _), branches) in spend_info.script_map() {
+            for branch in branches {
+                leaves.push(LeafRecord {
+                    script: script.clone(),
+                    merkle_path: branch.as_slice().to_vec(),
+                });
+            }
+        }
+
+        Self {
+            members: users.to_vec(),
+            amount,
+            internal_key: spend_info.internal_key(),
+            output_key: spend_info.output_key().to_x_only_public_key(),
+            leaves,
+        }
+    }
+
+    //rebuild the spend info from the leaves and check it commits to the stored output key
+    fn to_spend_info(&self) -> Result<TaprootSpendInfo> {
+        let leaves = self
+            .leaves
+            .iter()
+            .map(|leaf| (leaf.script.clone(), leaf.merkle_path.clone()))
+            .collect();
+
+        let node = build_node(leaves)
+            .with_context(|| format!("invalid taptree for pool state {:?}", self.members))?;
+        let spend_info = TaprootSpendInfo::from_node_info(&SECP, self.internal_key, node);
+
+        ensure!(
+            spend_info.output_key().to_x_only_public_key() == self.output_key,
+            "output key mismatch for pool state {:?}",
+            self.members
+        );
+
+        Ok(spend_info)
+    }
+}
+
+//the taptree of a pool state, rebuilt from the merkle paths in its spend info
+pub(crate) fn state_tree(spend_info: &TaprootSpendInfo) -> Result<NodeInfo> {
+    let leaves = spend_info
+        .script_map()
+        .iter()
+        .flat_map(|((script, _), branches)| {
+            branches
+                .iter()
+                .map(|branch| (script.clone(), branch.as_slice().to_vec()))
+        })
+        .collect();
+
+    build_node(leaves)
+}
+
+//a leaf script and the part of its merkle path that hasn't been matched to the tree yet
+type LeafPath = (ScriptBuf, Vec<TapNodeHash>);
+
+//the last hash in every merkle path is the sibling of the subtree the leaf is in, so the leaves
+//split into exactly two groups on it until each group is a single leaf
+fn build_node(mut leaves: Vec<LeafPath>) -> Result<NodeInfo> {
+    if leaves.len() == 1 && leaves[0].1.is_empty() {
+        let (script, _) = leaves.remove(0);
+        return Ok(NodeInfo::new_leaf_with_ver(script, LeafVersion::TapScript));
+    }
+
+    let mut groups: Vec<(TapNodeHash, Vec<LeafPath>)> = Vec::new();
+    for (script, mut path) in leaves {
+        let Some(sibling) = path.pop() else {
+            bail!("leaf at the root of a tree with more than one leaf");
+        };
+
+        match groups.iter_mut().find(|(hash, _)| *hash == sibling) {
+            Some((_, group)) => group.push((script, path)),
+            None => groups.push((sibling, vec![(script, path)])),
+        }
+    }
+
+    ensure!(
+        groups.len() == 2,
+        "expected 2 subtrees, found {}",
+        groups.len()
+    );
+
+    let (right_hash, right_leaves) = groups.pop().unwrap();
+    let (left_hash, left_leaves) = groups.pop().unwrap();
+
+    let left = build_node(left_leaves)?;
+    let right = build_node(right_leaves)?;
+
+    //each side of the tree has to hash to the sibling the other side committed to
+    ensure!(
+        left.node_hash() == right_hash && right.node_hash() == left_hash,
+        "merkle path does not match the tree"
+    );
+
+    Ok(NodeInfo::combine(left, right)?)
+}
+
+impl PaymentPool {
+    //write the pool tree to disk so it doesn't have to be rebuilt to exit later
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut states: Vec<PoolStateRecord> = self.with_states(|states| {
+            states
+                .par_iter()
+                .map(|(users, spend_info)| {
+                    PoolStateRecord::new(self.pool_amount(users), users, spend_info)
+                })
+                .collect()
+        });
+        states
+            .par_sort_by(|a, b| (a.members.len(), &a.members).cmp(&(b.members.len(), &b.members)));
+
+        let pool_file = PoolFile {
+            version: POOL_FILE_VERSION,
+            network: self.network(),
+            params: *self.params(),
+            anchor_addr: self.anchor_addr().as_unchecked().clone(),
+            members: self
+                .members()
+                .iter()
+                .map(|member| MemberRecord {
+                    withdraw_address: member.withdraw_address.as_unchecked().clone(),
+                    deposit: member.deposit,
+                    musig_key: member.musig_key,
+                    exit_key: member.exit_key,
+                })
+                .collect(),
+            states,
+        };
+
+        let file = File::create(path)
+            .with_context(|| format!("failed to create pool file {}", path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), &pool_file)?;
+
+        info!("saved pool to {} \n", path.display());
+        Ok(())
+    }
+
+    //load a pool written by save, recomputing every output key from its leaves and internal key.
+    //That only proves each state agrees with itself, the states are checked against the members
+    //and params as they are used instead of rebuilding the tree here
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read pool file {}", path.display()))?;
+
+        //check the version on its own first so an old layout gets a clear error instead of a parse error
+        let file_version: FileVersion = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse pool file {}", path.display()))?;
+        if file_version.version != POOL_FILE_VERSION {
+            bail!(
+                "unsupported pool file version {}, expected {}",
+                file_version.version,
+                POOL_FILE_VERSION
+            );
+        }
+
+        let pool_file: PoolFile = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse pool file {}", path.display()))?;
+
+        let network = pool_file.network;
+        let params = pool_file.params;
+        params.validate()?;
+
+        let anchor_addr = pool_file.anchor_addr.require_network(network)?;
+        let members = pool_file
+            .members
+            .into_iter()
+            .map(|member| {
+                Ok(PoolMember {
+                    withdraw_address: member.withdraw_address.require_network(network)?,
+                    deposit: member.deposit,
+                    musig_key: member.musig_key,
+                    exit_key: member.exit_key,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(
+            members.len() == params.users,
+            "pool file has {} members for a {} user pool",
+            members.len(),
+            params.users
+        );
+        if params.mode == PoolMode::SplitTree {
+            validate_tree_deposits(&params, &members)?;
+        }
+
+        let states = pool_file
+            .states
+            .into_par_iter()
+            .map(|state| {
+                ensure!(
+                    state.members.len() >= 2
+                        && state.members.windows(2).all(|pair| pair[0] < pair[1])
+                        && state.members.iter().all(|&u| u < params.users),
+                    "invalid members for pool state {:?}",
+                    state.members
+                );
+                let users: MemberSet = state.members.iter().copied().collect();
+                ensure!(
+                    params.mode == PoolMode::Exact || is_tree_node(params.users, &users),
+                    "pool state {:?} is not a node of the split tree",
+                    state.members
+                );
+                ensure!(
+                    state.amount == state_amount(&params, &members, &users),
+                    "amount mismatch for pool state {:?}",
+                    state.members
+                );
+
+                let spend_info = state.to_spend_info()?;
+                Ok((users, spend_info))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let pool = PaymentPool::from_parts(params, network, members, anchor_addr, states)?;
+
+        info!("loaded pool from {} \n", path.display());
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PoolParams,
+        ctv_scripts::{create_pool_address, ctv_script},
+        test_utils::{temp_path, test_address, test_pool},
+    };
+    use bitcoin::key::Keypair;
+    use rand::thread_rng;
+
+    fn round_trip(pool: &PaymentPool) -> PaymentPool {
+        let path = temp_path("pool.json");
+        pool.save(&path).unwrap();
+        let loaded = PaymentPool::load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        loaded
+    }
+
+    //save the pool, let tamper change the file and try to load it back
+    fn load_tampered(
+        pool: &PaymentPool,
+        tamper: impl FnOnce(&mut PoolFile),
+    ) -> Result<PaymentPool> {
+        let path = temp_path("tampered.json");
+        pool.save(&path).unwrap();
+        let mut pool_file: PoolFile =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        tamper(&mut pool_file);
+        fs::write(&path, serde_json::to_string(&pool_file).unwrap()).unwrap();
+        let loaded = PaymentPool::load(&path);
+        fs::remove_file(path).unwrap();
+        loaded
+    }
+
+    //swap the funding state for one that agrees with itself but was built by someone else
+    fn replace_funding_state(pool_file: &mut PoolFile, spend_info: &TaprootSpendInfo) {
+        let users = MemberSet::full(pool_file.params.users);
+        let state = pool_file
+            .states
+            .iter_mut()
+            .find(|state| state.members == users.to_vec())
+            .unwrap();
+        *state = PoolStateRecord::new(state.amount, &users, spend_info);
+    }
+
+    fn four_users() -> PoolParams {
+        PoolParams {
+            users: 4,
+            ..Default::default()
+        }
+    }
+
+    fn attacker_key() -> XOnlyPublicKey {
+        Keypair::new(&SECP, &mut thread_rng()).x_only_public_key().0
+    }
+
+    #[test]
+    fn saved_pool_loads_with_the_same_states() {
+        let pool = test_pool(four_users(), &[11000, 25000, 15000, 20000]);
+        let loaded = round_trip(&pool);
+
+        assert_eq!(
+            loaded.funding_address().unwrap(),
+            pool.funding_address().unwrap()
+        );
+        assert_eq!(loaded.derived_states(), pool.derived_states());
+        assert_eq!(loaded.members(), pool.members());
+    }
+
+    #[test]
+    fn saved_split_tree_loads_with_the_same_states() {
+        let params = PoolParams {
+            users: 7,
+            mode: PoolMode::SplitTree,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 7]);
+        let loaded = round_trip(&pool);
+
+        assert_eq!(
+            loaded.funding_address().unwrap(),
+            pool.funding_address().unwrap()
+        );
+        assert_eq!(loaded.derived_states(), 6);
+    }
+
+    #[test]
+    fn lazy_pool_saves_only_what_it_derived() {
+        let params = PoolParams {
+            users: 5,
+            ..Default::default()
+        };
+        let full = test_pool(params, &[20000; 5]);
+        let lazy = PaymentPool::new_lazy(
+            params,
+            full.network(),
+            full.members().to_vec(),
+            full.anchor_addr().clone(),
+        )
+        .unwrap();
+        lazy.spend_info(&[0, 1, 2].into_iter().collect()).unwrap();
+
+        let loaded = round_trip(&lazy);
+        assert_eq!(loaded.derived_states(), 4);
+        assert_eq!(
+            loaded.funding_address().unwrap(),
+            full.funding_address().unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_a_stolen_key_path() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let funding = pool.funding_spend_info().unwrap();
+        let leaves: Vec<ScriptBuf> = funding
+            .script_map()
+            .keys()
+            .map(|(script, _)| script.clone())
+            .collect();
+
+        //same leaves, but the key path belongs to the attacker
+        let stolen = create_pool_address(leaves, attacker_key()).unwrap();
+        let loaded =
+            load_tampered(&pool, |pool_file| replace_funding_state(pool_file, &stolen)).unwrap();
+        let err = loaded.funding_address().unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_a_leaf_the_members_didnt_build() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let funding = pool.funding_spend_info().unwrap();
+        let mut leaves: Vec<ScriptBuf> = funding
+            .script_map()
+            .keys()
+            .map(|(script, _)| script.clone())
+            .collect();
+        leaves[0] = ctv_script([7; 32]);
+
+        let tampered = create_pool_address(leaves, funding.internal_key()).unwrap();
+        let loaded = load_tampered(&pool, |pool_file| {
+            replace_funding_state(pool_file, &tampered)
+        })
+        .unwrap();
+        let err = loaded.funding_address().unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_a_changed_withdraw_address() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let loaded = load_tampered(&pool, |pool_file| {
+            pool_file.members[1].withdraw_address = test_address().as_unchecked().clone();
+        })
+        .unwrap();
+        let err = loaded.funding_address().unwrap_err();
+        assert!(err.to_string().contains("doesn't match"), "{:#}", err);
+    }
+
+    #[test]
+    fn rejects_an_output_key_its_leaves_dont_give() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let err = load_tampered(&pool, |pool_file| {
+            pool_file.states[0].output_key = attacker_key();
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("output key mismatch"), "{:#}", err);
+    }
+
+    #[test]
+    fn only_checks_the_states_in_use() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let users: MemberSet = [0, 1].into_iter().collect();
+        let stolen = create_pool_address(vec![ctv_script([7; 32])], attacker_key()).unwrap();
+        let loaded = load_tampered(&pool, |pool_file| {
+            let state = pool_file
+                .states
+                .iter_mut()
+                .find(|state| state.members == users.to_vec())
+                .unwrap();
+            *state = PoolStateRecord::new(state.amount, &users, &stolen);
+        })
+        .unwrap();
+
+        //nothing on the way from the funding state to [2, 3] goes through [0, 1]
+        let other: MemberSet = [2, 3].into_iter().collect();
+        assert_eq!(
+            loaded.spend_info(&other).unwrap().output_key(),
+            pool.spend_info(&other).unwrap().output_key()
+        );
+        assert!(loaded.spend_info(&users).is_err());
+    }
+
+    #[test]
+    fn derives_a_state_missing_from_the_file() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let loaded = load_tampered(&pool, |pool_file| {
+            pool_file
+                .states
+                .retain(|state| state.members != vec![0, 1, 2]);
+        })
+        .unwrap();
+
+        assert_eq!(
+            loaded.funding_address().unwrap(),
+            pool.funding_address().unwrap()
+        );
+        assert_eq!(loaded.derived_states(), pool.derived_states());
+    }
+}
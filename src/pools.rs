@@ -1,121 +1,362 @@
-use anyhow::Result;
-use std::{collections::HashMap, vec};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    vec,
+};
 
 use bitcoin::{
-    absolute, consensus::encode::serialize_hex, opcodes::all::OP_RETURN, script::Builder,
-    taproot::TaprootSpendInfo, transaction, Address, Amount, OutPoint, Sequence, Transaction, TxIn,
-    TxOut, Txid,
+    absolute, consensus::encode::serialize_hex, secp256k1::SecretKey, taproot::TaprootSpendInfo,
+    transaction, Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut,
+    Txid,
 };
-use bitcoincore_rpc::{Client, RpcApi};
 use itertools::Itertools;
 use rayon::prelude::*;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
-    config::{NetworkConfig, DEFAULT_FEE_RATE, FEE_AMOUNT, TX_VERSION},
-    ctv_scripts::{create_pool_address, create_withdraw_ctv_hash, spend_ctv},
-    AMOUNT_PER_USER, POOL_USERS,
+    chain::ChainBackend,
+    config::{PoolParams, DEFAULT_FEE_RATE, TX_VERSION},
+    cooperative::{state_internal_key, CooperativeSpend},
+    cpfp::{anchor_output, parent_fee, plan_child},
+    ctv_scripts::{
+        anchor_txout, calc_ctv_hash, create_pool_address, create_withdraw_ctv_hash, ctv_script,
+        exit_leaf_script, withdraw_outputs, CtvTemplate, LeafSpend,
+    },
+    members::{total_deposits, MemberSet, PoolMember},
+    payment_pool::{ExitSigner, PaymentPool},
 };
 
-pub fn create_entry_pool_withdraw_hashes(
-    addresses: &[Address],
-    second_pool_addresses: &HashMap<Vec<usize>, TaprootSpendInfo>,
+pub fn create_entry_pool_leaves(
+    params: &PoolParams,
+    members: &[PoolMember],
+    second_pool_addresses: &HashMap<MemberSet, TaprootSpendInfo>,
     anchor_addr: &Address,
-    config: &NetworkConfig,
-    pool_exit_ammount: Amount,
-) -> Vec<[u8; 32]> {
-    let mut entry_pool_withdraw_hashes = Vec::new();
+    network: Network,
+) -> Vec<ScriptBuf> {
+    let mut entry_pool_leaves = Vec::new();
 
-    for (i, address) in addresses.iter().enumerate() {
-        let users: Vec<_> = (0..POOL_USERS).filter(|&x| x != i).collect();
+    let all_users = MemberSet::full(params.users);
 
-        let key = users.clone();
+    for (i, member) in members.iter().enumerate() {
+        let users = all_users.without(i);
 
-        let triple_spend_info = &second_pool_addresses[&key];
+        let triple_spend_info = &second_pool_addresses[&users];
 
-        let addr = Address::p2tr_tweaked(triple_spend_info.output_key(), config.network);
-        let ctv_hash = create_withdraw_ctv_hash(&addr, address, anchor_addr, pool_exit_ammount);
-        entry_pool_withdraw_hashes.push(ctv_hash);
+        let addr = Address::p2tr_tweaked(triple_spend_info.output_key(), network);
+        let ctv_hash = create_withdraw_ctv_hash(
+            params,
+            &addr,
+            &member.withdraw_address,
+            anchor_addr,
+            total_deposits(members, &users),
+            member.deposit,
+        );
+        entry_pool_leaves.push(exit_leaf_script(ctv_hash, member.exit_key));
     }
 
-    entry_pool_withdraw_hashes
+    entry_pool_leaves.extend(split_leaf(params, members, anchor_addr, &all_users));
+
+    entry_pool_leaves
+}
+
+//the last 2 users exit together, either of them can take the exit so each gets their own leaf
+//(it's one leaf if neither has an exit key)
+fn final_exit_leaves(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    i: usize,
+    j: usize,
+) -> Vec<ScriptBuf> {
+    let ctv_hash = create_withdraw_ctv_hash(
+        params,
+        &members[i].withdraw_address,
+        &members[j].withdraw_address,
+        anchor_addr,
+        members[i].deposit,
+        members[j].deposit,
+    );
+
+    let mut leaves = vec![
+        exit_leaf_script(ctv_hash, members[i].exit_key),
+        exit_leaf_script(ctv_hash, members[j].exit_key),
+    ];
+    leaves.dedup();
+    leaves
 }
 
 pub fn create_exit_pool(
-    addresses: &[Address],
+    params: &PoolParams,
+    members: &[PoolMember],
     anchor_addr: &Address,
-) -> Result<HashMap<Vec<usize>, TaprootSpendInfo>> {
-    let combinations: Vec<_> = (0..POOL_USERS).combinations(2).collect();
-    
-    let exit_pool: Result<HashMap<Vec<usize>, TaprootSpendInfo>> = combinations
+) -> Result<HashMap<MemberSet, TaprootSpendInfo>> {
+    let combinations: Vec<_> = (0..params.users).combinations(2).collect();
+
+    let exit_pool: Result<HashMap<MemberSet, TaprootSpendInfo>> = combinations
         .into_par_iter()
-        .map(|mut combo| {
-            combo.sort();
-            let i = combo[0];
-            let j = combo[1];
-
-            let ctv_hash = create_withdraw_ctv_hash(
-                &addresses[i],
-                &addresses[j],
-                anchor_addr,
-                AMOUNT_PER_USER,
-            );
+        .map(|combo| {
+            let leaves = final_exit_leaves(params, members, anchor_addr, combo[0], combo[1]);
 
-            let spend_info = create_pool_address(vec![ctv_hash])?;
+            let users: MemberSet = combo.into_iter().collect();
+            let spend_info = create_pool_address(leaves, state_internal_key(members, &users)?)?;
 
-            Ok((combo, spend_info))
+            Ok((users, spend_info))
         })
         .collect();
 
     exit_pool
 }
 
-pub fn create_pool(
-    target_pool: &HashMap<Vec<usize>, TaprootSpendInfo>,
-    pool_size: usize,
-    addresses: &[Address],
+//leaf scripts for every exit of the pool state holding users, child_addr gives the address
+//of the pool state left behind when one user exits. The last 2 users exit together
+pub fn create_pool_state_leaves(
+    params: &PoolParams,
+    members: &[PoolMember],
     anchor_addr: &Address,
-    config: &NetworkConfig,
-) -> HashMap<Vec<usize>, TaprootSpendInfo> {
-    let mut new_pool: HashMap<Vec<usize>, TaprootSpendInfo> = HashMap::new();
+    users: &MemberSet,
+    mut child_addr: impl FnMut(&MemberSet) -> Result<Address>,
+) -> Result<Vec<ScriptBuf>> {
+    if users.len() == 2 {
+        let (i, j) = users.iter().collect_tuple().unwrap();
+        return Ok(final_exit_leaves(params, members, anchor_addr, i, j));
+    }
+
+    let mut leaves = Vec::new();
+
+    for user in users.iter() {
+        let remaining_users = users.without(user);
 
-    let num_users = addresses.len();
-    info!("Creating addresses for {} user pool \n", pool_size);
+        let withdrawal_address = child_addr(&remaining_users)?;
+        let ctv_hash = create_withdraw_ctv_hash(
+            params,
+            &withdrawal_address,
+            &members[user].withdraw_address,
+            anchor_addr,
+            total_deposits(members, &remaining_users),
+            members[user].deposit,
+        );
 
-    //iterate over all possible spending combinations of users in the pool
-    for users in (0..num_users).combinations(pool_size) {
-        let mut ctv_hashes = Vec::new();
+        leaves.push(exit_leaf_script(ctv_hash, members[user].exit_key));
+    }
 
-        for &user in &users {
-            let remaining_users: Vec<_> = users.iter().copied().filter(|&u| u != user).collect();
-            let spend_info = &target_pool[&remaining_users];
+    leaves.extend(split_leaf(params, members, anchor_addr, users));
 
-            let withdrawal_address = Address::p2tr_tweaked(spend_info.output_key(), config.network);
-            let ctv_hash = create_withdraw_ctv_hash(
-                &withdrawal_address,
-                &addresses[user],
-                anchor_addr,
-                (AMOUNT_PER_USER) * remaining_users.len().try_into().unwrap(),
-            );
+    Ok(leaves)
+}
 
-            ctv_hashes.push(ctv_hash);
-        }
+//the template every leaf of the pool state holding users commits to (the exits, then the split),
+//child_addr gives the address of the pool state left behind when one user exits
+pub fn create_pool_state_templates(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    users: &MemberSet,
+    mut child_addr: impl FnMut(&MemberSet) -> Result<Address>,
+) -> Result<Vec<CtvTemplate>> {
+    let mut templates = Vec::new();
+
+    if users.len() == 2 {
+        let (i, j) = users.iter().collect_tuple().unwrap();
+        let outputs = withdraw_outputs(
+            params,
+            &members[i].withdraw_address,
+            &members[j].withdraw_address,
+            anchor_addr,
+            members[i].deposit,
+            members[j].deposit,
+        );
+        templates.push(CtvTemplate::pool_spend(
+            outputs,
+            Sequence::ENABLE_RBF_NO_LOCKTIME,
+        ));
+        return Ok(templates);
+    }
+
+    for user in users.iter() {
+        let remaining_users = users.without(user);
+
+        let outputs = withdraw_outputs(
+            params,
+            &child_addr(&remaining_users)?,
+            &members[user].withdraw_address,
+            anchor_addr,
+            total_deposits(members, &remaining_users),
+            members[user].deposit,
+        );
+        templates.push(CtvTemplate::pool_spend(
+            outputs,
+            Sequence::ENABLE_RBF_NO_LOCKTIME,
+        ));
+    }
+
+    if has_split_leaf(params, users) {
+        templates.push(CtvTemplate::pool_spend(
+            split_outputs(params, members, anchor_addr, users),
+            split_sequence(params),
+        ));
+    }
+
+    Ok(templates)
+}
 
-        let spend_info = create_pool_address(ctv_hashes).unwrap();
-        new_pool.insert(users, spend_info);
+//every member of users gets their deposit back in one tx, the fee is split evenly between them
+pub fn split_outputs(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    users: &MemberSet,
+) -> Vec<TxOut> {
+    let fee_share = Amount::from_sat(params.exit_fee().to_sat().div_ceil(users.len() as u64));
+
+    let mut outputs: Vec<TxOut> = users
+        .iter()
+        .map(|u| TxOut {
+            value: members[u].deposit - fee_share,
+            script_pubkey: members[u].withdraw_address.script_pubkey(),
+        })
+        .collect();
+
+    outputs.extend(anchor_txout(anchor_addr, fee_share * users.len() as u64));
+
+    outputs
+}
+
+//the split tx can only be mined split_timeout blocks after the pool output it spends. ctv commits
+//to the sequence and BIP68 enforces it as a relative timelock, so the leaf doesn't need OP_CSV
+pub fn split_sequence(params: &PoolParams) -> Sequence {
+    Sequence::from_height(params.split_timeout)
+}
+
+//states with 2 members already split in their exit leaf so they don't get a split leaf
+fn has_split_leaf(params: &PoolParams, users: &MemberSet) -> bool {
+    params.split_timeout != 0 && users.len() > 2
+}
+
+//timelocked leaf splitting the pool state between all its members at once so nobody is stuck if
+//the others stop exiting
+fn split_leaf(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    users: &MemberSet,
+) -> Option<ScriptBuf> {
+    if !has_split_leaf(params, users) {
+        return None;
     }
 
-    new_pool
+    let outputs = split_outputs(params, members, anchor_addr, users);
+    let ctv_hash = calc_ctv_hash(&outputs, Some(split_sequence(params).0));
+    Some(ctv_script(ctv_hash))
+}
+
+//split the pool state holding users between its members through the timelocked leaf
+pub fn send_split(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    users: &MemberSet,
+    pool_spend_info: &TaprootSpendInfo,
+    previous_output: OutPoint,
+) -> Result<LeafSpend> {
+    let split_leaf = split_leaf(params, members, anchor_addr, users)
+        .ok_or_else(|| anyhow!("pool state {:?} has no split leaf", users))?;
+
+    let unsigned_tx = Transaction {
+        version: transaction::Version(TX_VERSION),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            sequence: split_sequence(params),
+            ..Default::default()
+        }],
+        output: split_outputs(params, members, anchor_addr, users),
+    };
+
+    let prevout = TxOut {
+        value: total_deposits(members, users),
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(pool_spend_info.output_key()),
+    };
+
+    Ok(LeafSpend {
+        unsigned_tx,
+        spend_info: pool_spend_info.clone(),
+        leaf_script: split_leaf,
+        prevout,
+    })
+}
+
+//how often create_pool logs how far it has got, as a fraction of the states in the pool
+const PROGRESS_STEPS: usize = 10;
+
+pub fn create_pool(
+    params: &PoolParams,
+    target_pool: &HashMap<MemberSet, TaprootSpendInfo>,
+    pool_size: usize,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    network: Network,
+) -> Result<HashMap<MemberSet, TaprootSpendInfo>> {
+    let num_users = members.len();
+    let total_states = binomial(num_users, pool_size);
+    info!(
+        "Creating {} addresses for {} user pool \n",
+        total_states, pool_size
+    );
+
+    let done = AtomicUsize::new(0);
+    let progress_step = (total_states / PROGRESS_STEPS).max(1);
+
+    //iterate over all possible spending combinations of users in the pool, every state only reads
+    //the previous (smaller) pool so they can all be built at the same time
+    let new_pool = (0..num_users)
+        .combinations(pool_size)
+        .par_bridge()
+        .map(|combo| {
+            let users: MemberSet = combo.into_iter().collect();
+            let leaves = create_pool_state_leaves(
+                params,
+                members,
+                anchor_addr,
+                &users,
+                |remaining_users| {
+                    let spend_info = target_pool.get(remaining_users).ok_or_else(|| {
+                        anyhow!("missing pool state {:?} for {:?}", remaining_users, users)
+                    })?;
+                    Ok(Address::p2tr_tweaked(spend_info.output_key(), network))
+                },
+            )?;
+
+            let spend_info = create_pool_address(leaves, state_internal_key(members, &users)?)?;
+
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if done.is_multiple_of(progress_step) && done < total_states {
+                info!(
+                    "{} user pool: {}/{} addresses \n",
+                    pool_size, done, total_states
+                );
+            }
+
+            Ok((users, spend_info))
+        })
+        .collect::<Result<HashMap<MemberSet, TaprootSpendInfo>>>()?;
+
+    Ok(new_pool)
+}
+
+fn binomial(n: usize, k: usize) -> usize {
+    (0..k).fold(1, |acc, i| acc * (n - i) / (i + 1))
 }
 
 pub fn create_all_pools(
-    addresses: &[Address],
+    params: &PoolParams,
+    members: &[PoolMember],
     anchor_addr: &Address,
-    config: &NetworkConfig,
-    pools: &mut Vec<HashMap<Vec<usize>, TaprootSpendInfo>>,
-) {
-    for pool_num in (1..=POOL_USERS).rev() {
-        let users_in_pool = POOL_USERS - pool_num;
+    network: Network,
+    pools: &mut Vec<HashMap<MemberSet, TaprootSpendInfo>>,
+) -> Result<()> {
+    for pool_num in (1..=params.users).rev() {
+        let users_in_pool = params.users - pool_num;
 
         if users_in_pool < 3 {
             continue;
@@ -123,61 +364,47 @@ pub fn create_all_pools(
 
         let previous_pool = pools.last().unwrap();
 
-        let new_pool = create_pool(previous_pool, users_in_pool, addresses, anchor_addr, config);
+        let new_pool = create_pool(
+            params,
+            previous_pool,
+            users_in_pool,
+            members,
+            anchor_addr,
+            network,
+        )?;
 
         pools.push(new_pool);
     }
+
+    Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn send_from_pool(
-    pools: &[HashMap<Vec<usize>, TaprootSpendInfo>],
-    config: &NetworkConfig,
-    pool_num: usize,
-    pool_combo: Vec<usize>,
-    withdraw_address: Address,
+    params: &PoolParams,
+    network: Network,
+    pool_spend_info: &TaprootSpendInfo,
+    previous_pool_spend_info: &TaprootSpendInfo,
+    member: &PoolMember,
     anchor_addr: &Address,
     pool_exit_ammount: Amount,
-    previous_txid: Txid,
-    previous_pool_combo: Vec<usize>,
-    vout: u32,
-) -> String {
-    info!(
-        "init withdrawal from pool {}, combo: {:?} \n",
-        pool_num, pool_combo
-    );
-    let withdraw_hash = create_withdraw_ctv_hash(
-        &Address::p2tr_tweaked(pools[pool_num][&pool_combo].output_key(), config.network),
-        &withdraw_address,
+    previous_output: OutPoint,
+) -> LeafSpend {
+    let pool_addr = Address::p2tr_tweaked(pool_spend_info.output_key(), network);
+
+    //the same outputs the exit leaf commits to
+    let tx_out = withdraw_outputs(
+        params,
+        &pool_addr,
+        &member.withdraw_address,
         anchor_addr,
         pool_exit_ammount,
+        member.deposit,
     );
-
-    let tx_out = [
-        TxOut {
-            value: pool_exit_ammount,
-            script_pubkey: Address::p2tr_tweaked(
-                pools[pool_num][&pool_combo].output_key(),
-                config.network,
-            )
-            .script_pubkey(),
-        },
-        TxOut {
-            value: AMOUNT_PER_USER - FEE_AMOUNT,
-            script_pubkey: withdraw_address.script_pubkey(),
-        },
-        #[cfg(feature = "regtest")]
-        TxOut {
-            value: FEE_AMOUNT,
-            script_pubkey: anchor_addr.script_pubkey(),
-        },
-    ];
+    let withdraw_hash = calc_ctv_hash(&tx_out, None);
 
     let inputs = vec![TxIn {
-        previous_output: OutPoint {
-            txid: previous_txid,
-            vout,
-        },
+        previous_output,
         sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
         ..Default::default()
     }];
@@ -186,228 +413,927 @@ pub fn send_from_pool(
         version: transaction::Version(TX_VERSION),
         lock_time: absolute::LockTime::ZERO,
         input: inputs,
-        output: tx_out.to_vec(),
+        output: tx_out,
     };
 
-    let parent_tx = spend_ctv(
-        unsigned_tx,
-        pools[pool_num + 1][&previous_pool_combo].clone(),
-        withdraw_hash,
-    );
+    let prevout = TxOut {
+        value: pool_exit_ammount + member.deposit,
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(previous_pool_spend_info.output_key()),
+    };
 
-    let parent_serialized_tx = serialize_hex(&parent_tx);
-    info!(
-        "withdrawal from pool {}, parent tx: {} \n",
-        pool_num, parent_serialized_tx
-    );
-    parent_serialized_tx
+    LeafSpend {
+        unsigned_tx,
+        spend_info: previous_pool_spend_info.clone(),
+        leaf_script: exit_leaf_script(withdraw_hash, member.exit_key),
+        prevout,
+    }
 }
 
+//create final exit tx for the last two users in the pool, exit_combo is sorted and spender_index is
+//whichever of them takes the exit
 #[allow(clippy::too_many_arguments)]
-pub fn process_pool_spend(
-    pools: &[HashMap<Vec<usize>, TaprootSpendInfo>],
-    config: &NetworkConfig,
-    rpc: &Client,
+pub fn send_final_exit(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    exit_combo: &MemberSet,
     spender_index: usize,
-    addresses: &[Address],
+    exit_pool_spend_info: &TaprootSpendInfo,
+    previous_output: OutPoint,
+) -> LeafSpend {
+    let (first, second) = exit_combo
+        .iter()
+        .map(|user| &members[user])
+        .collect_tuple()
+        .unwrap();
+
+    //the user who waits to leave last gets some extra sats! first takes the place of the next
+    //pool, so only second pays the fee
+    let last_pool_tx_out = withdraw_outputs(
+        params,
+        &first.withdraw_address,
+        &second.withdraw_address,
+        anchor_addr,
+        first.deposit,
+        second.deposit,
+    );
+    let last_pool_withdraw_hash = calc_ctv_hash(&last_pool_tx_out, None);
+
+    let inputs = vec![TxIn {
+        previous_output,
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        ..Default::default()
+    }];
+
+    let unsigned_tx = Transaction {
+        version: transaction::Version(TX_VERSION),
+        lock_time: absolute::LockTime::ZERO,
+        input: inputs,
+        output: last_pool_tx_out,
+    };
+
+    let prevout = TxOut {
+        value: first.deposit + second.deposit,
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(exit_pool_spend_info.output_key()),
+    };
+
+    LeafSpend {
+        unsigned_tx,
+        spend_info: exit_pool_spend_info.clone(),
+        leaf_script: exit_leaf_script(last_pool_withdraw_hash, members[spender_index].exit_key),
+        prevout,
+    }
+}
+
+//the output of previous_txid holding the pool state with the remaining members
+fn find_pool_output(
+    pool: &PaymentPool,
+    chain: &dyn ChainBackend,
+    remaining: &MemberSet,
     previous_txid: Txid,
-    anchor_addr: &Address,
-    mining_address: &Address,
-) -> Result<Txid> {
-    let pool_amount = (AMOUNT_PER_USER) * (POOL_USERS - spender_index).try_into()?;
+) -> Result<OutPoint> {
+    let pool_amount = pool.pool_amount(remaining);
+    //a withdraw or change output can have the same value, so match the state's script as well
+    let pool_script = ScriptBuf::new_p2tr_tweaked(pool.spend_info(remaining)?.output_key());
 
-    let previous_tx: Transaction = rpc.get_raw_transaction(&previous_txid, None).unwrap();
+    let previous_tx = chain.get_tx(previous_txid)?;
 
     let vout = previous_tx
         .output
         .iter()
-        .position(|vout| vout.value == pool_amount)
-        .unwrap() as u32;
+        .position(|vout| vout.value == pool_amount && vout.script_pubkey == pool_script)
+        .ok_or_else(|| {
+            anyhow!(
+                "no pool output of {} for users {:?} in {}",
+                pool_amount,
+                remaining,
+                previous_txid
+            )
+        })? as u32;
 
-    //create final exit tx for last two users
-    if spender_index == POOL_USERS - 2 {
-        let last_index = addresses.len() - 1;
-        let second_last_index = last_index - 1;
+    Ok(OutPoint {
+        txid: previous_txid,
+        vout,
+    })
+}
 
-        let last_pool_withdraw_hash = create_withdraw_ctv_hash(
-            &addresses[second_last_index],
-            &addresses[last_index],
-            anchor_addr,
-            AMOUNT_PER_USER,
-        );
+pub fn process_pool_spend(
+    pool: &PaymentPool,
+    chain: &dyn ChainBackend,
+    remaining: &MemberSet,
+    spender_index: usize,
+    previous_txid: Txid,
+    signer: Option<&ExitSigner>,
+) -> Result<Txid> {
+    let previous_output = find_pool_output(pool, chain, remaining, previous_txid)?;
 
-        let last_pool_tx_out = [
-            TxOut {
-                //the user who waits to leave last gets some extra sats!
-                value: AMOUNT_PER_USER,
-                script_pubkey: addresses[second_last_index].script_pubkey(),
-            },
-            TxOut {
-                value: AMOUNT_PER_USER - FEE_AMOUNT,
-                script_pubkey: addresses[last_index].script_pubkey(),
-            },
-            #[cfg(feature = "regtest")]
-            TxOut {
-                value: FEE_AMOUNT,
-                script_pubkey: anchor_addr.script_pubkey(),
-            },
-        ];
-
-        let inputs = vec![TxIn {
-            previous_output: OutPoint {
-                txid: previous_txid,
-                vout,
-            },
-            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-            ..Default::default()
-        }];
+    let exit_tx = pool.exit_tx(remaining, spender_index, previous_output, signer)?;
+    //catch a bad exit before the node does
+    pool.verify_spend(&exit_tx, remaining)?;
 
-        let unsigned_tx = Transaction {
-            version: transaction::Version(TX_VERSION),
-            lock_time: absolute::LockTime::ZERO,
-            input: inputs,
-            output: last_pool_tx_out.to_vec(),
-        };
+    let serialized_tx = serialize_hex(&exit_tx);
+    info!(
+        "withdrawal for user {}, parent tx: {} \n",
+        spender_index, serialized_tx
+    );
 
-        let final_tx = spend_ctv(
-            unsigned_tx,
-            pools[0][&vec![second_last_index, last_index]].clone(),
-            last_pool_withdraw_hash,
-        );
+    let withdraw_parent_txid = broadcast_pool_spend(chain, pool.anchor_addr(), &exit_tx)?;
+    info!("{} parent txid: {} \n", spender_index, withdraw_parent_txid);
 
-        let serialized_tx = serialize_hex(&final_tx);
-        info!("Final exit tx: {} \n", serialized_tx);
-        let txid = rpc.send_raw_transaction(serialized_tx)?;
-        info!("Final exit txid: {} \n", txid);
+    Ok(withdraw_parent_txid)
+}
 
-        #[cfg(feature = "regtest")]
-        let _ = rpc.generate_to_address(1, mining_address);
+//every member of the pool state holding remaining signs one key path spend paying everyone out,
+//seckeys holds the musig secret key of every user in the pool
+pub fn process_cooperative_close(
+    pool: &PaymentPool,
+    chain: &dyn ChainBackend,
+    remaining: &MemberSet,
+    seckeys: &[SecretKey],
+    previous_txid: Txid,
+) -> Result<Txid> {
+    let previous_output = find_pool_output(pool, chain, remaining, previous_txid)?;
 
-        #[cfg(feature = "regtest")]
-        cpfp_tx(rpc, txid);
+    let spend = CooperativeSpend::batch_exit(pool, remaining, previous_output)?;
 
-        #[cfg(feature = "regtest")]
-        let _ = rpc.generate_to_address(1, mining_address);
+    //round 1, every member makes a nonce and shares the public part
+    let secnonces = remaining
+        .iter()
+        .map(|u| spend.nonce(seckeys[u], rand::random()))
+        .collect::<Result<Vec<_>>>()?;
+    let pub_nonces: Vec<_> = secnonces.iter().map(|nonce| nonce.public_nonce()).collect();
 
-        return Ok(txid);
-    }
+    //round 2, every member signs once they have seen every nonce
+    let partial_signatures = remaining
+        .iter()
+        .zip(secnonces)
+        .map(|(u, secnonce)| spend.sign(seckeys[u], secnonce, &pub_nonces))
+        .collect::<Result<Vec<_>>>()?;
 
-    let pool_exit_amount = (AMOUNT_PER_USER) * (POOL_USERS - spender_index - 1).try_into()?;
+    let close_tx = spend.finalize(&pub_nonces, &partial_signatures)?;
 
-    let recipient_pool: Vec<usize> = ((spender_index + 1)..POOL_USERS).collect();
+    let serialized_tx = serialize_hex(&close_tx);
+    info!(
+        "cooperative close for users {:?}, parent tx: {} \n",
+        remaining, serialized_tx
+    );
 
-    let previous_pool: Vec<usize> = if spender_index == 0 {
-        vec![(0)]
-    } else {
-        (spender_index..POOL_USERS).collect()
-    };
+    let close_txid = broadcast_pool_spend(chain, pool.anchor_addr(), &close_tx)?;
+    info!("cooperative close txid: {} \n", close_txid);
 
-    let pool_num = pools.len() - 2 - spender_index;
+    Ok(close_txid)
+}
 
-    info!("previous pool: {:?}", previous_pool);
+//once nobody has exited for split_timeout blocks, anyone can split the pool between the remaining
+//members. Errors while the pool output has fewer confirmations than that, BIP68 keeps the split out
+//of blocks until then
+pub fn process_pool_split(
+    pool: &PaymentPool,
+    chain: &dyn ChainBackend,
+    remaining: &MemberSet,
+    previous_txid: Txid,
+) -> Result<Txid> {
+    let previous_output = find_pool_output(pool, chain, remaining, previous_txid)?;
+
+    let split_tx = pool.split_tx(remaining, previous_output)?;
+    pool.verify_spend(&split_tx, remaining)?;
+
+    let split_timeout = pool.params().split_timeout;
+    let confirmations = chain.tx_confirmations(previous_txid)?;
+    if confirmations < split_timeout.into() {
+        bail!(
+            "the pool output {} has {} of the {} confirmations the split needs, it can go out in {} blocks",
+            previous_output,
+            confirmations,
+            split_timeout,
+            u32::from(split_timeout) - confirmations
+        );
+    }
 
-    let withdraw_parent_serialized_tx = send_from_pool(
-        pools,
-        config,
-        pool_num,
-        recipient_pool,
-        addresses[spender_index].clone(),
-        anchor_addr,
-        pool_exit_amount,
-        previous_txid,
-        previous_pool,
-        vout,
+    let serialized_tx = serialize_hex(&split_tx);
+    info!(
+        "timeout split for users {:?}, parent tx: {} \n",
+        remaining, serialized_tx
     );
 
-    let withdraw_parent_txid = rpc.send_raw_transaction(withdraw_parent_serialized_tx)?;
-    info!("{} parent txid: {} \n", spender_index, withdraw_parent_txid);
+    let split_txid = broadcast_pool_spend(chain, pool.anchor_addr(), &split_tx)?;
+    info!("timeout split txid: {} \n", split_txid);
+
+    Ok(split_txid)
+}
 
-    //use p2a for fee management on regtest (i was having trouble with v3 transactions propagating on signet)
-    #[cfg(feature = "regtest")]
-    let _ = rpc.generate_to_address(1, mining_address);
+//broadcast the next split on member's path in a split tree pool, nodes holds the unspent nodes and
+//their outpoints and picks up the ones the split leaves behind. The split before has to confirm
+//before the next one can spend it, so call again once it has. None once member has been paid out
+pub fn process_tree_split(
+    pool: &PaymentPool,
+    chain: &dyn ChainBackend,
+    nodes: &mut HashMap<MemberSet, OutPoint>,
+    member: usize,
+) -> Result<Option<Txid>> {
+    let Some((node, split_tx)) = pool.tree_next_split(nodes, member)? else {
+        return Ok(None);
+    };
+    pool.verify_spend(&split_tx, &node)?;
 
-    #[cfg(feature = "regtest")]
-    cpfp_tx(rpc, withdraw_parent_txid);
+    let serialized_tx = serialize_hex(&split_tx);
+    info!(
+        "tree split of users {:?} for user {}, parent tx: {} \n",
+        node, member, serialized_tx
+    );
 
-    #[cfg(feature = "regtest")]
-    let _ = rpc.generate_to_address(1, mining_address);
+    let split_txid = broadcast_pool_spend(chain, pool.anchor_addr(), &split_tx)?;
+    info!("{} tree split txid: {} \n", member, split_txid);
 
-    Ok(withdraw_parent_txid)
+    Ok(Some(split_txid))
 }
 
-pub fn cpfp_tx(rpc: &Client, parent_txid: Txid) {
-    info!("Spending child transaction...");
+//With p2a the pool spends pay no fee themselves, a child spending the parent pays for both.
+//Parent and child go in as one package (v3 parents with no fee need package relay), if the
+//backend can't take packages they are broadcast one at a time, which works when the node relays
+//the parent on its own. Without p2a the pool spends already pay their fee
+pub fn broadcast_pool_spend(
+    chain: &dyn ChainBackend,
+    anchor_addr: &Address,
+    parent: &Transaction,
+) -> Result<Txid> {
+    if cfg!(feature = "p2a") {
+        submit_with_child(chain, anchor_addr, parent)
+    } else {
+        chain.broadcast(parent)
+    }
+}
 
-    let change_address = rpc.get_raw_change_address(None).unwrap();
+pub fn submit_with_child(
+    chain: &dyn ChainBackend,
+    anchor_addr: &Address,
+    parent: &Transaction,
+) -> Result<Txid> {
+    let child = cpfp_child(chain, anchor_addr, parent)?;
+    let parent_txid = parent.compute_txid();
+
+    let package_error = match chain.submit_package(&[parent.clone(), child.clone()]) {
+        Ok(_) => {
+            info!(
+                "package submitted, parent: {} child: {} \n",
+                parent_txid,
+                child.compute_txid()
+            );
+            return Ok(parent_txid);
+        }
+        Err(e) => e,
+    };
 
-    let input_size = 68; // SegWit input size
-    let output_size = 34; // SegWit output size
-    let fixed_overhead = 10; // Version, locktime, and input/output count
+    warn!(
+        "package rejected, broadcasting parent and child one at a time: {:#} \n",
+        package_error
+    );
+    chain.broadcast(parent).with_context(|| {
+        format!(
+            "parent {} rejected on its own after the package was rejected ({:#})",
+            parent_txid, package_error
+        )
+    })?;
+
+    //the parent is in the mempool, without the child it just waits for a fee bump
+    match chain.broadcast(&child) {
+        Ok(child_txid) => info!("\nchild txid: {}", child_txid),
+        Err(e) => warn!("child of {} rejected: {:#} \n", parent_txid, e),
+    }
 
-    let estimated_tx_size = (input_size) + (output_size) + fixed_overhead;
+    Ok(parent_txid)
+}
 
-    let fee_rate = rpc
-        .estimate_smart_fee(1, None)
+//a child spending the parent's anchor and confirmed wallet utxos that brings the package up to the
+//backend's fee estimate. The change goes back to a utxo's own script so the backend doesn't need
+//to hand out addresses
+pub fn cpfp_child(
+    chain: &dyn ChainBackend,
+    anchor_addr: &Address,
+    parent: &Transaction,
+) -> Result<Transaction> {
+    let fee_rate = chain
+        .estimate_fee_rate(1)
         .ok()
-        .and_then(|estimate| estimate.fee_rate.map(|rate| rate.to_sat()))
+        .flatten()
         .unwrap_or(DEFAULT_FEE_RATE);
+    cpfp_child_at(chain, anchor_addr, parent, fee_rate)
+}
 
-    let total_fee = fee_rate * estimated_tx_size / 1000;
+//cpfp_child for a package feerate of fee_rate sat/kvB, e.g. to bump a child that pays too little
+pub fn cpfp_child_at(
+    chain: &dyn ChainBackend,
+    anchor_addr: &Address,
+    parent: &Transaction,
+    fee_rate: u64,
+) -> Result<Transaction> {
+    //a backend without a wallet (Esplora) can't fund or sign the child, it says so before anything
+    //is built
+    let wallet_utxos = chain.wallet_utxos()?;
 
-    let unspent = rpc.list_unspent(Some(1), None, None, None, None).unwrap();
+    //with an ephemeral anchor the 0 sat output has to be spent in the same package, so the child
+    //always spends the anchor wherever it sits in the parent
+    let anchor = anchor_output(parent, anchor_addr)?;
 
-    let matching_utxo = unspent
-        .into_iter()
-        .find(|utxo| utxo.amount >= Amount::from_sat(total_fee))
-        .unwrap();
+    let parent_fee = parent_fee(chain, parent)?;
 
-    let op_return_script = Builder::new()
-        .push_opcode(OP_RETURN)
-        .push_slice(b"\xe2\x9a\x93 \xF0\x9F\xA5\xAA \xe2\x9a\x93")
-        .into_script();
+    let plan = plan_child(parent, parent_fee, anchor, wallet_utxos, fee_rate)?;
 
-    let child_spend = Transaction {
-        version: transaction::Version(TX_VERSION),
-        lock_time: absolute::LockTime::ZERO,
-        input: vec![
-            TxIn {
-                previous_output: OutPoint {
-                    txid: parent_txid,
-                    vout: 1,
-                },
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+    info!(
+        "child pays {} sat for a {} vB package at {} sat/kvB \n",
+        plan.fee.to_sat(),
+        plan.package_vsize,
+        plan.package_fee_rate(parent_fee)
+    );
+    info!("\nchild tx: {}", serialize_hex(&plan.tx));
+
+    //the parent isn't broadcast yet, so the wallet can't look the anchor up by itself
+    chain.sign_wallet_tx(plan.tx, &plan.prevouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        hashes::Hash,
+        key::Keypair,
+        secp256k1::{schnorr, Message},
+        TapSighash,
+    };
+    use rand::thread_rng;
+
+    use super::*;
+    #[cfg(feature = "p2a")]
+    use crate::chain::{NoWallet, Utxo};
+    use crate::{
+        chain::MockChain,
+        ctv_scripts::SECP,
+        test_utils::{funded_chain, test_address, test_anchor, test_members, test_pool},
+        verify::CtvVerifyError,
+    };
+    #[cfg(feature = "p2a")]
+    use crate::{config::FeeStrategy, cpfp::package_fee_rate};
+
+    fn four_users() -> PoolParams {
+        PoolParams {
+            users: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_the_pool_output_next_to_one_of_the_same_value() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let users = MemberSet::full(4);
+        let chain = MockChain::new();
+        let funded = chain.fund(&test_address(), pool.funding_amount() * 2);
+
+        //a decoy paying the pool amount somewhere else comes first
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: funded,
                 ..Default::default()
-            },
-            TxIn {
-                previous_output: OutPoint {
-                    txid: matching_utxo.txid,
-                    vout: matching_utxo.vout,
+            }],
+            output: vec![
+                TxOut {
+                    value: pool.funding_amount(),
+                    script_pubkey: test_address().script_pubkey(),
                 },
-                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-                ..Default::default()
-            },
-        ],
-        output: vec![
-            TxOut {
-                value: Amount::from_sat(0),
-                script_pubkey: op_return_script,
-            },
-            TxOut {
-                value: matching_utxo.amount - Amount::from_sat(total_fee),
-                script_pubkey: change_address.assume_checked().script_pubkey(),
-            },
-        ],
-    };
+                TxOut {
+                    value: pool.funding_amount(),
+                    script_pubkey: pool.funding_address().unwrap().script_pubkey(),
+                },
+            ],
+        };
+        let txid = chain.broadcast(&tx).unwrap();
+
+        let outpoint = find_pool_output(&pool, &chain, &users, txid).unwrap();
+        assert_eq!(outpoint, OutPoint { txid, vout: 1 });
+    }
+
+    #[test]
+    fn exit_txs_match_the_leaves_they_spend() {
+        let pool = test_pool(four_users(), &[11000, 25000, 15000, 20000]);
+        let mut remaining = MemberSet::full(4);
+        let mut previous_output = OutPoint::null();
+
+        //every exit down to the final one, each with different deposits on both sides
+        for member in [2, 0, 3] {
+            let tx = pool
+                .exit_tx(&remaining, member, previous_output, None)
+                .unwrap();
+            pool.verify_spend(&tx, &remaining).unwrap();
+            assert_eq!(
+                tx.output[1].value,
+                pool.members()[member].deposit - pool.params().exit_fee()
+            );
+
+            previous_output = OutPoint {
+                txid: tx.compute_txid(),
+                vout: 0,
+            };
+            remaining.remove(member);
+        }
+    }
+
+    //every member has an exit key, their keypairs come back in member order
+    fn signed_exit_pool() -> (PaymentPool, Vec<Keypair>) {
+        let keypairs: Vec<Keypair> = (0..4)
+            .map(|_| Keypair::new(&SECP, &mut thread_rng()))
+            .collect();
+        let members = test_members(&[20000; 4])
+            .into_iter()
+            .zip(&keypairs)
+            .map(|(member, keypair)| member.with_exit_key(keypair.x_only_public_key().0))
+            .collect();
+        let pool =
+            PaymentPool::new(four_users(), Network::Regtest, members, test_anchor()).unwrap();
+        (pool, keypairs)
+    }
+
+    //signs with the exit key of whichever member signer_for says
+    fn sign_as(
+        keypairs: &[Keypair],
+        signer_for: fn(usize) -> usize,
+    ) -> impl Fn(usize, TapSighash) -> Result<schnorr::Signature> + '_ {
+        move |member, sighash| {
+            let message = Message::from_digest(sighash.to_byte_array());
+            Ok(SECP.sign_schnorr(&message, &keypairs[signer_for(member)]))
+        }
+    }
+
+    #[test]
+    fn signed_exit_passes_verify_spend() {
+        let (pool, keypairs) = signed_exit_pool();
+        let mut remaining = MemberSet::full(4);
+        let mut previous_output = OutPoint::null();
+        let signer = sign_as(&keypairs, |member| member);
+
+        for member in [1, 3] {
+            let tx = pool
+                .exit_tx(&remaining, member, previous_output, Some(&signer))
+                .unwrap();
+            pool.verify_spend(&tx, &remaining).unwrap();
+
+            previous_output = OutPoint {
+                txid: tx.compute_txid(),
+                vout: 0,
+            };
+            remaining.remove(member);
+        }
+    }
+
+    #[test]
+    fn exit_with_an_exit_key_needs_a_signer() {
+        let (pool, _) = signed_exit_pool();
+        let err = pool
+            .exit_tx(&MemberSet::full(4), 1, OutPoint::null(), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("signature"), "{:#}", err);
+    }
+
+    #[test]
+    fn exit_signed_with_another_members_key_is_rejected() {
+        let (pool, keypairs) = signed_exit_pool();
+        let users = MemberSet::full(4);
+        //member 2 signs member 1's exit
+        let signer = sign_as(&keypairs, |_| 2);
+
+        let tx = pool
+            .exit_tx(&users, 1, OutPoint::null(), Some(&signer))
+            .unwrap();
+        let err = pool.verify_spend(&tx, &users).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CtvVerifyError>(),
+            Some(&CtvVerifyError::InvalidSignature)
+        );
+    }
+
+    //what the chain holds for address, mempool included
+    fn received(chain: &MockChain, address: &Address) -> Amount {
+        chain
+            .unspent_outputs(std::slice::from_ref(address))
+            .unwrap()
+            .iter()
+            .map(|utxo| utxo.txout.value)
+            .sum()
+    }
+
+    #[test]
+    fn members_exit_one_after_the_other() {
+        let pool = test_pool(four_users(), &[11000, 25000, 15000, 20000]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let members = pool.members();
+        let exit_fee = pool.params().exit_fee();
+
+        let mut remaining = MemberSet::full(4);
+        let mut txid = funding_outpoint.txid;
+        for member in [3, 1] {
+            txid = process_pool_spend(&pool, &chain, &remaining, member, txid, None).unwrap();
+            chain.mine(1);
+            remaining.remove(member);
+
+            let exit_tx = chain.get_tx(txid).unwrap();
+            assert_eq!(exit_tx.output[0].value, pool.pool_amount(&remaining));
+            assert_eq!(
+                received(&chain, &members[member].withdraw_address),
+                members[member].deposit - exit_fee
+            );
+        }
 
-    let child_serialized_tx = serialize_hex(&child_spend);
+        //the last 2 leave together, the one who didn't take the exit keeps their whole deposit
+        let final_txid = process_pool_spend(&pool, &chain, &remaining, 2, txid, None).unwrap();
+        chain.mine(1);
+        assert_eq!(
+            received(&chain, &members[2].withdraw_address),
+            members[2].deposit - exit_fee
+        );
+        assert_eq!(
+            received(&chain, &members[0].withdraw_address),
+            members[0].deposit
+        );
 
-    info!("\nchild tx: {}", child_serialized_tx);
+        //either of the last 2 closes the pool with the same tx
+        assert_eq!(
+            process_pool_spend(&pool, &chain, &remaining, 0, txid, None).unwrap(),
+            final_txid
+        );
+    }
 
-    let signed_child_tx = rpc
-        .sign_raw_transaction_with_wallet(child_serialized_tx, None, None)
+    #[test]
+    fn create_pool_builds_every_state_of_a_level() {
+        let params = PoolParams {
+            users: 5,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 5]);
+        let exit_pool = create_exit_pool(&params, pool.members(), pool.anchor_addr()).unwrap();
+
+        //the states are built on rayon's threads, they have to come out the same as the pool's
+        let level = create_pool(
+            &params,
+            &exit_pool,
+            3,
+            pool.members(),
+            pool.anchor_addr(),
+            Network::Regtest,
+        )
         .unwrap();
+        assert_eq!(level.len(), binomial(5, 3));
+        for (users, spend_info) in &level {
+            assert_eq!(users.len(), 3);
+            assert_eq!(
+                spend_info.output_key(),
+                pool.spend_info(users).unwrap().output_key()
+            );
+        }
+    }
+
+    #[test]
+    fn create_pool_returns_a_missing_state_as_an_error() {
+        let params = PoolParams {
+            users: 5,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 5]);
+        let mut exit_pool = create_exit_pool(&params, pool.members(), pool.anchor_addr()).unwrap();
+        let missing: MemberSet = [1, 3].into_iter().collect();
+        exit_pool.remove(&missing);
+
+        let err = create_pool(
+            &params,
+            &exit_pool,
+            3,
+            pool.members(),
+            pool.anchor_addr(),
+            Network::Regtest,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("missing pool state {:?}", missing)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn binomial_counts_the_states_progress_is_reported_against() {
+        assert_eq!(binomial(5, 3), 10);
+        assert_eq!(binomial(21, 10), 352716);
+        assert_eq!(binomial(4, 4), 1);
+        assert_eq!(binomial(4, 0), 1);
+    }
+
+    #[test]
+    fn pool_split_waits_for_the_split_timeout() {
+        let params = PoolParams {
+            users: 4,
+            split_timeout: 10,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let full = MemberSet::full(4);
+
+        //funded_chain confirms the funding tx, so 9 more blocks are still one short
+        chain.mine(8);
+        let err = process_pool_split(&pool, &chain, &full, funding_outpoint.txid).unwrap_err();
+        assert!(
+            err.to_string().contains("has 9 of the 10 confirmations"),
+            "{}",
+            err
+        );
+        assert!(chain.mempool().is_empty());
+
+        chain.mine(1);
+        let split_txid = process_pool_split(&pool, &chain, &full, funding_outpoint.txid).unwrap();
+        chain.mine(1);
+        assert_eq!(chain.tx_confirmations(split_txid).unwrap(), 1);
+    }
+
+    #[test]
+    fn chain_rejects_a_split_before_the_split_timeout() {
+        let params = PoolParams {
+            users: 4,
+            split_timeout: 10,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let full = MemberSet::full(4);
+        let split_tx = pool.split_tx(&full, funding_outpoint).unwrap();
+        assert_eq!(split_tx.input[0].sequence, Sequence::from_height(10));
+
+        //straight to the chain without process_pool_split's check, BIP68 keeps it out
+        chain.mine(8);
+        let err = broadcast_pool_spend(&chain, pool.anchor_addr(), &split_tx).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("after 9 of the 10 blocks its sequence needs"),
+            "{:#}",
+            err
+        );
+
+        chain.mine(1);
+        broadcast_pool_spend(&chain, pool.anchor_addr(), &split_tx).unwrap();
+    }
 
-    let child_txid = rpc.send_raw_transaction(&signed_child_tx.hex).unwrap();
+    #[test]
+    fn split_timeout_of_zero_leaves_out_the_split_leaf() {
+        let params = |split_timeout| PoolParams {
+            users: 4,
+            split_timeout,
+            ..Default::default()
+        };
+        let with_split = test_pool(params(10), &[20000; 4]);
+        let without_split = test_pool(params(0), &[20000; 4]);
+        let full = MemberSet::full(4);
+
+        let leaves = |pool: &PaymentPool| pool.spend_info(&full).unwrap().script_map().len();
+        assert_eq!(leaves(&without_split), leaves(&with_split) - 1);
+
+        let (chain, funding_outpoint) = funded_chain(&without_split);
+        let err = without_split.split_tx(&full, funding_outpoint).unwrap_err();
+        assert!(err.to_string().contains("has no split leaf"), "{}", err);
+        assert!(process_pool_split(&without_split, &chain, &full, funding_outpoint.txid).is_err());
+
+        //the members can still leave one by one
+        process_pool_spend(
+            &without_split,
+            &chain,
+            &full,
+            2,
+            funding_outpoint.txid,
+            None,
+        )
+        .unwrap();
+    }
+
+    //a backend that takes txs one at a time but has no package relay
+    #[cfg(feature = "p2a")]
+    struct NoPackageRelay(MockChain);
+
+    #[cfg(feature = "p2a")]
+    impl ChainBackend for NoPackageRelay {
+        fn get_tx(&self, txid: Txid) -> Result<Transaction> {
+            self.0.get_tx(txid)
+        }
+
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+            self.0.broadcast(tx)
+        }
+
+        fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>> {
+            self.0.find_spend(outpoint)
+        }
+
+        fn submit_package(&self, _txs: &[Transaction]) -> Result<Vec<Txid>> {
+            anyhow::bail!("package relay is not supported")
+        }
+
+        fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>> {
+            self.0.estimate_fee_rate(conf_target)
+        }
 
-    info!("\nchild txid: {}", child_txid);
+        fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>> {
+            self.0.unspent_outputs(addresses)
+        }
+
+        fn wallet_utxos(&self) -> Result<Vec<Utxo>> {
+            self.0.wallet_utxos()
+        }
+
+        fn tip_height(&self) -> Result<u32> {
+            self.0.tip_height()
+        }
+
+        fn tx_confirmations(&self, txid: Txid) -> Result<u32> {
+            self.0.tx_confirmations(txid)
+        }
+
+        fn sign_wallet_tx(
+            &self,
+            tx: Transaction,
+            prevouts: &[(OutPoint, TxOut)],
+        ) -> Result<Transaction> {
+            self.0.sign_wallet_tx(tx, prevouts)
+        }
+    }
+
+    //the child spending the anchor of parent, if the chain has one
+    #[cfg(feature = "p2a")]
+    fn anchor_child(chain: &dyn ChainBackend, parent: &Transaction) -> Option<Transaction> {
+        let anchor_vout = parent
+            .output
+            .iter()
+            .position(|output| output.script_pubkey == ScriptBuf::new_p2a())
+            .unwrap();
+        chain
+            .find_spend(OutPoint {
+                txid: parent.compute_txid(),
+                vout: anchor_vout as u32,
+            })
+            .unwrap()
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn pool_spend_goes_in_a_package_with_its_child() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        chain.set_fee_rate(Some(5000));
+
+        let exit_tx = pool
+            .exit_tx(&MemberSet::full(4), 1, funding_outpoint, None)
+            .unwrap();
+        let txid = submit_with_child(&chain, pool.anchor_addr(), &exit_tx).unwrap();
+        assert_eq!(txid, exit_tx.compute_txid());
+
+        let child = anchor_child(&chain, &exit_tx).expect("anchor isn't spent");
+        let mut mempool = chain.mempool();
+        mempool.sort();
+        let mut expected = vec![txid, child.compute_txid()];
+        expected.sort();
+        assert_eq!(mempool, expected);
+
+        //the child brings the package up to the fee rate
+        let child_fee = child
+            .input
+            .iter()
+            .map(|input| {
+                chain.get_tx(input.previous_output.txid).unwrap().output
+                    [input.previous_output.vout as usize]
+                    .value
+            })
+            .sum::<Amount>()
+            - child
+                .output
+                .iter()
+                .map(|output| output.value)
+                .sum::<Amount>();
+        let package_vsize = (exit_tx.vsize() + child.vsize()) as u64;
+        assert!(
+            (child_fee.to_sat() + pool.params().exit_fee().to_sat()) * 1000 / package_vsize >= 5000
+        );
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn parent_and_child_go_one_at_a_time_without_package_relay() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let chain = NoPackageRelay(chain);
+
+        let exit_tx = pool
+            .exit_tx(&MemberSet::full(4), 2, funding_outpoint, None)
+            .unwrap();
+        submit_with_child(&chain, pool.anchor_addr(), &exit_tx).unwrap();
+
+        assert!(chain.0.confirmations(exit_tx.compute_txid()) == Some(0));
+        assert!(anchor_child(&chain, &exit_tx).is_some());
+    }
+
+    //a backend with no wallet, like Esplora
+    #[cfg(feature = "p2a")]
+    struct NoWalletChain(MockChain);
+
+    #[cfg(feature = "p2a")]
+    impl ChainBackend for NoWalletChain {
+        fn get_tx(&self, txid: Txid) -> Result<Transaction> {
+            self.0.get_tx(txid)
+        }
+
+        fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+            self.0.broadcast(tx)
+        }
+
+        fn find_spend(&self, outpoint: OutPoint) -> Result<Option<Transaction>> {
+            self.0.find_spend(outpoint)
+        }
+
+        fn submit_package(&self, txs: &[Transaction]) -> Result<Vec<Txid>> {
+            self.0.submit_package(txs)
+        }
+
+        fn estimate_fee_rate(&self, conf_target: u16) -> Result<Option<u64>> {
+            self.0.estimate_fee_rate(conf_target)
+        }
+
+        fn unspent_outputs(&self, addresses: &[Address]) -> Result<Vec<Utxo>> {
+            self.0.unspent_outputs(addresses)
+        }
+
+        fn tip_height(&self) -> Result<u32> {
+            self.0.tip_height()
+        }
+
+        fn tx_confirmations(&self, txid: Txid) -> Result<u32> {
+            self.0.tx_confirmations(txid)
+        }
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn backend_without_a_wallet_says_so_before_building_the_child() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let chain = NoWalletChain(chain);
+
+        let exit_tx = pool
+            .exit_tx(&MemberSet::full(4), 1, funding_outpoint, None)
+            .unwrap();
+        let err = broadcast_pool_spend(&chain, pool.anchor_addr(), &exit_tx).unwrap_err();
+        assert_eq!(err.downcast_ref::<NoWallet>(), Some(&NoWallet));
+        assert!(chain.0.mempool().is_empty());
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn package_is_rejected_when_the_parent_is() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+
+        //member 2 already left, the same state can't be spent twice
+        let first = pool
+            .exit_tx(&MemberSet::full(4), 2, funding_outpoint, None)
+            .unwrap();
+        submit_with_child(&chain, pool.anchor_addr(), &first).unwrap();
+        let second = pool
+            .exit_tx(&MemberSet::full(4), 0, funding_outpoint, None)
+            .unwrap();
+
+        assert!(submit_with_child(&chain, pool.anchor_addr(), &second).is_err());
+        assert_eq!(chain.mempool().len(), 2);
+    }
+
+    #[cfg(feature = "p2a")]
+    #[test]
+    fn ephemeral_anchor_parent_is_paid_for_by_its_child() {
+        let params = PoolParams {
+            fee_strategy: FeeStrategy::EphemeralAnchor,
+            ..four_users()
+        };
+        let pool = test_pool(params, &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        chain.set_fee_rate(Some(5000));
+
+        //the exit keeps nothing back for the fee and its anchor is 0 sat
+        let users = MemberSet::full(4);
+        let exit_tx = pool.exit_tx(&users, 1, funding_outpoint, None).unwrap();
+        pool.verify_spend(&exit_tx, &users).unwrap();
+        assert_eq!(exit_tx.output[1].value, pool.members()[1].deposit);
+        let anchor = exit_tx.output.last().unwrap();
+        assert_eq!(anchor.script_pubkey, ScriptBuf::new_p2a());
+        assert_eq!(anchor.value, Amount::ZERO);
+        assert_eq!(parent_fee(&chain, &exit_tx).unwrap(), Amount::ZERO);
+
+        //the 0 sat anchor is dust that only relays when the package spends it
+        assert!(chain.broadcast(&exit_tx).is_err());
+        assert!(chain.mempool().is_empty());
+
+        submit_with_child(&chain, pool.anchor_addr(), &exit_tx).unwrap();
+        assert!(anchor_child(&chain, &exit_tx).is_some());
+        assert!(package_fee_rate(&chain, pool.anchor_addr(), &exit_tx).unwrap() >= 5000);
+    }
 }
@@ -0,0 +1,441 @@
+use anyhow::{anyhow, ensure, Context, Result};
+
+use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, Txid};
+use tracing::info;
+
+use crate::{
+    chain::ChainBackend, config::PoolMode, members::MemberSet, payment_pool::PaymentPool,
+    verify::verify_ctv_spend,
+};
+
+//one spend of a pool state found on chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolSpend {
+    //member left through their exit leaf and everyone else moved to the next pool state
+    Exit { member: usize, txid: Txid },
+    //the last 2 members left together
+    FinalExit { txid: Txid },
+    //the pool was split between the members still in it through the timeout leaf
+    Split { txid: Txid },
+    //a key path spend signed by every member, it can pay anywhere so the pool is gone
+    Cooperative { txid: Txid },
+}
+
+impl PoolSpend {
+    pub fn txid(&self) -> Txid {
+        match self {
+            PoolSpend::Exit { txid, .. }
+            | PoolSpend::FinalExit { txid }
+            | PoolSpend::Split { txid }
+            | PoolSpend::Cooperative { txid } => *txid,
+        }
+    }
+}
+
+//the pool output nobody has spent yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnspentPoolState {
+    pub users: MemberSet,
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveredPool {
+    //every spend from the funding outpoint on, oldest first
+    pub spends: Vec<PoolSpend>,
+    //None once the pool has been closed
+    pub state: Option<UnspentPoolState>,
+}
+
+impl PaymentPool {
+    //find where the pool is now by following its spends forward from the funding outpoint. Each
+    //spend is identified by the leaf of the pool state it satisfies, so nothing but the pool tree
+    //and the chain is needed. Only exact pools have one pool state to follow
+    pub fn recover(
+        &self,
+        chain: &dyn ChainBackend,
+        funding_outpoint: OutPoint,
+    ) -> Result<RecoveredPool> {
+        self.check_funding_output(chain, funding_outpoint)?;
+
+        let mut spends = Vec::new();
+        let mut state = self.funding_state(funding_outpoint);
+        while let Some((spend, next_state)) =
+            self.follow_spend(chain, &state.users, state.outpoint)?
+        {
+            spends.push(spend);
+
+            let Some(next_state) = next_state else {
+                return Ok(RecoveredPool {
+                    spends,
+                    state: None,
+                });
+            };
+            state = next_state;
+        }
+
+        info!(
+            "pool {:?} holds {} at {} after {} exits \n",
+            state.users,
+            state.amount,
+            state.outpoint,
+            spends.len()
+        );
+
+        Ok(RecoveredPool {
+            spends,
+            state: Some(state),
+        })
+    }
+
+    //how the pool state holding users at outpoint was spent and the pool state it left behind
+    //(None if the spend closed the pool), or None while it is unspent. Errors on a spend that is
+    //neither a key path spend nor one of the state's leaves
+    pub fn follow_spend(
+        &self,
+        chain: &dyn ChainBackend,
+        users: &MemberSet,
+        outpoint: OutPoint,
+    ) -> Result<Option<(PoolSpend, Option<UnspentPoolState>)>> {
+        ensure!(
+            self.params().mode == PoolMode::Exact,
+            "a split tree pool doesn't have one pool state to follow, use tree_unspent_node"
+        );
+
+        let Some(tx) = chain.find_spend(outpoint)? else {
+            return Ok(None);
+        };
+        let txid = tx.compute_txid();
+        let input_index = tx
+            .input
+            .iter()
+            .position(|input| input.previous_output == outpoint)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} was found as the spend of {} but doesn't spend it",
+                    txid,
+                    outpoint
+                )
+            })?;
+
+        //key path spends only have the signature in the witness, every other pool spend goes
+        //through one of the state's ctv leaves
+        let (spend, next_state) = if tx.input[input_index].witness.len() == 1 {
+            (PoolSpend::Cooperative { txid }, None)
+        } else {
+            self.leaf_spend(chain, users, &tx, input_index)?
+        };
+        if next_state.is_none() {
+            info!("pool {:?} closed by {:?} \n", users, spend);
+        }
+
+        Ok(Some((spend, next_state)))
+    }
+
+    //the pool state holding every member, before anyone has left
+    pub fn funding_state(&self, funding_outpoint: OutPoint) -> UnspentPoolState {
+        let users = MemberSet::full(self.params().users);
+        UnspentPoolState {
+            amount: self.pool_amount(&users),
+            users,
+            outpoint: funding_outpoint,
+        }
+    }
+
+    //funding_outpoint has to pay the whole pool amount to the funding address
+    pub(crate) fn check_funding_output(
+        &self,
+        chain: &dyn ChainBackend,
+        funding_outpoint: OutPoint,
+    ) -> Result<()> {
+        let funding_tx = chain.get_tx(funding_outpoint.txid)?;
+        let funding_output = funding_tx
+            .output
+            .get(funding_outpoint.vout as usize)
+            .ok_or_else(|| anyhow!("funding tx has no output {}", funding_outpoint.vout))?;
+        ensure!(
+            funding_output.script_pubkey == self.funding_address()?.script_pubkey()
+                && funding_output.value == self.funding_amount(),
+            "{} does not pay {} to the pool's funding address",
+            funding_outpoint,
+            self.funding_amount()
+        );
+
+        Ok(())
+    }
+
+    //which leaf of the pool state holding users input_index of tx spends it through, the state's
+    //exits come first in member order and then the split. Errors if the input doesn't satisfy
+    //one of the state's leaves
+    fn leaf_spend(
+        &self,
+        chain: &dyn ChainBackend,
+        users: &MemberSet,
+        tx: &Transaction,
+        input_index: usize,
+    ) -> Result<(PoolSpend, Option<UnspentPoolState>)> {
+        let txid = tx.compute_txid();
+
+        let prevouts = tx
+            .input
+            .iter()
+            .map(|input| {
+                let outpoint = input.previous_output;
+                chain
+                    .get_tx(outpoint.txid)?
+                    .output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("{} spends {} which doesn't exist", txid, outpoint))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let script_pubkey = ScriptBuf::new_p2tr_tweaked(self.spend_info(users)?.output_key());
+        ensure!(
+            prevouts[input_index].script_pubkey == script_pubkey,
+            "{} spends {} which isn't pool {:?}",
+            txid,
+            tx.input[input_index].previous_output,
+            users
+        );
+
+        let templates = self.state_templates(users)?;
+        let ctv_spend = verify_ctv_spend(tx, input_index, &prevouts, &templates)
+            .with_context(|| format!("can't explain how {} spends pool {:?}", txid, users))?;
+        let leaf = templates
+            .iter()
+            .position(|template| template.hash() == ctv_spend.template_hash)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} spends pool {:?} through a leaf it doesn't have",
+                    txid,
+                    users
+                )
+            })?;
+
+        if users.len() == 2 {
+            return Ok((PoolSpend::FinalExit { txid }, None));
+        }
+        let Some(member) = users.iter().nth(leaf) else {
+            return Ok((PoolSpend::Split { txid }, None));
+        };
+
+        //the exit template pays the pool state left behind first
+        let users = users.without(member);
+        let next_state = UnspentPoolState {
+            amount: self.pool_amount(&users),
+            users,
+            outpoint: OutPoint { txid, vout: 0 },
+        };
+        Ok((PoolSpend::Exit { member, txid }, Some(next_state)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        absolute::LockTime,
+        secp256k1::{PublicKey, SecretKey},
+        transaction::Version,
+        Network, TxIn, TxOut, Witness,
+    };
+    use rand::thread_rng;
+
+    use super::*;
+    use crate::{
+        chain::MockChain,
+        config::PoolParams,
+        ctv_scripts::{ctv_script, SECP},
+        pools::{process_cooperative_close, process_pool_spend, process_pool_split},
+        test_utils::{funded_chain, test_address, test_anchor, test_members, test_pool},
+    };
+
+    fn five_users() -> PoolParams {
+        PoolParams {
+            users: 5,
+            split_timeout: 10,
+            ..Default::default()
+        }
+    }
+
+    //each member leaves in turn and is mined, returns the txid of the last exit
+    fn exit(
+        pool: &PaymentPool,
+        chain: &MockChain,
+        funding_outpoint: OutPoint,
+        members: &[usize],
+    ) -> Txid {
+        let mut remaining = MemberSet::full(pool.params().users);
+        let mut txid = funding_outpoint.txid;
+        for &member in members {
+            txid = process_pool_spend(pool, chain, &remaining, member, txid, None).unwrap();
+            chain.mine(1);
+            remaining.remove(member);
+        }
+        txid
+    }
+
+    #[test]
+    fn unspent_pool_is_where_it_was_funded() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+
+        let recovered = pool.recover(&chain, funding_outpoint).unwrap();
+        assert!(recovered.spends.is_empty());
+        assert_eq!(recovered.state, Some(pool.funding_state(funding_outpoint)));
+    }
+
+    #[test]
+    fn recovers_the_pool_state_after_exits() {
+        let pool = test_pool(five_users(), &[11000, 25000, 15000, 20000, 30000]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let txid = exit(&pool, &chain, funding_outpoint, &[4, 1]);
+
+        let recovered = pool.recover(&chain, funding_outpoint).unwrap();
+        assert_eq!(recovered.spends.len(), 2);
+        for (spend, member) in recovered.spends.iter().zip([4, 1]) {
+            assert!(matches!(spend, PoolSpend::Exit { member: m, .. } if *m == member));
+        }
+
+        let users: MemberSet = [0, 2, 3].into_iter().collect();
+        let state = recovered.state.unwrap();
+        assert_eq!(state.outpoint, OutPoint { txid, vout: 0 });
+        assert_eq!(state.amount, pool.pool_amount(&users));
+        assert_eq!(state.users, users);
+    }
+
+    #[test]
+    fn closed_pool_has_no_state() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let txid = exit(&pool, &chain, funding_outpoint, &[0, 1, 2, 3]);
+
+        let recovered = pool.recover(&chain, funding_outpoint).unwrap();
+        assert_eq!(recovered.spends.len(), 4);
+        assert_eq!(recovered.spends[3], PoolSpend::FinalExit { txid });
+        assert!(recovered.state.is_none());
+    }
+
+    #[test]
+    fn recovers_a_timeout_split() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let exit_txid = exit(&pool, &chain, funding_outpoint, &[2]);
+
+        let remaining = MemberSet::full(5).without(2);
+        chain.mine(pool.params().split_timeout.into());
+        let split_txid = process_pool_split(&pool, &chain, &remaining, exit_txid).unwrap();
+
+        let recovered = pool.recover(&chain, funding_outpoint).unwrap();
+        assert_eq!(recovered.spends[1], PoolSpend::Split { txid: split_txid });
+        assert!(recovered.state.is_none());
+    }
+
+    #[test]
+    fn recovers_a_cooperative_close() {
+        let seckeys: Vec<_> = (0..5).map(|_| SecretKey::new(&mut thread_rng())).collect();
+        let members = test_members(&[20000; 5])
+            .into_iter()
+            .zip(&seckeys)
+            .map(|(member, seckey)| {
+                member.with_musig_key(PublicKey::from_secret_key(&SECP, seckey))
+            })
+            .collect();
+        let pool =
+            PaymentPool::new(five_users(), Network::Regtest, members, test_anchor()).unwrap();
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let exit_txid = exit(&pool, &chain, funding_outpoint, &[0]);
+
+        let remaining = MemberSet::full(5).without(0);
+        let close_txid =
+            process_cooperative_close(&pool, &chain, &remaining, &seckeys, exit_txid).unwrap();
+
+        let recovered = pool.recover(&chain, funding_outpoint).unwrap();
+        assert_eq!(
+            recovered.spends[1],
+            PoolSpend::Cooperative { txid: close_txid }
+        );
+        assert!(recovered.state.is_none());
+    }
+
+    #[test]
+    fn rejects_a_funding_outpoint_that_doesnt_fund_the_pool() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, _) = funded_chain(&pool);
+        let other = chain.fund(&test_address(), pool.funding_amount());
+
+        assert!(pool.recover(&chain, other).is_err());
+    }
+
+    //a spend of the funding output the pool didn't make, next to an input from the wallet
+    fn foreign_spend(
+        chain: &MockChain,
+        funding_outpoint: OutPoint,
+        pool_witness: Witness,
+        wallet_witness: Witness,
+    ) -> Txid {
+        let wallet_utxo = chain.wallet_utxos().unwrap()[0].clone();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![
+                TxIn {
+                    previous_output: wallet_utxo.outpoint,
+                    witness: wallet_witness,
+                    ..Default::default()
+                },
+                TxIn {
+                    previous_output: funding_outpoint,
+                    witness: pool_witness,
+                    ..Default::default()
+                },
+            ],
+            output: vec![TxOut {
+                value: Amount::from_sat(50000),
+                script_pubkey: test_address().script_pubkey(),
+            }],
+        };
+        chain.broadcast(&tx).unwrap()
+    }
+
+    #[test]
+    fn another_inputs_key_path_spend_is_not_a_cooperative_close() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        foreign_spend(
+            &chain,
+            funding_outpoint,
+            Witness::new(),
+            Witness::from_slice(&[[0u8; 64]]),
+        );
+
+        assert!(pool.recover(&chain, funding_outpoint).is_err());
+    }
+
+    #[test]
+    fn spend_through_no_leaf_of_the_state_is_not_a_split() {
+        let pool = test_pool(five_users(), &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let leaf = ctv_script([7; 32]);
+        foreign_spend(
+            &chain,
+            funding_outpoint,
+            Witness::from_slice(&[leaf.as_bytes(), &[0xc0; 33]]),
+            Witness::new(),
+        );
+
+        assert!(pool.recover(&chain, funding_outpoint).is_err());
+    }
+
+    #[test]
+    fn split_tree_pools_are_not_followed() {
+        let params = PoolParams {
+            mode: PoolMode::SplitTree,
+            ..five_users()
+        };
+        let pool = test_pool(params, &[20000; 5]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+
+        assert!(pool.recover(&chain, funding_outpoint).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use bitcoin::{
+    absolute, taproot::TaprootSpendInfo, transaction, Address, Amount, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut,
+};
+
+use crate::{
+    chain::ChainBackend,
+    config::{PoolParams, PoolParamsError, TX_VERSION},
+    ctv_scripts::{anchor_txout, calc_ctv_hash, ctv_script, CtvTemplate, LeafSpend},
+    members::{MemberSet, PoolMember},
+    payment_pool::PaymentPool,
+};
+
+//In a split tree pool every pool state (node) holds a run of members and has one leaf, splitting
+//it into its two halves. A half with 2 or more members is the next node, a half with one member
+//pays them directly. A member gets out by splitting every node on their path, log2(users)
+//spends, and the whole tree is users - 1 nodes instead of every subset of users.
+//
+//Every split is paid for by the members of the node it splits, each one pays an even share of the
+//exit fee (rounded up) out of their own balance, the way the timeout split does
+
+//the two halves a node splits into, the left one is smaller when the node has an odd size
+pub fn tree_children(node: &MemberSet) -> (MemberSet, MemberSet) {
+    let members = node.to_vec();
+    let (left, right) = members.split_at(members.len() / 2);
+    (
+        left.iter().copied().collect(),
+        right.iter().copied().collect(),
+    )
+}
+
+//every node from the root down to the last one member is in before they're paid out
+pub fn tree_path(users: usize, member: usize) -> Vec<MemberSet> {
+    let mut path = Vec::new();
+    if member >= users {
+        return path;
+    }
+
+    let mut node = MemberSet::full(users);
+    while node.len() >= 2 {
+        let (left, right) = tree_children(&node);
+        let next = if left.contains(member) { left } else { right };
+        path.push(node);
+        node = next;
+    }
+    path
+}
+
+//whether node is one of the states of a split tree over users
+pub fn is_tree_node(users: usize, node: &MemberSet) -> bool {
+    node.iter()
+        .next()
+        .is_some_and(|first| tree_path(users, first).contains(node))
+}
+
+//what one member of node pays for splitting it
+fn split_fee_share(params: &PoolParams, node: &MemberSet) -> Amount {
+    Amount::from_sat(params.exit_fee().to_sat().div_ceil(node.len() as u64))
+}
+
+//what every member of node has already paid for the splits above it
+fn paid_before(params: &PoolParams, users: usize, node: &MemberSet) -> Amount {
+    let Some(first) = node.iter().next() else {
+        return Amount::ZERO;
+    };
+
+    tree_path(users, first)
+        .iter()
+        .take_while(|ancestor| *ancestor != node)
+        .map(|ancestor| split_fee_share(params, ancestor))
+        .sum()
+}
+
+//amount locked in node, every member's deposit less what they paid for the splits above it
+pub fn tree_node_amount(params: &PoolParams, members: &[PoolMember], node: &MemberSet) -> Amount {
+    let paid = paid_before(params, members.len(), node);
+    node.iter().map(|u| members[u].deposit - paid).sum()
+}
+
+//what member pays for every split on their path
+fn split_fees(params: &PoolParams, users: usize, member: usize) -> Amount {
+    tree_path(users, member)
+        .iter()
+        .map(|node| split_fee_share(params, node))
+        .sum()
+}
+
+//what member is paid once every node on their path is split
+pub fn tree_payout(params: &PoolParams, members: &[PoolMember], member: usize) -> Amount {
+    members[member].deposit - split_fees(params, members.len(), member)
+}
+
+//every member pays for log2(users) splits instead of one exit, so their payout has to stay above dust
+pub fn validate_tree_deposits(
+    params: &PoolParams,
+    members: &[PoolMember],
+) -> Result<(), PoolParamsError> {
+    for (member, pool_member) in members.iter().enumerate() {
+        let fees = split_fees(params, members.len(), member);
+        if pool_member.deposit <= fees + params.dust_amount {
+            return Err(PoolParamsError::AmountTooSmall {
+                amount: pool_member.deposit,
+                fee_amount: fees,
+                dust_amount: params.dust_amount,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+//outputs of the split of node, the left half first. node_addr gives the address of a half that is
+//a node itself
+pub fn tree_split_outputs(
+    params: &PoolParams,
+    members: &[PoolMember],
+    anchor_addr: &Address,
+    node: &MemberSet,
+    mut node_addr: impl FnMut(&MemberSet) -> Result<Address>,
+) -> Result<Vec<TxOut>> {
+    let (left, right) = tree_children(node);
+
+    let mut outputs = Vec::new();
+    for half in [left, right] {
+        let output = match half.iter().collect::<Vec<_>>()[..] {
+            [member] => TxOut {
+                value: tree_payout(params, members, member),
+                script_pubkey: members[member].withdraw_address.script_pubkey(),
+            },
+            _ => TxOut {
+                value: tree_node_amount(params, members, &half),
+                script_pubkey: node_addr(&half)?.script_pubkey(),
+            },
+        };
+        outputs.push(output);
+    }
+
+    outputs.extend(anchor_txout(
+        anchor_addr,
+        split_fee_share(params, node) * node.len() as u64,
+    ));
+
+    Ok(outputs)
+}
+
+//the only leaf of node. Anyone can split a node, it can't move funds out of anybody's half so it
+//doesn't need an exit key
+pub fn tree_split_leaf(outputs: &[TxOut]) -> ScriptBuf {
+    ctv_script(calc_ctv_hash(outputs, None))
+}
+
+pub fn tree_split_template(outputs: Vec<TxOut>) -> CtvTemplate {
+    CtvTemplate::pool_spend(outputs, Sequence::ENABLE_RBF_NO_LOCKTIME)
+}
+
+//split node, held in the output at previous_output, into its two halves
+pub fn send_tree_split(
+    outputs: Vec<TxOut>,
+    node_amount: Amount,
+    node_spend_info: &TaprootSpendInfo,
+    previous_output: OutPoint,
+) -> LeafSpend {
+    let leaf_script = tree_split_leaf(&outputs);
+
+    let unsigned_tx = Transaction {
+        version: transaction::Version(TX_VERSION),
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output,
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        }],
+        output: outputs,
+    };
+
+    let prevout = TxOut {
+        value: node_amount,
+        script_pubkey: ScriptBuf::new_p2tr_tweaked(node_spend_info.output_key()),
+    };
+
+    LeafSpend {
+        unsigned_tx,
+        spend_info: node_spend_info.clone(),
+        leaf_script,
+        prevout,
+    }
+}
+
+impl PaymentPool {
+    //outputs of the split of node in this pool
+    pub(crate) fn tree_split_outputs(&self, node: &MemberSet) -> Result<Vec<TxOut>> {
+        tree_split_outputs(
+            self.params(),
+            self.members(),
+            self.anchor_addr(),
+            node,
+            |half| {
+                let spend_info = self.spend_info(half)?;
+                Ok(Address::p2tr_tweaked(
+                    spend_info.output_key(),
+                    self.network(),
+                ))
+            },
+        )
+    }
+
+    //the split of node before its witness is built
+    pub fn tree_split_spend(
+        &self,
+        node: &MemberSet,
+        previous_output: OutPoint,
+    ) -> Result<LeafSpend> {
+        let spend_info = self.spend_info(node)?;
+        Ok(send_tree_split(
+            self.tree_split_outputs(node)?,
+            self.pool_amount(node),
+            &spend_info,
+            previous_output,
+        ))
+    }
+
+    //split node, held in the output at previous_output. The left half is output 0, the right one 1
+    pub fn tree_split_tx(
+        &self,
+        node: &MemberSet,
+        previous_output: OutPoint,
+    ) -> Result<Transaction> {
+        self.tree_split_spend(node, previous_output)?.finalize(None)
+    }
+
+    //the splits member still needs to be paid out, each with the node it splits. nodes holds every
+    //unspent node and its outpoint, the nodes the splits leave behind are added to it. Nothing is
+    //returned once member has been paid out
+    pub fn tree_exit(
+        &self,
+        nodes: &mut HashMap<MemberSet, OutPoint>,
+        member: usize,
+    ) -> Result<Vec<(MemberSet, Transaction)>> {
+        let mut splits = Vec::new();
+        while let Some(split) = self.tree_next_split(nodes, member)? {
+            splits.push(split);
+        }
+
+        Ok(splits)
+    }
+
+    //the first split member still needs and the node it splits, its halves take the node's place
+    //in nodes. Each split spends the one before it, so on chain one has to confirm before the next
+    //goes out. None once member has been paid out
+    pub fn tree_next_split(
+        &self,
+        nodes: &mut HashMap<MemberSet, OutPoint>,
+        member: usize,
+    ) -> Result<Option<(MemberSet, Transaction)>> {
+        if member >= self.params().users {
+            bail!(
+                "user {} is not in a {} user pool",
+                member,
+                self.params().users
+            );
+        }
+
+        //nodes above the first unspent one are already split
+        let Some((node, previous_output)) = tree_path(self.params().users, member)
+            .into_iter()
+            .find_map(|node| nodes.remove(&node).map(|outpoint| (node, outpoint)))
+        else {
+            return Ok(None);
+        };
+
+        let tx = self.tree_split_tx(&node, previous_output)?;
+        let txid = tx.compute_txid();
+        let (left, right) = tree_children(&node);
+        for (vout, half) in [left, right].into_iter().enumerate() {
+            if half.len() >= 2 {
+                nodes.insert(
+                    half,
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                );
+            }
+        }
+
+        Ok(Some((node, tx)))
+    }
+
+    //the node on member's path that is still unspent on chain and its outpoint, found by following
+    //the splits down from the funding outpoint. Empty once member has been paid out, an error if a
+    //node on the path was spent by anything but its split
+    pub fn tree_unspent_node(
+        &self,
+        chain: &dyn ChainBackend,
+        funding_outpoint: OutPoint,
+        member: usize,
+    ) -> Result<HashMap<MemberSet, OutPoint>> {
+        self.check_funding_output(chain, funding_outpoint)?;
+
+        let mut outpoint = funding_outpoint;
+        for node in tree_path(self.params().users, member) {
+            let Some(split_tx) = chain.find_spend(outpoint)? else {
+                return Ok(HashMap::from([(node, outpoint)]));
+            };
+            //the members can spend a node through its key path too (e.g. a musig2 close), its
+            //outputs are only the halves if it really is the split
+            if split_tx.output != self.tree_split_outputs(&node)? {
+                bail!(
+                    "node {:?} at {} was spent by {}, which isn't its split",
+                    node,
+                    outpoint,
+                    split_tx.compute_txid()
+                );
+            }
+
+            let (left, _) = tree_children(&node);
+            outpoint = OutPoint {
+                txid: split_tx.compute_txid(),
+                vout: if left.contains(member) { 0 } else { 1 },
+            };
+        }
+
+        Ok(HashMap::new())
+    }
+
+    //the log2(users) splits from the funding outpoint down to member's payout
+    pub fn tree_exit_transactions(
+        &self,
+        funding_outpoint: OutPoint,
+        member: usize,
+    ) -> Result<Vec<Transaction>> {
+        let mut nodes = HashMap::from([(MemberSet::full(self.params().users), funding_outpoint)]);
+        Ok(self
+            .tree_exit(&mut nodes, member)?
+            .into_iter()
+            .map(|(_, tx)| tx)
+            .collect())
+    }
+
+    //every split of the tree, in the order the members of exit_order need them
+    pub(crate) fn tree_split_transactions(
+        &self,
+        funding_outpoint: OutPoint,
+        exit_order: &[usize],
+    ) -> Result<Vec<Transaction>> {
+        let mut nodes = HashMap::from([(MemberSet::full(self.params().users), funding_outpoint)]);
+        let mut txs = Vec::new();
+        for &member in exit_order {
+            txs.extend(
+                self.tree_exit(&mut nodes, member)?
+                    .into_iter()
+                    .map(|(_, tx)| tx),
+            );
+        }
+        Ok(txs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PoolMode,
+        test_utils::{funded_chain, test_address, test_pool},
+    };
+
+    fn seven_users() -> PoolParams {
+        PoolParams {
+            users: 7,
+            mode: PoolMode::SplitTree,
+            ..Default::default()
+        }
+    }
+
+    //without p2a there is no anchor and the split's fee goes to the miners instead
+    #[test]
+    fn split_outputs_add_up_to_the_node_amount() {
+        let pool = test_pool(
+            seven_users(),
+            &[20000, 21000, 22000, 23000, 24000, 25000, 26000],
+        );
+        let nodes: Vec<MemberSet> = (0..7).flat_map(|member| tree_path(7, member)).collect();
+
+        for node in nodes {
+            let outputs = pool.tree_split_outputs(&node).unwrap();
+            assert_eq!(outputs.len(), if cfg!(feature = "p2a") { 3 } else { 2 });
+
+            let paid: Amount = outputs.iter().map(|output| output.value).sum();
+            let fee = if cfg!(feature = "p2a") {
+                Amount::ZERO
+            } else {
+                split_fee_share(pool.params(), &node) * node.len() as u64
+            };
+            assert_eq!(
+                paid + fee,
+                tree_node_amount(pool.params(), pool.members(), &node)
+            );
+        }
+    }
+
+    #[test]
+    fn unspent_node_follows_the_splits() {
+        let pool = test_pool(seven_users(), &[20000; 7]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+
+        let splits = pool.tree_exit_transactions(funding_outpoint, 0).unwrap();
+        for split in &splits {
+            chain.broadcast(split).unwrap();
+        }
+        chain.mine(1);
+
+        assert!(pool
+            .tree_unspent_node(&chain, funding_outpoint, 0)
+            .unwrap()
+            .is_empty());
+        //the root split left member 6's half unspent at its output 1
+        let right: MemberSet = (3..7).collect();
+        assert_eq!(
+            pool.tree_unspent_node(&chain, funding_outpoint, 6).unwrap(),
+            HashMap::from([(right, OutPoint::new(splits[0].compute_txid(), 1))])
+        );
+    }
+
+    #[test]
+    fn rejects_a_node_spent_by_something_else() {
+        let pool = test_pool(seven_users(), &[20000; 7]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+
+        //e.g. the members closing the pool through the key path
+        let close = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: funding_outpoint,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(100000),
+                script_pubkey: test_address().script_pubkey(),
+            }],
+        };
+        chain.broadcast(&close).unwrap();
+        chain.mine(1);
+
+        let err = pool
+            .tree_unspent_node(&chain, funding_outpoint, 0)
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't its split"), "{:#}", err);
+    }
+}
@@ -0,0 +1,58 @@
+//pools for the unit tests, every member gets a fresh random withdraw address
+use std::path::PathBuf;
+
+use bitcoin::{key::Keypair, Address, Amount, Network, OutPoint, ScriptBuf};
+use rand::thread_rng;
+
+use crate::{
+    chain::MockChain, config::PoolParams, ctv_scripts::SECP, members::PoolMember,
+    payment_pool::PaymentPool,
+};
+
+pub(crate) fn test_address() -> Address {
+    let keypair = Keypair::new(&SECP, &mut thread_rng());
+    Address::p2tr(&SECP, keypair.x_only_public_key().0, None, Network::Regtest)
+}
+
+//the pay to anchor address the regtest config uses
+pub(crate) fn test_anchor() -> Address {
+    Address::from_script(&ScriptBuf::new_p2a(), Network::Regtest).unwrap()
+}
+
+pub(crate) fn test_members(deposits: &[u64]) -> Vec<PoolMember> {
+    deposits
+        .iter()
+        .map(|&deposit| PoolMember::new(test_address(), Amount::from_sat(deposit)))
+        .collect()
+}
+
+pub(crate) fn test_pool(params: PoolParams, deposits: &[u64]) -> PaymentPool {
+    PaymentPool::new(
+        params,
+        Network::Regtest,
+        test_members(deposits),
+        test_anchor(),
+    )
+    .unwrap()
+}
+
+//a mock chain with the pool funded and some wallet utxos for the children paying pool spend fees
+pub(crate) fn funded_chain(pool: &PaymentPool) -> (MockChain, OutPoint) {
+    let chain = MockChain::new();
+    let wallet = test_address();
+    for _ in 0..5 {
+        chain.fund_wallet(&wallet, Amount::from_sat(100_000));
+    }
+    let funding_outpoint = chain.fund(&pool.funding_address().unwrap(), pool.funding_amount());
+    (chain, funding_outpoint)
+}
+
+//a path in the temp dir no other test uses
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "ctv_pool_test_{}_{}_{}",
+        std::process::id(),
+        rand::random::<u32>(),
+        name
+    ))
+}
@@ -0,0 +1,543 @@
+use std::fmt;
+
+use bitcoin::{
+    absolute,
+    hashes::Hash,
+    opcodes::all::{OP_CHECKSIG, OP_DROP, OP_NOP4},
+    script::Instruction,
+    secp256k1::Message,
+    sighash::{Prevouts, SighashCache},
+    taproot::{self, ControlBlock, LeafVersion, TapLeafHash, TAPROOT_ANNEX_PREFIX},
+    transaction, ScriptBuf, Sequence, Transaction, TxOut, XOnlyPublicKey,
+};
+
+use crate::ctv_scripts::{CtvTemplate, TemplateHash, SECP};
+
+//a ctv leaf spend that checked out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtvSpend {
+    pub leaf_script: ScriptBuf,
+    pub template_hash: TemplateHash,
+    //the key that signed the spend, for leaves from ctv_checksig_script
+    pub signer: Option<XOnlyPublicKey>,
+}
+
+//one field of the tx that differs from the template the leaf was built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplateField {
+    Version {
+        expected: transaction::Version,
+        found: transaction::Version,
+    },
+    LockTime {
+        expected: absolute::LockTime,
+        found: absolute::LockTime,
+    },
+    InputCount {
+        expected: usize,
+        found: usize,
+    },
+    ScriptSig {
+        input: usize,
+    },
+    Sequence {
+        input: usize,
+        expected: Sequence,
+        found: Sequence,
+    },
+    OutputCount {
+        expected: usize,
+        found: usize,
+    },
+    Output {
+        index: usize,
+        expected: TxOut,
+        found: TxOut,
+    },
+    InputIndex {
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for TemplateField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateField::Version { expected, found } => {
+                write!(f, "version is {}, expected {}", found.0, expected.0)
+            }
+            TemplateField::LockTime { expected, found } => {
+                write!(f, "locktime is {}, expected {}", found, expected)
+            }
+            TemplateField::InputCount { expected, found } => {
+                write!(f, "tx has {} inputs, expected {}", found, expected)
+            }
+            TemplateField::ScriptSig { input } => {
+                write!(f, "script sig of input {} is different", input)
+            }
+            TemplateField::Sequence {
+                input,
+                expected,
+                found,
+            } => write!(
+                f,
+                "sequence of input {} is {:#x}, expected {:#x}",
+                input, found.0, expected.0
+            ),
+            TemplateField::OutputCount { expected, found } => {
+                write!(f, "tx has {} outputs, expected {}", found, expected)
+            }
+            TemplateField::Output {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "output {} pays {} to {}, expected {} to {}",
+                index, found.value, found.script_pubkey, expected.value, expected.script_pubkey
+            ),
+            TemplateField::InputIndex { expected, found } => {
+                write!(f, "spent at input {}, expected input {}", found, expected)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CtvVerifyError {
+    InputOutOfRange {
+        input_index: usize,
+        inputs: usize,
+    },
+    MissingPrevouts {
+        prevouts: usize,
+        inputs: usize,
+    },
+    NotTaproot,
+    NotScriptPath,
+    AnnexNotSupported,
+    InvalidControlBlock(taproot::TaprootError),
+    UnsupportedLeafVersion(LeafVersion),
+    //the control block and leaf don't hash to the output key of the prevout
+    CommitmentMismatch,
+    NotCtvLeaf,
+    //the witness stack doesn't hold exactly what the leaf needs
+    UnexpectedWitness {
+        expected: usize,
+        found: usize,
+    },
+    InvalidSignature,
+    //fields is empty when none of the expected templates has the hash the leaf commits to
+    TemplateMismatch {
+        committed: TemplateHash,
+        computed: TemplateHash,
+        fields: Vec<TemplateField>,
+    },
+}
+
+impl fmt::Display for CtvVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CtvVerifyError::InputOutOfRange {
+                input_index,
+                inputs,
+            } => write!(
+                f,
+                "input {} is out of range, tx has {} inputs",
+                input_index, inputs
+            ),
+            CtvVerifyError::MissingPrevouts { prevouts, inputs } => {
+                write!(f, "got {} prevouts for {} inputs", prevouts, inputs)
+            }
+            CtvVerifyError::NotTaproot => write!(f, "prevout is not a taproot output"),
+            CtvVerifyError::NotScriptPath => write!(f, "input is not a script path spend"),
+            CtvVerifyError::AnnexNotSupported => write!(f, "witness has an annex"),
+            CtvVerifyError::InvalidControlBlock(e) => write!(f, "invalid control block: {}", e),
+            CtvVerifyError::UnsupportedLeafVersion(version) => {
+                write!(f, "unsupported leaf version {}", version)
+            }
+            CtvVerifyError::CommitmentMismatch => {
+                write!(
+                    f,
+                    "control block does not commit the leaf to the output key"
+                )
+            }
+            CtvVerifyError::NotCtvLeaf => write!(f, "leaf is not a ctv leaf"),
+            CtvVerifyError::UnexpectedWitness { expected, found } => write!(
+                f,
+                "leaf needs {} witness items before the script, found {}",
+                expected, found
+            ),
+            CtvVerifyError::InvalidSignature => write!(f, "invalid signature for the leaf key"),
+            CtvVerifyError::TemplateMismatch {
+                committed,
+                computed,
+                fields,
+            } => {
+                write!(
+                    f,
+                    "template hash {} does not match the leaf's {}",
+                    computed, committed
+                )?;
+                for field in fields {
+                    write!(f, ", {}", field)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CtvVerifyError {}
+
+//Check input_index of tx spends its taproot prevout through a ctv leaf without a node: the control
+//block has to commit the leaf to the output key, the leaf has to be one this crate builds, any
+//signature has to be valid and the tx has to match the template hash in the leaf.
+//
+//prevouts holds the output spent by every input. If the template hash doesn't match and one of
+//expected_templates has the committed hash, the error lists every field that differs from it
+pub fn verify_ctv_spend(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &[TxOut],
+    expected_templates: &[CtvTemplate],
+) -> Result<CtvSpend, CtvVerifyError> {
+    let input = tx
+        .input
+        .get(input_index)
+        .ok_or(CtvVerifyError::InputOutOfRange {
+            input_index,
+            inputs: tx.input.len(),
+        })?;
+    if prevouts.len() != tx.input.len() {
+        return Err(CtvVerifyError::MissingPrevouts {
+            prevouts: prevouts.len(),
+            inputs: tx.input.len(),
+        });
+    }
+
+    let prevout = &prevouts[input_index];
+    if !prevout.script_pubkey.is_p2tr() {
+        return Err(CtvVerifyError::NotTaproot);
+    }
+    let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..34])
+        .map_err(|_| CtvVerifyError::NotTaproot)?;
+
+    //witness is [stack items.., leaf script, control block]
+    let witness: Vec<&[u8]> = input.witness.iter().collect();
+    if witness.len() < 2 {
+        return Err(CtvVerifyError::NotScriptPath);
+    }
+    if witness
+        .last()
+        .is_some_and(|item| item.first() == Some(&TAPROOT_ANNEX_PREFIX))
+    {
+        return Err(CtvVerifyError::AnnexNotSupported);
+    }
+    let (stack, script_and_control) = witness.split_at(witness.len() - 2);
+
+    let control_block =
+        ControlBlock::decode(script_and_control[1]).map_err(CtvVerifyError::InvalidControlBlock)?;
+    if control_block.leaf_version != LeafVersion::TapScript {
+        return Err(CtvVerifyError::UnsupportedLeafVersion(
+            control_block.leaf_version,
+        ));
+    }
+
+    let leaf_script = ScriptBuf::from_bytes(script_and_control[0].to_vec());
+    if !control_block.verify_taproot_commitment(&SECP, output_key, &leaf_script) {
+        return Err(CtvVerifyError::CommitmentMismatch);
+    }
+
+    let (template_hash, signer) = parse_ctv_leaf(&leaf_script).ok_or(CtvVerifyError::NotCtvLeaf)?;
+
+    let expected_stack = usize::from(signer.is_some());
+    if stack.len() != expected_stack {
+        return Err(CtvVerifyError::UnexpectedWitness {
+            expected: expected_stack,
+            found: stack.len(),
+        });
+    }
+
+    let computed = TemplateHash::from_tx(tx, input_index as u32);
+    if computed != template_hash {
+        let fields = expected_templates
+            .iter()
+            .find(|expected| expected.hash() == template_hash)
+            .map(|expected| {
+                template_mismatches(expected, &CtvTemplate::from_tx(tx, input_index as u32))
+            })
+            .unwrap_or_default();
+
+        return Err(CtvVerifyError::TemplateMismatch {
+            committed: template_hash,
+            computed,
+            fields,
+        });
+    }
+
+    if let Some(signer) = signer {
+        let signature = taproot::Signature::from_slice(stack[0])
+            .map_err(|_| CtvVerifyError::InvalidSignature)?;
+        let sighash = SighashCache::new(tx)
+            .taproot_script_spend_signature_hash(
+                input_index,
+                &Prevouts::All(prevouts),
+                TapLeafHash::from_script(&leaf_script, LeafVersion::TapScript),
+                signature.sighash_type,
+            )
+            .map_err(|_| CtvVerifyError::InvalidSignature)?;
+
+        SECP.verify_schnorr(
+            &signature.signature,
+            &Message::from_digest(sighash.to_byte_array()),
+            &signer,
+        )
+        .map_err(|_| CtvVerifyError::InvalidSignature)?;
+    }
+
+    Ok(CtvSpend {
+        leaf_script,
+        template_hash,
+        signer,
+    })
+}
+
+//the template hash of a leaf from ctv_script or ctv_checksig_script, and the key of the latter
+pub(crate) fn parse_ctv_leaf(
+    leaf_script: &ScriptBuf,
+) -> Option<(TemplateHash, Option<XOnlyPublicKey>)> {
+    let instructions = leaf_script
+        .instructions()
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+
+    match instructions.as_slice() {
+        [Instruction::PushBytes(hash), Instruction::Op(OP_NOP4)] => {
+            let hash = TemplateHash::from_byte_array(hash.as_bytes().try_into().ok()?);
+            Some((hash, None))
+        }
+        [Instruction::PushBytes(hash), Instruction::Op(OP_NOP4), Instruction::Op(OP_DROP), Instruction::PushBytes(key), Instruction::Op(OP_CHECKSIG)] =>
+        {
+            let key = XOnlyPublicKey::from_slice(key.as_bytes()).ok()?;
+            let hash = TemplateHash::from_byte_array(hash.as_bytes().try_into().ok()?);
+            Some((hash, Some(key)))
+        }
+        _ => None,
+    }
+}
+
+//every field of found that differs from expected
+pub fn template_mismatches(expected: &CtvTemplate, found: &CtvTemplate) -> Vec<TemplateField> {
+    let mut fields = Vec::new();
+
+    if expected.version != found.version {
+        fields.push(TemplateField::Version {
+            expected: expected.version,
+            found: found.version,
+        });
+    }
+
+    if expected.lock_time != found.lock_time {
+        fields.push(TemplateField::LockTime {
+            expected: expected.lock_time,
+            found: found.lock_time,
+        });
+    }
+
+    if expected.sequences.len() != found.sequences.len() {
+        fields.push(TemplateField::InputCount {
+            expected: expected.sequences.len(),
+            found: found.sequences.len(),
+        });
+    }
+
+    //an empty script sig on every input isn't committed to, so there is nothing to compare
+    let has_script_sigs = |template: &CtvTemplate| {
+        template
+            .script_sigs
+            .iter()
+            .any(|script_sig| !script_sig.is_empty())
+    };
+    if has_script_sigs(expected) || has_script_sigs(found) {
+        for (input, (expected_sig, found_sig)) in expected
+            .script_sigs
+            .iter()
+            .zip(&found.script_sigs)
+            .enumerate()
+        {
+            if expected_sig != found_sig {
+                fields.push(TemplateField::ScriptSig { input });
+            }
+        }
+    }
+
+    for (input, (expected_seq, found_seq)) in
+        expected.sequences.iter().zip(&found.sequences).enumerate()
+    {
+        if expected_seq != found_seq {
+            fields.push(TemplateField::Sequence {
+                input,
+                expected: *expected_seq,
+                found: *found_seq,
+            });
+        }
+    }
+
+    if expected.outputs.len() != found.outputs.len() {
+        fields.push(TemplateField::OutputCount {
+            expected: expected.outputs.len(),
+            found: found.outputs.len(),
+        });
+    }
+
+    for (index, (expected_out, found_out)) in
+        expected.outputs.iter().zip(&found.outputs).enumerate()
+    {
+        if expected_out != found_out {
+            fields.push(TemplateField::Output {
+                index,
+                expected: expected_out.clone(),
+                found: found_out.clone(),
+            });
+        }
+    }
+
+    if expected.input_index != found.input_index {
+        fields.push(TemplateField::InputIndex {
+            expected: expected.input_index,
+            found: found.input_index,
+        });
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{Amount, OutPoint, TxIn};
+
+    use super::*;
+    use crate::{
+        config::PoolParams,
+        members::MemberSet,
+        test_utils::{test_address, test_pool},
+    };
+
+    //member 1's exit from the full state of a 4 user pool, with the output it spends and the
+    //templates of every leaf of the state
+    fn valid_exit() -> (Transaction, TxOut, Vec<CtvTemplate>) {
+        let params = PoolParams {
+            users: 4,
+            ..Default::default()
+        };
+        let pool = test_pool(params, &[20000; 4]);
+        let users = MemberSet::full(4);
+        let tx = pool.exit_tx(&users, 1, OutPoint::null(), None).unwrap();
+        let prevout = TxOut {
+            value: pool.pool_amount(&users),
+            script_pubkey: pool.funding_address().unwrap().script_pubkey(),
+        };
+        let templates = pool.state_templates(&users).unwrap();
+        (tx, prevout, templates)
+    }
+
+    //the fields verify_ctv_spend reports for the exit after change made it
+    fn mismatches(change: impl FnOnce(&mut Transaction, &mut Vec<TxOut>)) -> Vec<TemplateField> {
+        let (mut tx, prevout, templates) = valid_exit();
+        let mut prevouts = vec![prevout];
+        change(&mut tx, &mut prevouts);
+        let input_index = tx
+            .input
+            .iter()
+            .position(|input| input.previous_output == OutPoint::null())
+            .unwrap();
+
+        match verify_ctv_spend(&tx, input_index, &prevouts, &templates) {
+            Err(CtvVerifyError::TemplateMismatch { fields, .. }) => fields,
+            other => panic!("expected a template mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn valid_exit_verifies() {
+        let (tx, prevout, templates) = valid_exit();
+        let spend = verify_ctv_spend(&tx, 0, &[prevout], &templates).unwrap();
+        assert!(templates
+            .iter()
+            .any(|template| template.hash() == spend.template_hash));
+    }
+
+    #[test]
+    fn reports_a_changed_version() {
+        let fields = mismatches(|tx, _| tx.version = transaction::Version::ONE);
+        assert!(matches!(
+            fields[..],
+            [TemplateField::Version { found, .. }] if found == transaction::Version::ONE
+        ));
+    }
+
+    #[test]
+    fn reports_a_changed_locktime() {
+        let lock_time = absolute::LockTime::from_height(100).unwrap();
+        let fields = mismatches(|tx, _| tx.lock_time = lock_time);
+        assert!(matches!(
+            fields[..],
+            [TemplateField::LockTime { found, .. }] if found == lock_time
+        ));
+    }
+
+    #[test]
+    fn reports_a_changed_sequence() {
+        let fields = mismatches(|tx, _| tx.input[0].sequence = Sequence::MAX);
+        assert!(matches!(
+            fields[..],
+            [TemplateField::Sequence { input: 0, found, .. }] if found == Sequence::MAX
+        ));
+    }
+
+    #[test]
+    fn reports_a_changed_output() {
+        let thief = test_address().script_pubkey();
+        let fields = mismatches(|tx, _| tx.output[1].script_pubkey = thief.clone());
+        assert!(matches!(
+            &fields[..],
+            [TemplateField::Output { index: 1, found, .. }] if found.script_pubkey == thief
+        ));
+    }
+
+    #[test]
+    fn reports_a_changed_input_index() {
+        //another input in front of the pool output moves it to input 1
+        let fields = mismatches(|tx, prevouts| {
+            tx.input.insert(
+                0,
+                TxIn {
+                    previous_output: OutPoint::new(tx.compute_txid(), 7),
+                    sequence: tx.input[0].sequence,
+                    ..Default::default()
+                },
+            );
+            prevouts.insert(
+                0,
+                TxOut {
+                    value: Amount::from_sat(10000),
+                    script_pubkey: test_address().script_pubkey(),
+                },
+            );
+        });
+        assert_eq!(
+            fields,
+            [
+                TemplateField::InputCount {
+                    expected: 1,
+                    found: 2
+                },
+                TemplateField::InputIndex {
+                    expected: 0,
+                    found: 1
+                }
+            ]
+        );
+    }
+}
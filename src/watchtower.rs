@@ -0,0 +1,446 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use bitcoin::{Amount, OutPoint, Txid};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    chain::ChainBackend,
+    config::PoolMode,
+    cpfp::package_fee_rate,
+    members::MemberSet,
+    payment_pool::PaymentPool,
+    recover::{PoolSpend, UnspentPoolState},
+};
+
+//bump this whenever the layout of the watch file changes
+pub const WATCH_FILE_VERSION: u32 = 1;
+
+//a pending spend paying less than the node's estimate for confirming within this many blocks
+//needs a fee bump
+pub const FEE_BUMP_TARGET: u16 = 6;
+
+//what the watchtower saw happen to the pool, serialized with an "event" field naming the variant
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PoolEvent {
+    //member left through their exit leaf, members are the ones still in the pool at pool_outpoint
+    MemberLeft {
+        member: usize,
+        txid: Txid,
+        pool_outpoint: OutPoint,
+        members: Vec<usize>,
+        #[serde(with = "bitcoin::amount::serde::as_sat")]
+        amount: Amount,
+    },
+    //the last 2 members left together, the pool is closed
+    FinalExit {
+        txid: Txid,
+        members: Vec<usize>,
+    },
+    //the timeout split paid out every member still in the pool, the pool is closed
+    Split {
+        txid: Txid,
+        members: Vec<usize>,
+    },
+    //the members spent the pool together through the key path, the pool is closed
+    CooperativeClose {
+        txid: Txid,
+        members: Vec<usize>,
+    },
+    //a spend of the pool state holding members is in the mempool, it's reported as one of the
+    //events above once it confirms. fee_rate is what it pays with its child in sat/kvB,
+    //needs_fee_bump is set while that's below the node's estimate (e.g. run bump-fee)
+    SpendPending {
+        txid: Txid,
+        members: Vec<usize>,
+        fee_rate: u64,
+        needs_fee_bump: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatchFile {
+    version: u32,
+    funding_outpoint: OutPoint,
+    //None once the pool is closed
+    state: Option<WatchedState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WatchedState {
+    members: Vec<usize>,
+    outpoint: OutPoint,
+}
+
+//Follows one pool on chain and reports every spend of it as a PoolEvent. Only confirmed spends
+//move the watchtower on, a spend still in the mempool is reported as SpendPending and checked
+//again on the next poll, since it can be replaced or dropped. Where it got to is saved to a watch
+//file after every confirmed spend, so a restarted watchtower carries on from there. A watchtower
+//started on a pool that already had exits reports those first. Split tree pools can't be watched.
+pub struct Watchtower<'a> {
+    pool: &'a PaymentPool,
+    chain: &'a dyn ChainBackend,
+    path: PathBuf,
+    funding_outpoint: OutPoint,
+    //the last confirmed pool state
+    state: Option<UnspentPoolState>,
+    //the last SpendPending reported, so it's only reported again when something changed
+    pending: Option<PoolEvent>,
+}
+
+impl<'a> Watchtower<'a> {
+    //watch the pool funded at funding_outpoint, picking up from path if an earlier run saved it there
+    pub fn new(
+        pool: &'a PaymentPool,
+        chain: &'a dyn ChainBackend,
+        funding_outpoint: OutPoint,
+        path: &Path,
+    ) -> Result<Self> {
+        ensure!(
+            pool.params().mode == PoolMode::Exact,
+            "only exact pools can be watched, a split tree pool has no single pool state"
+        );
+
+        let state = if path.exists() {
+            load_state(pool, funding_outpoint, path)?
+        } else {
+            pool.check_funding_output(chain, funding_outpoint)?;
+            Some(pool.funding_state(funding_outpoint))
+        };
+
+        let watchtower = Self {
+            pool,
+            chain,
+            path: path.to_path_buf(),
+            funding_outpoint,
+            state,
+            pending: None,
+        };
+        watchtower.save()?;
+
+        Ok(watchtower)
+    }
+
+    //the last confirmed pool state, None once the pool is closed
+    pub fn state(&self) -> Option<&UnspentPoolState> {
+        self.state.as_ref()
+    }
+
+    //check the chain once and pass every confirmed spend since the last poll to on_event, oldest
+    //first, then the spend waiting in the mempool if there is a new one or its fee changed.
+    //the watch file is saved after on_event returns, so after a crash the last event can be seen twice
+    pub fn poll(&mut self, mut on_event: impl FnMut(&PoolEvent)) -> Result<usize> {
+        let mut events = 0;
+
+        while let Some(state) = &self.state {
+            let Some((spend, next_state)) =
+                self.pool
+                    .follow_spend(self.chain, &state.users, state.outpoint)?
+            else {
+                self.pending = None;
+                break;
+            };
+
+            let txid = spend.txid();
+            if self.chain.tx_confirmations(txid)? == 0 {
+                let users = state.users.clone();
+                let pending = self.pending_event(txid, &users)?;
+                if self.pending.as_ref() != Some(&pending) {
+                    on_event(&pending);
+                    events += 1;
+                    self.pending = Some(pending);
+                }
+                break;
+            }
+
+            on_event(&pool_event(spend, &state.users, next_state.as_ref()));
+            events += 1;
+
+            self.pending = None;
+            self.state = next_state;
+            self.save()?;
+        }
+
+        Ok(events)
+    }
+
+    fn pending_event(&self, txid: Txid, users: &MemberSet) -> Result<PoolEvent> {
+        let tx = self.chain.get_tx(txid)?;
+        let fee_rate = package_fee_rate(self.chain, self.pool.anchor_addr(), &tx)?;
+        let target_fee_rate = self.chain.estimate_fee_rate(FEE_BUMP_TARGET)?;
+
+        Ok(PoolEvent::SpendPending {
+            txid,
+            members: users.to_vec(),
+            fee_rate,
+            needs_fee_bump: target_fee_rate.is_some_and(|target| fee_rate < target),
+        })
+    }
+
+    //poll every interval until the pool is closed. Failed polls (e.g. the node being restarted)
+    //are logged and retried on the next interval
+    pub fn run(&mut self, interval: Duration, mut on_event: impl FnMut(&PoolEvent)) -> Result<()> {
+        loop {
+            if let Err(e) = self.poll(&mut on_event) {
+                warn!("watchtower poll failed: {:#} \n", e);
+            }
+
+            if self.state.is_none() {
+                info!("pool funded at {} is closed \n", self.funding_outpoint);
+                return Ok(());
+            }
+
+            thread::sleep(interval);
+        }
+    }
+
+    //write to a temporary file first so a crash mid write never leaves a broken watch file
+    fn save(&self) -> Result<()> {
+        let watch_file = WatchFile {
+            version: WATCH_FILE_VERSION,
+            funding_outpoint: self.funding_outpoint,
+            state: self.state.as_ref().map(|state| WatchedState {
+                members: state.users.to_vec(),
+                outpoint: state.outpoint,
+            }),
+        };
+
+        let tmp_path = self.path.with_extension("tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create watch file {}", tmp_path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), &watch_file)?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to write watch file {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+fn load_state(
+    pool: &PaymentPool,
+    funding_outpoint: OutPoint,
+    path: &Path,
+) -> Result<Option<UnspentPoolState>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read watch file {}", path.display()))?;
+    let watch_file: WatchFile = serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse watch file {}", path.display()))?;
+
+    if watch_file.version != WATCH_FILE_VERSION {
+        bail!(
+            "unsupported watch file version {}, expected {}",
+            watch_file.version,
+            WATCH_FILE_VERSION
+        );
+    }
+    ensure!(
+        watch_file.funding_outpoint == funding_outpoint,
+        "watch file {} is for the pool funded at {}",
+        path.display(),
+        watch_file.funding_outpoint
+    );
+
+    let Some(state) = watch_file.state else {
+        return Ok(None);
+    };
+    ensure!(
+        state.members.len() >= 2
+            && state.members.windows(2).all(|pair| pair[0] < pair[1])
+            && state.members.iter().all(|&u| u < pool.params().users),
+        "invalid members {:?} in watch file {}",
+        state.members,
+        path.display()
+    );
+
+    let users: MemberSet = state.members.into_iter().collect();
+    info!(
+        "watching pool {:?} at {} from {} \n",
+        users,
+        state.outpoint,
+        path.display()
+    );
+
+    Ok(Some(UnspentPoolState {
+        amount: pool.pool_amount(&users),
+        users,
+        outpoint: state.outpoint,
+    }))
+}
+
+fn pool_event(
+    spend: PoolSpend,
+    users: &MemberSet,
+    next_state: Option<&UnspentPoolState>,
+) -> PoolEvent {
+    let members = users.to_vec();
+
+    match (spend, next_state) {
+        (PoolSpend::Exit { member, txid }, Some(next_state)) => PoolEvent::MemberLeft {
+            member,
+            txid,
+            pool_outpoint: next_state.outpoint,
+            members: next_state.users.to_vec(),
+            amount: next_state.amount,
+        },
+        (PoolSpend::Exit { txid, .. } | PoolSpend::FinalExit { txid }, _) => {
+            PoolEvent::FinalExit { txid, members }
+        }
+        (PoolSpend::Split { txid }, _) => PoolEvent::Split { txid, members },
+        (PoolSpend::Cooperative { txid }, _) => PoolEvent::CooperativeClose { txid, members },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::PoolParams,
+        pools::{broadcast_pool_spend, process_pool_spend},
+        test_utils::{funded_chain, temp_path, test_pool},
+    };
+
+    fn four_users() -> PoolParams {
+        PoolParams {
+            users: 4,
+            ..Default::default()
+        }
+    }
+
+    fn poll(watchtower: &mut Watchtower) -> Vec<PoolEvent> {
+        let mut events = Vec::new();
+        watchtower.poll(|event| events.push(event.clone())).unwrap();
+        events
+    }
+
+    #[test]
+    fn reports_exits_and_carries_on_after_a_restart() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let path = temp_path("watch.json");
+
+        let mut watchtower = Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+        assert!(poll(&mut watchtower).is_empty());
+
+        let full = MemberSet::full(4);
+        let txid =
+            process_pool_spend(&pool, &chain, &full, 1, funding_outpoint.txid, None).unwrap();
+        chain.mine(1);
+
+        let remaining = full.without(1);
+        assert_eq!(
+            poll(&mut watchtower),
+            [PoolEvent::MemberLeft {
+                member: 1,
+                txid,
+                pool_outpoint: OutPoint { txid, vout: 0 },
+                members: vec![0, 2, 3],
+                amount: pool.pool_amount(&remaining),
+            }]
+        );
+        assert!(poll(&mut watchtower).is_empty());
+
+        //a new watchtower picks up from the file and only sees what happened since
+        drop(watchtower);
+        let mut watchtower = Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+        assert_eq!(watchtower.state().unwrap().users, remaining);
+
+        let txid = process_pool_spend(&pool, &chain, &remaining, 3, txid, None).unwrap();
+        chain.mine(1);
+        let final_txid =
+            process_pool_spend(&pool, &chain, &remaining.without(3), 0, txid, None).unwrap();
+        chain.mine(1);
+
+        let events = poll(&mut watchtower);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0], PoolEvent::MemberLeft { member: 3, .. }));
+        assert_eq!(
+            events[1],
+            PoolEvent::FinalExit {
+                txid: final_txid,
+                members: vec![0, 2],
+            }
+        );
+        assert!(watchtower.state().is_none());
+
+        //once closed it stays closed
+        let watchtower = Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+        assert!(watchtower.state().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_watch_file_for_another_pool() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let other_outpoint = chain.fund(&pool.funding_address().unwrap(), pool.funding_amount());
+        let path = temp_path("watch.json");
+
+        Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+        assert!(Watchtower::new(&pool, &chain, other_outpoint, &path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn mempool_spends_are_pending_until_they_confirm() {
+        let pool = test_pool(four_users(), &[20000; 4]);
+        let (chain, funding_outpoint) = funded_chain(&pool);
+        let path = temp_path("watch.json");
+        let mut watchtower = Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+
+        let full = MemberSet::full(4);
+        let exit_tx = pool.exit_tx(&full, 2, funding_outpoint, None).unwrap();
+        let txid = broadcast_pool_spend(&chain, pool.anchor_addr(), &exit_tx).unwrap();
+
+        let events = poll(&mut watchtower);
+        assert_eq!(events.len(), 1);
+        let PoolEvent::SpendPending {
+            txid: pending_txid,
+            ref members,
+            fee_rate,
+            needs_fee_bump: false,
+        } = events[0]
+        else {
+            panic!("expected a pending spend, got {:?}", events[0]);
+        };
+        assert_eq!(pending_txid, txid);
+        assert_eq!(members, &[0, 1, 2, 3]);
+
+        //nothing moved, the file still has the funding state and the same spend isn't repeated
+        assert_eq!(watchtower.state().unwrap().users, full);
+        assert!(poll(&mut watchtower).is_empty());
+        let restarted = Watchtower::new(&pool, &chain, funding_outpoint, &path).unwrap();
+        assert_eq!(restarted.state().unwrap().users, full);
+
+        //the node wants more than the spend pays
+        chain.set_fee_rate(Some(fee_rate + 1));
+        assert_eq!(
+            poll(&mut watchtower),
+            [PoolEvent::SpendPending {
+                txid,
+                members: vec![0, 1, 2, 3],
+                fee_rate,
+                needs_fee_bump: true,
+            }]
+        );
+
+        chain.mine(1);
+        let events = poll(&mut watchtower);
+        assert!(matches!(
+            events[..],
+            [PoolEvent::MemberLeft { member: 2, .. }]
+        ));
+        assert_eq!(watchtower.state().unwrap().users, full.without(2));
+
+        fs::remove_file(&path).unwrap();
+    }
+}